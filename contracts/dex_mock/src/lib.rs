@@ -0,0 +1,184 @@
+#![no_std]
+use soroban_sdk::{
+    contract, contractclient, contracterror, contractimpl, contracttype, panic_with_error, token,
+    Address, Env, Symbol,
+};
+use swap_router::SwapRouter;
+
+const FIXED_POINT_ONE: i128 = 10_000_000;
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Asset {
+    Stellar(Address),
+    Other(Symbol),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct PriceData {
+    // The price in contracts' base asset and decimals.
+    pub price: i128,
+    // The timestamp of the price.
+    pub timestamp: u64,
+}
+
+#[allow(dead_code)]
+#[contractclient(name = "OracleClient")]
+trait Oracle {
+    fn lastprice(e: Env, asset: Asset) -> Option<PriceData>;
+}
+
+#[contracttype]
+pub enum DataKey {
+    Oracle,
+    // Slippage applied to every swap, 1.0 = 10000000_i128
+    Slippage,
+    // Token address -> ticker used to look up its price from the oracle
+    Ticker(Address),
+    // Amount added to the output every swap reports without being paid
+    Overreport,
+}
+
+#[contracterror]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+#[repr(u32)]
+pub enum DexMockError {
+    InvalidSlippage = 1,
+    UnknownToken = 2,
+    NoLastPrice = 3,
+    InvalidAmount = 4,
+}
+
+/// Swaps at oracle prices minus a configurable slippage, paying out of its own balance.
+/// `min_amount_out` is not enforced and the reported output can be inflated, so that the callers'
+/// own slippage checks can be tested.
+#[contract]
+pub struct DexMock;
+
+#[contractimpl]
+impl DexMock {
+    pub fn __constructor(e: Env, oracle: Address, slippage: i128) {
+        e.storage().instance().set(&DataKey::Oracle, &oracle);
+        Self::set_slippage(e, slippage);
+    }
+
+    pub fn set_slippage(e: Env, slippage: i128) {
+        if !(0..FIXED_POINT_ONE).contains(&slippage) {
+            panic_with_error!(&e, DexMockError::InvalidSlippage);
+        }
+        e.storage().instance().set(&DataKey::Slippage, &slippage);
+    }
+
+    pub fn set_overreport(e: Env, amount: i128) {
+        e.storage().instance().set(&DataKey::Overreport, &amount);
+    }
+
+    pub fn set_ticker(e: Env, token: Address, ticker: Symbol) {
+        e.storage()
+            .persistent()
+            .set(&DataKey::Ticker(token), &ticker);
+    }
+
+    /// Amount of `token_out` a swap of `amount_in` would return.
+    pub fn quote(e: Env, token_in: Address, token_out: Address, amount_in: i128) -> i128 {
+        if amount_in < 0 {
+            panic_with_error!(&e, DexMockError::InvalidAmount);
+        }
+        let slippage: i128 = e.storage().instance().get(&DataKey::Slippage).unwrap();
+
+        amount_in * Self::price(&e, &token_in) / Self::price(&e, &token_out)
+            * (FIXED_POINT_ONE - slippage)
+            / FIXED_POINT_ONE
+    }
+
+    fn price(e: &Env, token: &Address) -> i128 {
+        let ticker: Symbol = e
+            .storage()
+            .persistent()
+            .get(&DataKey::Ticker(token.clone()))
+            .unwrap_or_else(|| panic_with_error!(e, DexMockError::UnknownToken));
+        let oracle: Address = e.storage().instance().get(&DataKey::Oracle).unwrap();
+
+        OracleClient::new(e, &oracle)
+            .lastprice(&Asset::Other(ticker))
+            .unwrap_or_else(|| panic_with_error!(e, DexMockError::NoLastPrice))
+            .price
+    }
+}
+
+#[contractimpl]
+impl SwapRouter for DexMock {
+    fn swap(
+        e: Env,
+        token_in: Address,
+        token_out: Address,
+        amount_in: i128,
+        _min_amount_out: i128,
+        to: Address,
+    ) -> i128 {
+        let amount_out = Self::quote(e.clone(), token_in, token_out.clone(), amount_in);
+        token::Client::new(&e, &token_out).transfer(
+            &e.current_contract_address(),
+            &to,
+            &amount_out,
+        );
+        let overreport: i128 = e
+            .storage()
+            .instance()
+            .get(&DataKey::Overreport)
+            .unwrap_or(0);
+        amount_out + overreport
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use soroban_sdk::{contract, contractimpl, testutils::Address as _, token::StellarAssetClient};
+
+    #[contract]
+    struct OracleMock;
+
+    #[contractimpl]
+    impl OracleMock {
+        pub fn lastprice(e: Env, asset: Asset) -> Option<PriceData> {
+            let price = match asset {
+                Asset::Other(ticker) if ticker == Symbol::new(&e, "XLM") => 2_000_000,
+                _ => 10_000_000,
+            };
+            Some(PriceData {
+                price,
+                timestamp: 1,
+            })
+        }
+    }
+
+    #[test]
+    fn swap_at_oracle_price_with_slippage() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths();
+        let admin = Address::generate(&e);
+        let user = Address::generate(&e);
+        let xlm = e
+            .register_stellar_asset_contract_v2(admin.clone())
+            .address();
+        let usdc = e.register_stellar_asset_contract_v2(admin).address();
+
+        let oracle = e.register(OracleMock, ());
+        let dex = e.register(DexMock, (oracle, 100_000_i128)); // 1%
+        let dex_client = DexMockClient::new(&e, &dex);
+        dex_client.set_ticker(&xlm, &Symbol::new(&e, "XLM"));
+        dex_client.set_ticker(&usdc, &Symbol::new(&e, "USDC"));
+        StellarAssetClient::new(&e, &usdc).mint(&dex, &1_000);
+
+        // ACT
+        let amount_out = dex_client.swap(&xlm, &usdc, &1_000, &0, &user);
+
+        // ASSERT
+        assert_eq!(amount_out, 198);
+        assert_eq!(token::Client::new(&e, &usdc).balance(&user), 198);
+        assert_eq!(dex_client.quote(&usdc, &xlm, &100), 495);
+    }
+}
@@ -1,16 +1,56 @@
+use crate::dto::{AccountSummary, LeverageTarget, LoanPage, LoanSummary, OperationPreview};
 use crate::error::LoanManagerError;
 use crate::oracle::{self, Asset};
-use crate::storage::{self, Loan, LoanId, NewLoan};
-
-use soroban_sdk::{contract, contractimpl, token, Address, BytesN, Env, Symbol, Vec};
+use crate::storage::{
+    self, AccrualOutcome, AdaptiveRateParams, AssetCategory, FixedRate, FixedRateConfig,
+    InterestRateParams, KeeperReward, LiquidationAuction, LiquidationOutcome, Loan, LoanId,
+    LoanLimits, NewLoan, OperatorScope, Trigger, TriggerAction, HEALTH_FACTOR_THRESHOLD,
+};
+use soroban_sdk::{
+    contract, contractimpl, panic_with_error, token, vec,
+    xdr::{ScErrorCode, ScErrorType},
+    Address, BytesN, Env, Error, Executable, InvokeError, Symbol, Vec,
+};
+use swap_router::SwapRouterClient;
 
 mod loan_pool {
     soroban_sdk::contractimport!(file = "../../target/wasm32v1-none/release/loan_pool.wasm");
 }
 
+const FIXED_POINT_ONE: i128 = 10_000_000;
+const SECONDS_IN_YEAR: u64 = 31_556_926;
+// Amount of oracle records used for the TWAP price, 12 * 5 min = 1h average.
+const TWAP_DATA_POINTS: u32 = 12;
+// Fee paid to the keeper that executes a trigger, as a share of the repaid value.
+const TRIGGER_KEEPER_FEE: i128 = 50_000; // 0.5%
+
+// Maximum amount of loans liquidated in one `liquidate_batch` call.
+const MAX_LIQUIDATION_BATCH: u32 = 5;
+
+// Maximum amount of loans returned by one `list_loans` call.
+const MAX_LOANS_PAGE: u32 = 50;
+
+// Maximum amount of loans whose storage can be extended in one `bump` call.
+const MAX_BUMP_LOANS: u32 = 20;
+
+// Maximum amount of loans accrued in one `accrue_loans` call.
+const MAX_ACCRUE_LOANS: u32 = 20;
+
 #[contract]
 struct LoanManager;
 
+/// A validated liquidation that is ready to be executed.
+struct Liquidation {
+    loan: Loan,
+    amount: i128,
+    collateral_amount_bonus: i128,
+    // Part of `collateral_amount_bonus` that is paid on top of the repaid value.
+    bonus_amount: i128,
+    // Part of `bonus_amount` that goes to the protocol.
+    protocol_bonus: i128,
+    new_loan: Loan,
+}
+
 #[allow(dead_code)]
 #[contractimpl]
 impl LoanManager {
@@ -100,6 +140,263 @@ impl LoanManager {
         Ok(())
     }
 
+    /// Configure the Dutch auction for liquidation bonuses. Until this is set, the bonus is fixed
+    /// to half of the collateral pool's margin, (1 - collateral factor) / 2.
+    pub fn set_liquidation_auction(
+        e: &Env,
+        auction: LiquidationAuction,
+    ) -> Result<(), LoanManagerError> {
+        let admin = storage::read_admin(e)?;
+        admin.require_auth();
+
+        if auction.min_bonus < 0
+            || auction.max_bonus < auction.min_bonus
+            || auction.max_bonus >= FIXED_POINT_ONE
+            || auction.duration == 0
+        {
+            return Err(LoanManagerError::InvalidLiquidationAuction);
+        }
+
+        storage::write_liquidation_auction(e, &auction);
+        Ok(())
+    }
+
+    pub fn get_liquidation_auction(e: &Env) -> Option<LiquidationAuction> {
+        storage::read_liquidation_auction(e)
+    }
+
+    /// Get the bonus rate a liquidator of the loan would get right now. 1.0 = 10000000_i128
+    pub fn get_liquidation_bonus(e: &Env, loan_id: LoanId) -> Result<i128, LoanManagerError> {
+        let loan = Self::get_loan(e, loan_id)?;
+        Self::liquidation_bonus(e, &loan)
+    }
+
+    /// Create or update an asset category for efficiency mode. Liquidating a loan in the
+    /// category has to raise its health factor, so the bonus has to fit in the category's margin.
+    pub fn set_asset_category(
+        e: &Env,
+        category_id: u32,
+        category: AssetCategory,
+    ) -> Result<(), LoanManagerError> {
+        let admin = storage::read_admin(e)?;
+        admin.require_auth();
+
+        let liquidation_multiplier = category
+            .liquidation_bonus
+            .checked_add(FIXED_POINT_ONE)
+            .and_then(|bonus| bonus.checked_mul(category.collateral_factor))
+            .ok_or(LoanManagerError::OverOrUnderFlow)?
+            / FIXED_POINT_ONE;
+        if category.collateral_factor <= 0
+            || category.collateral_factor >= FIXED_POINT_ONE
+            || category.liquidation_bonus < 0
+            || liquidation_multiplier >= FIXED_POINT_ONE
+        {
+            return Err(LoanManagerError::InvalidAssetCategory);
+        }
+
+        storage::write_asset_category(e, category_id, &category);
+        Ok(())
+    }
+
+    pub fn get_asset_category(e: &Env, category_id: u32) -> Option<AssetCategory> {
+        storage::read_asset_category(e, category_id)
+    }
+
+    /// Put a pool in an asset category, or take it out with `None`.
+    pub fn set_pool_category(
+        e: &Env,
+        pool_address: Address,
+        category_id: Option<u32>,
+    ) -> Result<(), LoanManagerError> {
+        let admin = storage::read_admin(e)?;
+        admin.require_auth();
+
+        if !storage::read_pool_addresses(e).contains(&pool_address) {
+            return Err(LoanManagerError::AddressNotFound);
+        }
+        if let Some(category_id) = category_id {
+            storage::read_asset_category(e, category_id)
+                .ok_or(LoanManagerError::InvalidAssetCategory)?;
+        }
+
+        storage::write_pool_category(e, &pool_address, category_id);
+        Ok(())
+    }
+
+    pub fn get_pool_category(e: &Env, pool_address: Address) -> Option<u32> {
+        storage::read_pool_category(e, &pool_address)
+    }
+
+    /// Set the share of the liquidation bonus paid out of a pool's collateral that goes to the
+    /// protocol instead of the liquidator. 1.0 = 10000000_i128. The pool validates the share, a
+    /// share it rejects returns `InvalidLiquidationFeeShare`.
+    pub fn set_liquidation_fee_share(
+        e: &Env,
+        pool_address: Address,
+        share: i128,
+    ) -> Result<(), LoanManagerError> {
+        let admin = storage::read_admin(e)?;
+        admin.require_auth();
+
+        if !storage::read_pool_addresses(e).contains(&pool_address) {
+            return Err(LoanManagerError::AddressNotFound);
+        }
+
+        let result = loan_pool::Client::new(e, &pool_address).try_set_liquidation_fee_share(&share);
+        Self::pool_setter_result(
+            e,
+            result,
+            loan_pool::LoanPoolError::InvalidLiquidationFeeShare,
+            LoanManagerError::InvalidLiquidationFeeShare,
+        )
+    }
+
+    /// Set the interest rate curve of a pool. The pool validates the curve, parameters it rejects
+    /// return `InvalidInterestRateParams`.
+    pub fn set_interest_rate_params(
+        e: &Env,
+        pool_address: Address,
+        params: InterestRateParams,
+    ) -> Result<(), LoanManagerError> {
+        let admin = storage::read_admin(e)?;
+        admin.require_auth();
+
+        if !storage::read_pool_addresses(e).contains(&pool_address) {
+            return Err(LoanManagerError::AddressNotFound);
+        }
+
+        let result = loan_pool::Client::new(e, &pool_address).try_set_interest_rate_params(
+            &loan_pool::InterestRateParams {
+                base_rate: params.base_rate,
+                rate_at_panic: params.rate_at_panic,
+                max_rate: params.max_rate,
+                panic_threshold: params.panic_threshold,
+            },
+        );
+        Self::pool_setter_result(
+            e,
+            result,
+            loan_pool::LoanPoolError::InvalidInterestRateParams,
+            LoanManagerError::InvalidInterestRateParams,
+        )
+    }
+
+    /// Switch a pool to the adaptive interest rate curve. The pool validates the parameters,
+    /// parameters it rejects return `InvalidInterestRateParams`. Setting a kinked curve with
+    /// `set_interest_rate_params` switches the pool back.
+    pub fn set_adaptive_rate_params(
+        e: &Env,
+        pool_address: Address,
+        params: AdaptiveRateParams,
+    ) -> Result<(), LoanManagerError> {
+        let admin = storage::read_admin(e)?;
+        admin.require_auth();
+
+        if !storage::read_pool_addresses(e).contains(&pool_address) {
+            return Err(LoanManagerError::AddressNotFound);
+        }
+
+        let result = loan_pool::Client::new(e, &pool_address).try_set_adaptive_rate_params(
+            &loan_pool::AdaptiveRateParams {
+                target_utilization: params.target_utilization,
+                initial_rate_at_target: params.initial_rate_at_target,
+                min_rate_at_target: params.min_rate_at_target,
+                max_rate_at_target: params.max_rate_at_target,
+                adjustment_speed: params.adjustment_speed,
+            },
+        );
+        Self::pool_setter_result(
+            e,
+            result,
+            loan_pool::LoanPoolError::InvalidInterestRateParams,
+            LoanManagerError::InvalidInterestRateParams,
+        )
+    }
+
+    /// Set the smallest debt and collateral loans can have in a pool. Repayments and
+    /// liquidations that would leave less than `min_borrow` outstanding have to close the loan.
+    pub fn set_loan_limits(
+        e: &Env,
+        pool_address: Address,
+        limits: LoanLimits,
+    ) -> Result<(), LoanManagerError> {
+        let admin = storage::read_admin(e)?;
+        admin.require_auth();
+
+        if !storage::read_pool_addresses(e).contains(&pool_address) {
+            return Err(LoanManagerError::AddressNotFound);
+        }
+        if limits.min_borrow < 0 || limits.min_collateral < 0 {
+            return Err(LoanManagerError::InvalidLoanLimits);
+        }
+
+        storage::write_loan_limits(e, &pool_address, &limits);
+        Ok(())
+    }
+
+    pub fn get_loan_limits(e: &Env, pool_address: Address) -> LoanLimits {
+        storage::read_loan_limits(e, &pool_address)
+    }
+
+    /// Set the reward paid to keepers per loan in `accrue_loans`. A loan only earns the reward
+    /// once it has gone `min_interval` seconds without accruing. An amount of 0 turns it off.
+    pub fn set_keeper_reward(
+        e: &Env,
+        token_address: Address,
+        amount_per_loan: i128,
+        min_interval: u64,
+    ) -> Result<(), LoanManagerError> {
+        let admin = storage::read_admin(e)?;
+        admin.require_auth();
+
+        if amount_per_loan < 0 {
+            return Err(LoanManagerError::InvalidAmount);
+        }
+
+        storage::write_keeper_reward(
+            e,
+            &KeeperReward {
+                token_address,
+                amount_per_loan,
+                min_interval,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn get_keeper_reward(e: &Env) -> Option<KeeperReward> {
+        storage::read_keeper_reward(e)
+    }
+
+    /// Allow `operator` to perform operations of the given scope on all of `owner`'s loans.
+    pub fn approve_operator(
+        e: &Env,
+        owner: Address,
+        operator: Address,
+        scope: OperatorScope,
+    ) -> Result<(), LoanManagerError> {
+        owner.require_auth();
+        storage::write_operator(e, &owner, &operator, scope);
+        Ok(())
+    }
+
+    /// Remove a previously given operator approval.
+    pub fn revoke_operator(
+        e: &Env,
+        owner: Address,
+        operator: Address,
+        scope: OperatorScope,
+    ) -> Result<(), LoanManagerError> {
+        owner.require_auth();
+        storage::remove_operator(e, &owner, &operator, scope);
+        Ok(())
+    }
+
+    pub fn is_operator(e: &Env, owner: Address, operator: Address, scope: OperatorScope) -> bool {
+        storage::is_operator(e, &owner, &operator, scope)
+    }
+
     /// Initialize a new loan
     pub fn create_loan(
         e: Env,
@@ -111,61 +408,105 @@ impl LoanManager {
     ) -> Result<Loan, LoanManagerError> {
         user.require_auth();
 
-        let pool_addresses = storage::read_pool_addresses(&e);
-        if !pool_addresses.contains(&borrowed_from) {
-            return Err(LoanManagerError::InvalidLoanToken);
-        }
-        if !pool_addresses.contains(&collateral_from) {
-            return Err(LoanManagerError::InvalidCollateralToken);
-        }
+        Self::open_loan(
+            &e,
+            user,
+            borrowed,
+            borrowed_from,
+            collateral,
+            collateral_from,
+        )
+    }
 
-        let collateral_pool_client = loan_pool::Client::new(&e, &collateral_from);
-        let borrow_pool_client = loan_pool::Client::new(&e, &borrowed_from);
+    /// Initialize a new loan at a fixed interest rate that runs until `maturity`. The rate is
+    /// the borrow pool's variable rate after the loan is taken plus the premium configured for
+    /// the borrow pool.
+    pub fn create_fixed_rate_loan(
+        e: Env,
+        user: Address,
+        borrowed: i128,
+        borrowed_from: Address,
+        collateral: i128,
+        collateral_from: Address,
+        maturity: u64,
+    ) -> Result<Loan, LoanManagerError> {
+        user.require_auth();
 
-        let token_currency = borrow_pool_client.get_currency();
-        let collateral_currency = collateral_pool_client.get_currency();
-        let health_factor: i128 = Self::calculate_health_factor(
+        let config = storage::read_fixed_rate_config(&e, &borrowed_from)
+            .ok_or(LoanManagerError::FixedRateNotEnabled)?;
+        let now = e.ledger().timestamp();
+        if maturity <= now {
+            return Err(LoanManagerError::InvalidMaturity);
+        }
+
+        let loan = Self::open_loan(
             &e,
-            token_currency.ticker,
+            user,
             borrowed,
-            collateral_currency.ticker,
+            borrowed_from.clone(),
             collateral,
-            collateral_from.clone(),
+            collateral_from,
         )?;
 
-        // Health factor has to be over 1.2 for the loan to be initialized.
-        // Health factor is defined as so: 1.0 = 10000000_i128
-        const HEALTH_FACTOR_THRESHOLD: i128 = 10000000;
-        assert!(
-            health_factor > HEALTH_FACTOR_THRESHOLD,
-            "Health factor must be over {HEALTH_FACTOR_THRESHOLD} to create a new loan!"
+        let borrow_pool_client = loan_pool::Client::new(&e, &borrowed_from);
+        let rate = borrow_pool_client
+            .get_interest()
+            .checked_add(config.premium)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        storage::write_fixed_rate(
+            &e,
+            &loan.loan_id,
+            &FixedRate {
+                rate,
+                penalty_rate: config.penalty_rate,
+                maturity,
+                last_updated: now,
+            },
         );
 
-        // Deposit collateral
-        let collateral_amount = collateral_pool_client.deposit_collateral(&user, &collateral);
+        storage::EventFixedRateLoanCreated {
+            loan_id: loan.loan_id.clone(),
+            rate,
+            maturity,
+        }
+        .publish(&e);
 
-        // Borrow the funds
-        let borrowed_amount = borrow_pool_client.borrow(&user, &borrowed);
+        Ok(loan)
+    }
 
-        let unpaid_interest = 0;
+    /// Set the terms a pool offers for new fixed-rate loans. Loans already taken keep the
+    /// terms they were created with.
+    pub fn set_fixed_rate_config(
+        e: &Env,
+        pool_address: Address,
+        config: FixedRateConfig,
+    ) -> Result<(), LoanManagerError> {
+        let admin = storage::read_admin(e)?;
+        admin.require_auth();
 
-        let new_loan = NewLoan {
-            borrower_address: user.clone(),
-            borrowed_amount,
-            borrowed_from,
-            collateral_amount,
-            collateral_from,
-            health_factor,
-            unpaid_interest,
-            last_accrual: borrow_pool_client.get_accrual(),
-        };
+        if !storage::read_pool_addresses(e).contains(&pool_address) {
+            return Err(LoanManagerError::AddressNotFound);
+        }
+        if config.premium < 0 || config.penalty_rate < 0 {
+            return Err(LoanManagerError::InvalidFixedRateConfig);
+        }
 
-        let loan = storage::create_loan(&e, user.clone(), new_loan);
+        storage::write_fixed_rate_config(e, &pool_address, &config);
+        Ok(())
+    }
 
-        Ok(loan)
+    /// Terms a pool offers for new fixed-rate loans, `None` if it offers none.
+    pub fn get_fixed_rate_config(e: &Env, pool_address: Address) -> Option<FixedRateConfig> {
+        storage::read_fixed_rate_config(e, &pool_address)
+    }
+
+    /// Get the locked terms of a fixed-rate loan, `None` for variable-rate loans.
+    pub fn get_fixed_rate(e: &Env, loan_id: LoanId) -> Option<FixedRate> {
+        storage::read_fixed_rate(e, &loan_id)
     }
 
-    /// add interest to a loan
+    /// add interest to a loan. Failing pool and oracle calls are returned as errors, so
+    /// `accrue_loans` can skip the loan instead of aborting the batch.
     pub fn add_interest(e: &Env, loan_id: LoanId) -> Result<Loan, LoanManagerError> {
         let Loan {
             borrowed_from,
@@ -180,29 +521,32 @@ impl LoanManager {
         let borrow_pool_client = loan_pool::Client::new(e, &borrowed_from);
         let collateral_pool_client = loan_pool::Client::new(e, &collateral_from);
 
-        let token_ticker = borrow_pool_client.get_currency().ticker;
-        let token_collateral_ticker = collateral_pool_client.get_currency().ticker;
-
-        const DECIMAL: i128 = 10000000;
-
-        borrow_pool_client.add_interest_to_accrual();
-        let current_accrual = borrow_pool_client.get_accrual();
-        let interest_since_update_multiplier = current_accrual
-            .checked_mul(DECIMAL)
-            .ok_or(LoanManagerError::OverOrUnderFlow)?
-            .checked_div(last_accrual)
-            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        let token_ticker = Self::pool_result(borrow_pool_client.try_get_currency())?.ticker;
+        let token_collateral_ticker =
+            Self::pool_result(collateral_pool_client.try_get_currency())?.ticker;
 
-        let new_borrowed_amount = borrowed_amount
-            .checked_mul(interest_since_update_multiplier)
-            .ok_or(LoanManagerError::OverOrUnderFlow)?
-            .checked_div(DECIMAL)
-            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        // Fixed-rate loans accrue against their locked rate instead of the pool's accrual index.
+        let fixed_rate = storage::read_fixed_rate(e, &loan_id);
+        let (new_borrowed_amount, current_accrual) = match &fixed_rate {
+            Some(fixed_rate) => (
+                Self::fixed_rate_accrued_amount(e, borrowed_amount, fixed_rate)?,
+                last_accrual,
+            ),
+            None => {
+                Self::pool_result(borrow_pool_client.try_add_interest_to_accrual())?;
+                let current_accrual = Self::pool_result(borrow_pool_client.try_get_accrual())?;
+                (
+                    Self::accrued_amount(borrowed_amount, last_accrual, current_accrual)?,
+                    current_accrual,
+                )
+            }
+        };
 
-        let new_health_factor = Self::calculate_health_factor(
+        let new_health_factor = Self::calculate_loan_health_factor(
             e,
             token_ticker,
             new_borrowed_amount,
+            borrowed_from.clone(),
             token_collateral_ticker,
             collateral_amount,
             collateral_from.clone(),
@@ -217,7 +561,20 @@ impl LoanManager {
 
         // Update the pool's positions to reflect the increased liabilities from interest
         if borrow_change > 0 {
-            borrow_pool_client.increase_liabilities(&loan_id.borrower_address, &borrow_change);
+            Self::pool_result(
+                borrow_pool_client
+                    .try_increase_liabilities(&loan_id.borrower_address, &borrow_change),
+            )?;
+            if let Some(fixed_rate) = fixed_rate {
+                storage::write_fixed_rate(
+                    e,
+                    &loan_id,
+                    &FixedRate {
+                        last_updated: e.ledger().timestamp(),
+                        ..fixed_rate
+                    },
+                );
+            }
         }
 
         let updated_loan = Loan {
@@ -232,10 +589,114 @@ impl LoanManager {
         };
 
         storage::write_loan(e, &loan_id, &updated_loan);
+        storage::write_loan_accrued_at(e, &loan_id, e.ledger().timestamp());
 
         Ok(updated_loan)
     }
 
+    /// Add interest to up to `MAX_ACCRUE_LOANS` loans at once. Every entry gets an outcome in the
+    /// returned list, so a loan that no longer exists or fails to accrue does not abort the
+    /// batch. If a keeper reward is set, the keeper is paid for every loan whose debt grew and
+    /// that had gone more than the reward's `min_interval` without accruing, up to what the
+    /// manager holds of the reward token.
+    pub fn accrue_loans(
+        e: &Env,
+        keeper: Address,
+        loan_ids: Vec<LoanId>,
+    ) -> Result<Vec<AccrualOutcome>, LoanManagerError> {
+        keeper.require_auth();
+        if loan_ids.len() > MAX_ACCRUE_LOANS {
+            return Err(LoanManagerError::InvalidAmount);
+        }
+
+        let keeper_reward = storage::read_keeper_reward(e);
+        let now = e.ledger().timestamp();
+        let mut outcomes = vec![e];
+        let mut accrued: u32 = 0;
+        let mut failed: u32 = 0;
+        let mut rewarded: i128 = 0;
+        for loan_id in loan_ids.iter() {
+            let Some(loan) = storage::read_loan(e, &loan_id) else {
+                outcomes.push_back(AccrualOutcome::LoanNotFound);
+                continue;
+            };
+            let accrued_at = storage::read_loan_accrued_at(e, &loan_id);
+            let Ok(updated_loan) = Self::add_interest(e, loan_id) else {
+                failed += 1;
+                outcomes.push_back(AccrualOutcome::Failed);
+                continue;
+            };
+            accrued += 1;
+
+            let is_due = keeper_reward.as_ref().is_some_and(|reward| {
+                accrued_at
+                    .is_none_or(|accrued_at| now.saturating_sub(accrued_at) > reward.min_interval)
+            });
+            if is_due && updated_loan.borrowed_amount > loan.borrowed_amount {
+                rewarded += 1;
+                outcomes.push_back(AccrualOutcome::Rewarded);
+            } else {
+                outcomes.push_back(AccrualOutcome::Accrued);
+            }
+        }
+
+        let mut reward = 0;
+        if let Some(KeeperReward {
+            token_address,
+            amount_per_loan,
+            ..
+        }) = keeper_reward
+        {
+            let token_client = token::Client::new(e, &token_address);
+            reward = amount_per_loan
+                .checked_mul(rewarded)
+                .ok_or(LoanManagerError::OverOrUnderFlow)?
+                .min(token_client.balance(&e.current_contract_address()));
+            if reward > 0 {
+                token_client.transfer(&e.current_contract_address(), &keeper, &reward);
+            }
+        }
+
+        storage::EventLoansAccrued {
+            keeper,
+            accrued,
+            failed,
+            reward,
+        }
+        .publish(e);
+
+        Ok(outcomes)
+    }
+
+    /// Extend the TTL of the contract, its configuration and the given loans, including the
+    /// borrower's positions in both pools. Anyone can call this. Loans that no longer exist are
+    /// skipped. Returns the amount of loans that were bumped.
+    pub fn bump(e: &Env, loan_ids: Vec<LoanId>) -> Result<u32, LoanManagerError> {
+        if loan_ids.len() > MAX_BUMP_LOANS {
+            return Err(LoanManagerError::InvalidAmount);
+        }
+
+        storage::bump_config(e);
+
+        let mut bumped: u32 = 0;
+        for loan_id in loan_ids.iter() {
+            let Some(loan) = storage::bump_loan(e, &loan_id) else {
+                continue;
+            };
+            let users = vec![e, loan_id.borrower_address.clone()];
+            loan_pool::Client::new(e, &loan.borrowed_from).bump(&users);
+            if loan.collateral_from != loan.borrowed_from {
+                loan_pool::Client::new(e, &loan.collateral_from).bump(&users);
+            }
+            bumped += 1;
+        }
+
+        Ok(bumped)
+    }
+
+    /// Health factor of a debt against collateral, using the collateral pool's own collateral
+    /// factor. Use `calculate_loan_health_factor` for the health factor of a loan between two
+    /// pools, which accounts for asset categories.
     pub fn calculate_health_factor(
         e: &Env,
         token_ticker: Symbol,
@@ -244,57 +705,141 @@ impl LoanManager {
         token_collateral_amount: i128,
         token_collateral_address: Address,
     ) -> Result<i128, LoanManagerError> {
-        const DECIMAL_TO_INT_MULTIPLIER: i128 = 10000000;
-        let reflector_address = storage::read_oracle(e)?;
-        let reflector_contract = oracle::Client::new(e, &reflector_address);
-
-        // get the price and calculate the value of the collateral
-        let collateral_asset = Asset::Other(token_collateral_ticker);
-
-        let collateral_pool_client = loan_pool::Client::new(e, &token_collateral_address);
-        let collateral_factor = collateral_pool_client.get_collateral_factor();
-
-        let amount_of_data_points = 12; // 12 * 5 min = 1h average
-        let collateral_asset_price = reflector_contract
-            .twap(&collateral_asset, &amount_of_data_points)
-            .ok_or(LoanManagerError::NoLastPrice)?;
-        let collateral_value = collateral_asset_price
-            .checked_mul(token_collateral_amount)
-            .ok_or(LoanManagerError::OverOrUnderFlow)?
-            .checked_mul(collateral_factor)
-            .ok_or(LoanManagerError::OverOrUnderFlow)?
-            .checked_div(DECIMAL_TO_INT_MULTIPLIER)
-            .ok_or(LoanManagerError::OverOrUnderFlow)?;
-
-        // get the price and calculate the value of the borrowed asset
-        let borrowed_asset = Asset::Other(token_ticker);
-        let asset_price = reflector_contract
-            .twap(&borrowed_asset, &amount_of_data_points)
-            .ok_or(LoanManagerError::NoLastPrice)?;
-        let borrowed_value = asset_price
-            .checked_mul(token_amount)
-            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        let collateral_factor =
+            loan_pool::Client::new(e, &token_collateral_address).get_collateral_factor();
+        let collateral_asset_price = Self::twap_price(e, token_collateral_ticker)?;
+        let asset_price = Self::twap_price(e, token_ticker)?;
+
+        Self::health_factor_at_prices(
+            token_amount,
+            asset_price,
+            token_collateral_amount,
+            collateral_asset_price,
+            collateral_factor,
+        )
+    }
 
-        let health_factor = collateral_value
-            .checked_mul(DECIMAL_TO_INT_MULTIPLIER)
-            .ok_or(LoanManagerError::OverOrUnderFlow)?
-            .checked_div(borrowed_value)
-            .ok_or(LoanManagerError::OverOrUnderFlow)?;
-        Ok(health_factor)
+    /// Health factor of a debt borrowed from the pool at `token_address` against collateral in
+    /// the pool at `token_collateral_address`. If both pools are in the same asset category,
+    /// the category's collateral factor is used.
+    pub fn calculate_loan_health_factor(
+        e: &Env,
+        token_ticker: Symbol,
+        token_amount: i128,
+        token_address: Address,
+        token_collateral_ticker: Symbol,
+        token_collateral_amount: i128,
+        token_collateral_address: Address,
+    ) -> Result<i128, LoanManagerError> {
+        let collateral_factor =
+            Self::collateral_factor(e, &token_address, &token_collateral_address)?;
+        let collateral_asset_price = Self::twap_price(e, token_collateral_ticker)?;
+        let asset_price = Self::twap_price(e, token_ticker)?;
+
+        Self::health_factor_at_prices(
+            token_amount,
+            asset_price,
+            token_collateral_amount,
+            collateral_asset_price,
+            collateral_factor,
+        )
     }
 
     pub fn get_oracle(e: Env) -> Result<Address, LoanManagerError> {
         storage::read_oracle(&e)
     }
 
-    /// Get the loans for a specific user
-    pub fn get_loans(e: &Env, user: Address) -> Vec<Loan> {
-        storage::read_user_loans(e, &user)
+    /// Set the swap router the manager trades through. The router has to be a deployed contract
+    /// implementing the `swap_router::SwapRouter` interface, and cannot be the manager, the
+    /// oracle or one of the pools.
+    pub fn set_swap_router(e: &Env, router: Address) -> Result<(), LoanManagerError> {
+        let admin = storage::read_admin(e)?;
+        admin.require_auth();
+
+        if !matches!(router.executable(), Some(Executable::Wasm(_)))
+            || router == e.current_contract_address()
+            || router == storage::read_oracle(e)?
+            || storage::read_pool_addresses(e).contains(&router)
+        {
+            return Err(LoanManagerError::InvalidSwapRouter);
+        }
+
+        storage::write_swap_router(e, &router);
+        Ok(())
     }
 
-    /// Get a single loan by id
-    pub fn get_loan(e: &Env, loan_id: LoanId) -> Result<Loan, LoanManagerError> {
-        storage::read_loan(e, &loan_id).ok_or(LoanManagerError::LoanNotFound)
+    pub fn get_swap_router(e: &Env) -> Result<Address, LoanManagerError> {
+        storage::read_swap_router(e)
+    }
+
+    /// Set the address the protocol's share of liquidation bonuses is sent to. Until one is set
+    /// it goes to the admin, so that it does not mix with the manager's own balance.
+    pub fn set_treasury(e: &Env, treasury: Address) -> Result<(), LoanManagerError> {
+        let admin = storage::read_admin(e)?;
+        admin.require_auth();
+
+        storage::write_treasury(e, &treasury);
+        Ok(())
+    }
+
+    pub fn get_treasury(e: &Env) -> Option<Address> {
+        storage::read_treasury(e)
+    }
+
+    /// Get the loans for a specific user
+    pub fn get_loans(e: &Env, user: Address) -> Vec<Loan> {
+        storage::read_user_loans(e, &user)
+    }
+
+    /// List the loans of a user, starting at position `cursor` of the user's loan index. At most
+    /// `MAX_LOANS_PAGE` loans are returned per call. Closing a loan moves the user's last loan
+    /// into its position, so the order is not stable between calls.
+    pub fn get_loans_page(e: &Env, user: Address, cursor: u32, limit: u32) -> Vec<Loan> {
+        storage::read_user_loans_page(e, &user, cursor, limit.min(MAX_LOANS_PAGE))
+    }
+
+    /// Amount of open loans of a user.
+    pub fn user_loan_count(e: &Env, user: Address) -> u32 {
+        storage::read_user_loan_count(e, &user)
+    }
+
+    /// List open loans from the global loan index, starting at position `cursor`. At most
+    /// `MAX_LOANS_PAGE` loans are returned per call. Closing a loan moves the last loan of the
+    /// index into its position, so the order is not stable between calls. The page's
+    /// generation changes whenever that happens.
+    pub fn list_loans(e: &Env, cursor: u32, limit: u32) -> LoanPage {
+        LoanPage {
+            loans: storage::read_loans_page(e, cursor, limit.min(MAX_LOANS_PAGE)),
+            generation: storage::read_loan_index_generation(e),
+        }
+    }
+
+    /// Add loans created before the global loan index existed to it. At most `MAX_LOANS_PAGE`
+    /// loans can be passed per call, loans that do not exist or are already indexed are
+    /// skipped. Returns the amount of loans added.
+    pub fn index_loans(e: &Env, loan_ids: Vec<LoanId>) -> Result<u32, LoanManagerError> {
+        storage::read_admin(e)?.require_auth();
+        if loan_ids.len() > MAX_LOANS_PAGE {
+            return Err(LoanManagerError::InvalidAmount);
+        }
+
+        let mut indexed = 0;
+        for loan_id in loan_ids.iter() {
+            if storage::index_loan(e, &loan_id) {
+                indexed += 1;
+            }
+        }
+        Ok(indexed)
+    }
+
+    /// Amount of open loans in the global loan index.
+    pub fn loan_count(e: &Env) -> u32 {
+        storage::read_loan_count(e)
+    }
+
+    /// Get a single loan by id
+    pub fn get_loan(e: &Env, loan_id: LoanId) -> Result<Loan, LoanManagerError> {
+        storage::read_loan(e, &loan_id).ok_or(LoanManagerError::LoanNotFound)
     }
 
     /// Get the price of a token
@@ -305,380 +850,4739 @@ impl LoanManager {
         let asset = Asset::Other(token);
 
         let asset_pricedata = reflector_contract
-            .lastprice(&asset)
+            .try_lastprice(&asset)
+            .map_err(|_| LoanManagerError::NoLastPrice)?
+            .map_err(|_| LoanManagerError::NoLastPrice)?
             .ok_or(LoanManagerError::NoLastPrice)?;
         Ok(asset_pricedata.price)
     }
 
-    pub fn repay(e: &Env, loan_id: LoanId, amount: i128) -> Result<(i128, i128), LoanManagerError> {
-        let user = loan_id.borrower_address.clone();
-        user.require_auth();
+    /// Summarise all loans of a user with interest accrued up to the current ledger. Nothing is
+    /// written, so the figures match what `add_interest` would store right now.
+    pub fn get_account_summary(e: &Env, user: Address) -> Result<AccountSummary, LoanManagerError> {
+        let mut summary = AccountSummary {
+            loans: Vec::new(e),
+            total_borrowed_value: 0,
+            total_collateral_value: 0,
+            total_borrowing_power: 0,
+        };
+        for loan in storage::read_user_loans(e, &user).iter() {
+            let loan_summary = Self::loan_summary(e, loan)?;
+            summary.total_borrowed_value = summary
+                .total_borrowed_value
+                .checked_add(loan_summary.borrowed_value)
+                .ok_or(LoanManagerError::OverOrUnderFlow)?;
+            summary.total_collateral_value = summary
+                .total_collateral_value
+                .checked_add(loan_summary.collateral_value)
+                .ok_or(LoanManagerError::OverOrUnderFlow)?;
+            summary.total_borrowing_power = summary
+                .total_borrowing_power
+                .checked_add(loan_summary.borrowing_power)
+                .ok_or(LoanManagerError::OverOrUnderFlow)?;
+            summary.loans.push_back(loan_summary);
+        }
+        Ok(summary)
+    }
 
-        let Loan {
-            borrowed_amount,
-            borrowed_from,
-            collateral_amount,
+    /// Preview `create_loan` without executing it.
+    pub fn preview_create_loan(
+        e: &Env,
+        borrowed: i128,
+        borrowed_from: Address,
+        collateral: i128,
+        collateral_from: Address,
+    ) -> Result<OperationPreview, LoanManagerError> {
+        Self::require_trusted_pools(e, &borrowed_from, &collateral_from)?;
+        Self::require_loan_limits(e, borrowed, &borrowed_from, collateral, &collateral_from)?;
+
+        let health_factor = Self::calculate_loan_health_factor(
+            e,
+            loan_pool::Client::new(e, &borrowed_from)
+                .get_currency()
+                .ticker,
+            borrowed,
+            borrowed_from.clone(),
+            loan_pool::Client::new(e, &collateral_from)
+                .get_currency()
+                .ticker,
+            collateral,
             collateral_from,
-            unpaid_interest,
-            last_accrual,
-            ..
-        } = Self::add_interest(e, loan_id.clone())?;
+        )?;
+        if health_factor <= HEALTH_FACTOR_THRESHOLD {
+            return Err(LoanManagerError::HealthFactorTooLow);
+        }
 
-        assert!(
-            amount <= borrowed_amount,
-            "Amount can not be greater than borrowed amount!"
-        );
+        Ok(OperationPreview {
+            borrowed_amount: borrowed,
+            collateral_amount: collateral,
+            unpaid_interest: 0,
+            health_factor: Some(health_factor),
+            amount_in: 0,
+            amount_out: borrowed,
+            collateral_in: collateral,
+            collateral_out: 0,
+            protocol_fee: 0,
+            liquidation_bonus: 0,
+            protocol_bonus: 0,
+        })
+    }
 
-        let collateral_pool_client = loan_pool::Client::new(e, &collateral_from);
-        let borrow_pool_client = loan_pool::Client::new(e, &borrowed_from);
-        borrow_pool_client.repay(&user, &amount, &unpaid_interest);
+    /// Preview `repay` without executing it.
+    pub fn preview_repay(
+        e: &Env,
+        loan_id: LoanId,
+        amount: i128,
+    ) -> Result<OperationPreview, LoanManagerError> {
+        let loan = Self::accrued_loan(e, Self::get_loan(e, loan_id)?)?;
+        if amount > loan.borrowed_amount {
+            return Err(LoanManagerError::InvalidAmount);
+        }
 
-        let new_unpaid_interest = if amount < unpaid_interest {
-            unpaid_interest
-                .checked_sub(amount)
-                .ok_or(LoanManagerError::OverOrUnderFlow)?
-        } else {
-            0
-        };
+        let protocol_fee = Self::protocol_fee(amount, loan.unpaid_interest);
+        let new_loan = Self::repaid_loan(e, loan, amount)?;
+        Ok(OperationPreview {
+            amount_in: amount,
+            protocol_fee,
+            ..Self::loan_preview(new_loan)
+        })
+    }
 
-        let new_borrowed_amount = borrowed_amount
+    /// Preview `repay_and_close_manager` without executing it. `amount_in` is the least
+    /// `max_allowed_amount` that closes the loan.
+    pub fn preview_repay_and_close(
+        e: &Env,
+        loan_id: LoanId,
+    ) -> Result<OperationPreview, LoanManagerError> {
+        let loan = Self::accrued_loan(e, Self::get_loan(e, loan_id)?)?;
+
+        Ok(OperationPreview {
+            borrowed_amount: 0,
+            collateral_amount: 0,
+            unpaid_interest: 0,
+            health_factor: None,
+            amount_in: loan.borrowed_amount,
+            amount_out: 0,
+            collateral_in: 0,
+            collateral_out: loan.collateral_amount,
+            protocol_fee: Self::protocol_fee(loan.borrowed_amount, loan.unpaid_interest),
+            liquidation_bonus: 0,
+            protocol_bonus: 0,
+        })
+    }
+
+    /// Preview `add_collateral` without executing it.
+    pub fn preview_add_collateral(
+        e: &Env,
+        loan_id: LoanId,
+        amount: i128,
+    ) -> Result<OperationPreview, LoanManagerError> {
+        if amount <= 0 {
+            return Err(LoanManagerError::InvalidAmount);
+        }
+        let loan = Self::accrued_loan(e, Self::get_loan(e, loan_id)?)?;
+
+        let new_collateral_amount = loan
+            .collateral_amount
+            .checked_add(amount)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        let new_loan = Self::loan_with_collateral(e, loan, new_collateral_amount)?;
+        Ok(OperationPreview {
+            collateral_in: amount,
+            ..Self::loan_preview(new_loan)
+        })
+    }
+
+    /// Preview `withdraw_collateral` without executing it.
+    pub fn preview_withdraw_collateral(
+        e: &Env,
+        loan_id: LoanId,
+        amount: i128,
+    ) -> Result<OperationPreview, LoanManagerError> {
+        let loan = Self::accrued_loan(e, Self::get_loan(e, loan_id)?)?;
+        if amount <= 0 || amount > loan.collateral_amount {
+            return Err(LoanManagerError::InvalidAmount);
+        }
+
+        let new_collateral_amount = loan
+            .collateral_amount
             .checked_sub(amount)
             .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        let new_loan = Self::loan_with_collateral(e, loan, new_collateral_amount)?;
+        if new_loan.health_factor <= HEALTH_FACTOR_THRESHOLD {
+            return Err(LoanManagerError::HealthFactorTooLow);
+        }
+        Ok(OperationPreview {
+            collateral_out: amount,
+            ..Self::loan_preview(new_loan)
+        })
+    }
 
-        let new_health_factor = Self::calculate_health_factor(
-            e,
-            borrow_pool_client.get_currency().ticker,
-            new_borrowed_amount,
-            collateral_pool_client.get_currency().ticker,
-            collateral_amount,
-            collateral_from.clone(),
-        )?;
+    /// Preview `liquidate` without executing it.
+    pub fn preview_liquidate(
+        e: &Env,
+        loan_id: LoanId,
+        amount: i128,
+    ) -> Result<OperationPreview, LoanManagerError> {
+        let loan = Self::accrued_loan(e, Self::get_loan(e, loan_id)?)?;
+        let Liquidation {
+            loan,
+            amount,
+            collateral_amount_bonus,
+            bonus_amount,
+            protocol_bonus,
+            new_loan,
+        } = Self::quote_liquidation(e, loan, amount)?;
+
+        Ok(OperationPreview {
+            amount_in: amount,
+            collateral_out: collateral_amount_bonus,
+            protocol_fee: Self::protocol_fee(amount, loan.unpaid_interest),
+            liquidation_bonus: bonus_amount,
+            protocol_bonus,
+            ..Self::loan_preview(new_loan)
+        })
+    }
 
-        storage::write_loan(
-            e,
-            &loan_id,
-            &Loan {
-                loan_id: loan_id.clone(),
-                borrowed_amount: new_borrowed_amount,
-                borrowed_from,
-                collateral_amount,
-                collateral_from,
-                health_factor: new_health_factor,
-                unpaid_interest: new_unpaid_interest,
-                last_accrual,
-            },
-        );
+    /// Repay part of a loan. The caller funds the repayment and has to be the borrower or an
+    /// operator approved for `OperatorScope::Repay`.
+    pub fn repay(
+        e: &Env,
+        caller: Address,
+        loan_id: LoanId,
+        amount: i128,
+    ) -> Result<(i128, i128), LoanManagerError> {
+        Self::require_borrower_or_operator(e, &caller, &loan_id, OperatorScope::Repay)?;
+        Self::repay_loan(e, &caller, loan_id, amount)
+    }
 
-        Ok((borrowed_amount, new_borrowed_amount))
+    /// Repay part of someone else's loan. Anyone can fund the repayment, the loan owner's debt
+    /// and pool position are reduced.
+    pub fn repay_for(
+        e: &Env,
+        payer: Address,
+        loan_id: LoanId,
+        amount: i128,
+    ) -> Result<(i128, i128), LoanManagerError> {
+        payer.require_auth();
+        Self::repay_loan(e, &payer, loan_id, amount)
     }
 
+    /// Repay a loan in full and return its collateral to the borrower. The caller funds the
+    /// repayment and has to be the borrower or an operator approved for `OperatorScope::Close`.
     pub fn repay_and_close_manager(
         e: &Env,
+        caller: Address,
         max_allowed_amount: i128,
         loan_id: LoanId,
     ) -> Result<i128, LoanManagerError> {
-        let user = loan_id.borrower_address.clone();
-        user.require_auth();
-
-        let Loan {
-            borrowed_amount,
-            borrowed_from,
-            collateral_amount,
-            collateral_from,
-            unpaid_interest,
-            ..
-        } = Self::add_interest(e, loan_id.clone())?;
+        Self::require_borrower_or_operator(e, &caller, &loan_id, OperatorScope::Close)?;
 
-        let borrow_pool_client = loan_pool::Client::new(e, &borrowed_from);
-        borrow_pool_client.repay_and_close(
-            &user,
-            &borrowed_amount,
-            &max_allowed_amount,
-            &unpaid_interest,
-        );
-
-        let collateral_pool_client = loan_pool::Client::new(e, &collateral_from);
-        collateral_pool_client.withdraw_collateral(&user, &collateral_amount);
-
-        storage::delete_loan(e, &loan_id);
-        Ok(borrowed_amount)
+        let loan = Self::add_interest(e, loan_id)?;
+        Self::close_loan(e, &caller, loan, max_allowed_amount)
     }
 
-    pub fn liquidate(
-        e: Env,
+    /// Open a loan leveraged to `target.leverage` times `collateral` in one call. The debt is
+    /// borrowed up front, swapped into more collateral through the swap router and deposited together
+    /// with `collateral`. The swap may return at most `target.max_slippage` less than the oracle
+    /// price implies, and the final loan has to be healthy.
+    pub fn create_leveraged_loan(
+        e: &Env,
         user: Address,
-        loan_id: LoanId,
-        amount: i128,
+        collateral: i128,
+        collateral_from: Address,
+        borrowed_from: Address,
+        target: LeverageTarget,
     ) -> Result<Loan, LoanManagerError> {
         user.require_auth();
+        let LeverageTarget {
+            leverage,
+            max_slippage,
+        } = target;
+        Self::require_trusted_pools(e, &borrowed_from, &collateral_from)?;
+        if collateral <= 0 || leverage <= FIXED_POINT_ONE {
+            return Err(LoanManagerError::InvalidAmount);
+        }
+        if !(0..FIXED_POINT_ONE).contains(&max_slippage) {
+            return Err(LoanManagerError::InvalidAmount);
+        }
 
-        let Loan {
-            loan_id,
-            borrowed_amount,
-            borrowed_from,
-            collateral_from,
-            collateral_amount,
-            unpaid_interest,
-            last_accrual,
-            ..
-        } = Self::add_interest(&e, loan_id.clone())?;
-
-        let borrow_pool_client = loan_pool::Client::new(&e, &borrowed_from);
-        let collateral_pool_client = loan_pool::Client::new(&e, &collateral_from);
-
-        let borrowed_ticker = borrow_pool_client.get_currency().ticker;
-        let collateral_ticker = collateral_pool_client.get_currency().ticker;
-
-        // Check that loan is for sure liquidatable at this moment.
-        let health_factor_before_liquidation = Self::calculate_health_factor(
-            &e,
-            borrowed_ticker.clone(),
-            borrowed_amount,
-            collateral_ticker.clone(),
-            collateral_amount,
-            collateral_from.clone(),
-        )?;
-        assert!(health_factor_before_liquidation < 10000000);
-        // Assert that the liquidation is not more than 50% of loan
-        assert!(
-            amount
-                < (borrowed_amount
-                    .checked_div(2)
-                    .ok_or(LoanManagerError::OverOrUnderFlow)?)
-        );
-        // Assert that the liquidation is atleast 1% of loan
-        assert!(
-            amount
-                > (borrowed_amount
-                    .checked_div(100)
-                    .ok_or(LoanManagerError::OverOrUnderFlow)?)
-        );
-
-        let borrowed_price = Self::get_price(&e, borrowed_ticker.clone())?;
-        let collateral_price = Self::get_price(&e, collateral_ticker.clone())?;
-        let collateral_factor = collateral_pool_client.get_collateral_factor();
-        const FIXED_POINT_ONE: i128 = 10_000_000;
+        let borrow_pool_client = loan_pool::Client::new(e, &borrowed_from);
+        let collateral_pool_client = loan_pool::Client::new(e, &collateral_from);
+        let borrow_currency = borrow_pool_client.get_currency();
+        let collateral_currency = collateral_pool_client.get_currency();
 
-        // bonus rate = (1-collateralfactor) / 2 = e.g. 2.5-10 %
-        // As multiplier = bonus rate + 1
-        let bonus = FIXED_POINT_ONE
-            .checked_sub(collateral_factor)
-            .ok_or(LoanManagerError::OverOrUnderFlow)?
-            .checked_div(2_i128)
+        // Debt worth the collateral that is bought on top of the user's own.
+        let extra_collateral = collateral
+            .checked_mul(leverage - FIXED_POINT_ONE)
             .ok_or(LoanManagerError::OverOrUnderFlow)?
-            .checked_add(FIXED_POINT_ONE)
-            .ok_or(LoanManagerError::OverOrUnderFlow)?;
-
-        let liquidation_value = amount
-            .checked_mul(borrowed_price)
+            .checked_div(FIXED_POINT_ONE)
             .ok_or(LoanManagerError::OverOrUnderFlow)?;
-        let collateral_amount_bonus = liquidation_value
-            .checked_mul(bonus)
+        let borrowed = extra_collateral
+            .checked_mul(Self::get_price(e, collateral_currency.ticker.clone())?)
             .ok_or(LoanManagerError::OverOrUnderFlow)?
-            .checked_div(collateral_price)
+            .checked_div(Self::get_price(e, borrow_currency.ticker.clone())?)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        let min_amount_out = extra_collateral
+            .checked_mul(FIXED_POINT_ONE - max_slippage)
             .ok_or(LoanManagerError::OverOrUnderFlow)?
-            .checked_div(10_000_000)
+            .checked_div(FIXED_POINT_ONE)
             .ok_or(LoanManagerError::OverOrUnderFlow)?;
 
-        borrow_pool_client.liquidate(&user, &amount, &unpaid_interest, &loan_id.borrower_address);
-
-        collateral_pool_client.liquidate_transfer_collateral(
+        let router = storage::read_swap_router(e)?;
+        let borrowed_amount = borrow_pool_client.borrow(&user, &borrowed);
+        token::Client::new(e, &borrow_currency.token_address).transfer(
             &user,
-            &collateral_amount_bonus,
-            &loan_id.borrower_address,
+            &router,
+            &borrowed_amount,
         );
-
-        let new_borrowed_amount = borrowed_amount
-            .checked_sub(amount)
+        // Only what the user received counts, not what the router reports.
+        let collateral_token_client = token::Client::new(e, &collateral_currency.token_address);
+        let balance_before = collateral_token_client.balance(&user);
+        SwapRouterClient::new(e, &router).swap(
+            &borrow_currency.token_address,
+            &collateral_currency.token_address,
+            &borrowed_amount,
+            &min_amount_out,
+            &user,
+        );
+        let amount_out = collateral_token_client
+            .balance(&user)
+            .checked_sub(balance_before)
             .ok_or(LoanManagerError::OverOrUnderFlow)?;
-        let new_collateral_amount = collateral_amount
-            .checked_sub(collateral_amount_bonus)
+        if amount_out < min_amount_out {
+            return Err(LoanManagerError::SlippageExceeded);
+        }
+
+        let total_collateral = collateral
+            .checked_add(amount_out)
             .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        Self::require_loan_limits(
+            e,
+            borrowed_amount,
+            &borrowed_from,
+            total_collateral,
+            &collateral_from,
+        )?;
+        let collateral_amount = collateral_pool_client.deposit_collateral(&user, &total_collateral);
 
-        let new_health_factor = Self::calculate_health_factor(
-            &e,
-            borrowed_ticker,
-            new_borrowed_amount,
-            collateral_ticker,
-            new_collateral_amount,
+        let health_factor = Self::calculate_loan_health_factor(
+            e,
+            borrow_currency.ticker,
+            borrowed_amount,
+            borrowed_from.clone(),
+            collateral_currency.ticker,
+            collateral_amount,
             collateral_from.clone(),
         )?;
-
-        if new_health_factor < health_factor_before_liquidation {
-            return Err(LoanManagerError::InvalidLiquidation);
+        if health_factor <= HEALTH_FACTOR_THRESHOLD {
+            return Err(LoanManagerError::HealthFactorTooLow);
         }
 
-        let new_loan = Loan {
-            loan_id: loan_id.clone(),
-            borrowed_amount: new_borrowed_amount,
+        let new_loan = NewLoan {
+            borrower_address: user.clone(),
+            borrowed_amount,
             borrowed_from,
+            collateral_amount,
             collateral_from,
-            collateral_amount: new_collateral_amount,
-            health_factor: new_health_factor,
-            unpaid_interest, // Temp
-            last_accrual,
+            health_factor,
+            unpaid_interest: 0,
+            last_accrual: borrow_pool_client.get_accrual(),
         };
-
-        storage::write_loan(&e, &loan_id, &new_loan);
-
-        Ok(new_loan)
+        Ok(storage::create_loan(e, user, new_loan))
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::contract::loan_pool::{PoolState, Positions};
+    /// Repay debt with the loan's own collateral. `collateral_amount` is sold through the swap
+    /// router for the borrowed token and the output repays the loan. The swap has to return at least
+    /// `min_amount_out` and the loan has to stay healthy. If the output covers the whole debt,
+    /// the loan is closed and the rest of the collateral and the leftover output go to the
+    /// borrower. Returns the loan after deleveraging, `None` if it was closed.
+    pub fn deleverage(
+        e: &Env,
+        loan_id: LoanId,
+        collateral_amount: i128,
+        min_amount_out: i128,
+    ) -> Result<Option<Loan>, LoanManagerError> {
+        let user = loan_id.borrower_address.clone();
+        user.require_auth();
 
-    use super::*;
-    use loan_pool::Currency;
-    use soroban_sdk::{
-        testutils::{Address as _, Ledger},
-        token::{Client as TokenClient, StellarAssetClient},
-        xdr::ToXdr,
-        Env,
-    };
-    mod loan_manager {
-        soroban_sdk::contractimport!(file = "../../target/wasm32v1-none/release/loan_manager.wasm");
-    }
+        let loan = Self::add_interest(e, loan_id)?;
+        if collateral_amount <= 0 || collateral_amount > loan.collateral_amount {
+            return Err(LoanManagerError::InvalidAmount);
+        }
 
-    #[test]
-    fn initialize() {
+        let borrow_pool_client = loan_pool::Client::new(e, &loan.borrowed_from);
+        let collateral_pool_client = loan_pool::Client::new(e, &loan.collateral_from);
+        let token_in = collateral_pool_client.get_currency().token_address;
+        let token_out = borrow_pool_client.get_currency().token_address;
+
+        let router = storage::read_swap_router(e)?;
+        // The collateral goes straight to the router and the output to the borrower, who then
+        // repays the loan with it. The output is what the borrower received, not what the
+        // router reports.
+        let token_out_client = token::Client::new(e, &token_out);
+        let balance_before = token_out_client.balance(&user);
+        collateral_pool_client.transfer_collateral_out(&router, &collateral_amount, &user);
+        SwapRouterClient::new(e, &router).swap(
+            &token_in,
+            &token_out,
+            &collateral_amount,
+            &min_amount_out,
+            &user,
+        );
+        let amount_out = token_out_client
+            .balance(&user)
+            .checked_sub(balance_before)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        if amount_out < min_amount_out {
+            return Err(LoanManagerError::SlippageExceeded);
+        }
+
+        let loan = Loan {
+            collateral_amount: loan
+                .collateral_amount
+                .checked_sub(collateral_amount)
+                .ok_or(LoanManagerError::OverOrUnderFlow)?,
+            ..loan
+        };
+        if amount_out >= loan.borrowed_amount {
+            let borrowed_amount = loan.borrowed_amount;
+            Self::close_loan(e, &user, loan, borrowed_amount)?;
+            return Ok(None);
+        }
+
+        let new_loan = Self::repay_accrued_loan(e, &user, loan, amount_out)?;
+        if new_loan.health_factor <= HEALTH_FACTOR_THRESHOLD {
+            return Err(LoanManagerError::HealthFactorTooLow);
+        }
+        Ok(Some(new_loan))
+    }
+
+    /// Add collateral to a loan. The caller provides the tokens and has to be the borrower or an
+    /// operator approved for `OperatorScope::AddCollateral`.
+    pub fn add_collateral(
+        e: &Env,
+        caller: Address,
+        loan_id: LoanId,
+        amount: i128,
+    ) -> Result<Loan, LoanManagerError> {
+        Self::require_borrower_or_operator(e, &caller, &loan_id, OperatorScope::AddCollateral)?;
+        if amount <= 0 {
+            return Err(LoanManagerError::InvalidAmount);
+        }
+
+        let loan = Self::add_interest(e, loan_id.clone())?;
+
+        let collateral_pool_client = loan_pool::Client::new(e, &loan.collateral_from);
+        collateral_pool_client.add_collateral(&caller, &amount, &loan_id.borrower_address);
+
+        let new_collateral_amount = loan
+            .collateral_amount
+            .checked_add(amount)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        let new_loan = Self::loan_with_collateral(e, loan, new_collateral_amount)?;
+        storage::write_loan(e, &loan_id, &new_loan);
+
+        Ok(new_loan)
+    }
+
+    /// Withdraw collateral from a loan to the borrower. The caller has to be the borrower or an
+    /// operator approved for `OperatorScope::WithdrawCollateral`, and the loan has to stay healthy.
+    pub fn withdraw_collateral(
+        e: &Env,
+        caller: Address,
+        loan_id: LoanId,
+        amount: i128,
+    ) -> Result<Loan, LoanManagerError> {
+        Self::require_borrower_or_operator(
+            e,
+            &caller,
+            &loan_id,
+            OperatorScope::WithdrawCollateral,
+        )?;
+
+        let loan = Self::add_interest(e, loan_id.clone())?;
+        if amount <= 0 || amount > loan.collateral_amount {
+            return Err(LoanManagerError::InvalidAmount);
+        }
+
+        let new_collateral_amount = loan
+            .collateral_amount
+            .checked_sub(amount)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        let new_loan = Self::loan_with_collateral(e, loan, new_collateral_amount)?;
+        if new_loan.health_factor <= HEALTH_FACTOR_THRESHOLD {
+            return Err(LoanManagerError::HealthFactorTooLow);
+        }
+
+        let collateral_pool_client = loan_pool::Client::new(e, &new_loan.collateral_from);
+        collateral_pool_client.withdraw_collateral(&loan_id.borrower_address, &amount);
+
+        storage::write_loan(e, &loan_id, &new_loan);
+
+        Ok(new_loan)
+    }
+
+    /// Transfer a loan to a new owner. Both the current and the new owner have to authorize the
+    /// transfer. The loan gets a new id under the new owner's nonce sequence and the pool
+    /// positions backing it move along.
+    pub fn transfer_loan(
+        e: &Env,
+        loan_id: LoanId,
+        new_owner: Address,
+    ) -> Result<Loan, LoanManagerError> {
+        let old_owner = loan_id.borrower_address.clone();
+        if old_owner == new_owner {
+            return Err(LoanManagerError::InvalidLoanTransfer);
+        }
+        old_owner.require_auth();
+        new_owner.require_auth();
+
+        let loan = Self::add_interest(e, loan_id)?;
+
+        let borrow_pool_client = loan_pool::Client::new(e, &loan.borrowed_from);
+        let collateral_pool_client = loan_pool::Client::new(e, &loan.collateral_from);
+        borrow_pool_client.transfer_positions(&old_owner, &new_owner, &loan.borrowed_amount, &0);
+        collateral_pool_client.transfer_positions(
+            &old_owner,
+            &new_owner,
+            &0,
+            &loan.collateral_amount,
+        );
+
+        Ok(storage::transfer_loan(e, loan, new_owner))
+    }
+
+    /// Register a stop-loss trigger on a loan. Replaces any earlier trigger of the loan.
+    pub fn set_trigger(e: &Env, loan_id: LoanId, trigger: Trigger) -> Result<(), LoanManagerError> {
+        loan_id.borrower_address.require_auth();
+        Self::get_loan(e, loan_id.clone())?;
+
+        // The trigger has to fire before the loan becomes liquidatable.
+        if trigger.health_factor <= HEALTH_FACTOR_THRESHOLD
+            || trigger.fraction <= 0
+            || trigger.fraction >= FIXED_POINT_ONE
+        {
+            return Err(LoanManagerError::InvalidTrigger);
+        }
+
+        storage::write_trigger(e, &loan_id, &trigger);
+        Ok(())
+    }
+
+    pub fn remove_trigger(e: &Env, loan_id: LoanId) -> Result<(), LoanManagerError> {
+        loan_id.borrower_address.require_auth();
+        storage::read_trigger(e, &loan_id).ok_or(LoanManagerError::TriggerNotFound)?;
+        storage::remove_trigger(e, &loan_id);
+        Ok(())
+    }
+
+    pub fn get_trigger(e: &Env, loan_id: LoanId) -> Option<Trigger> {
+        storage::read_trigger(e, &loan_id)
+    }
+
+    /// Execute a loan's trigger once its health factor has fallen to the trigger level. The keeper
+    /// repays the debt share and is paid back in collateral at the TWAP price plus a small fee.
+    /// The loan has to end up healthier and keep at least the borrow pool's minimum debt.
+    pub fn execute_trigger(
+        e: &Env,
+        keeper: Address,
+        loan_id: LoanId,
+    ) -> Result<Loan, LoanManagerError> {
+        keeper.require_auth();
+
+        let trigger =
+            storage::read_trigger(e, &loan_id).ok_or(LoanManagerError::TriggerNotFound)?;
+
+        let Loan {
+            borrowed_amount,
+            borrowed_from,
+            collateral_amount,
+            collateral_from,
+            health_factor,
+            unpaid_interest,
+            last_accrual,
+            ..
+        } = Self::add_interest(e, loan_id.clone())?;
+
+        if health_factor > trigger.health_factor {
+            return Err(LoanManagerError::TriggerNotMet);
+        }
+
+        let borrow_pool_client = loan_pool::Client::new(e, &borrowed_from);
+        let collateral_pool_client = loan_pool::Client::new(e, &collateral_from);
+        let borrowed_ticker = borrow_pool_client.get_currency().ticker;
+        let collateral_ticker = collateral_pool_client.get_currency().ticker;
+
+        let amount = borrowed_amount
+            .checked_mul(trigger.fraction)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?
+            .checked_div(FIXED_POINT_ONE)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        if amount <= 0 {
+            return Err(LoanManagerError::InvalidAmount);
+        }
+
+        let new_borrowed_amount = borrowed_amount
+            .checked_sub(amount)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        // A loan left with less than the pool's minimum debt has to be closed instead.
+        if new_borrowed_amount < storage::read_loan_limits(e, &borrowed_from).min_borrow {
+            return Err(LoanManagerError::DustRemaining);
+        }
+
+        // Collateral equal in value to the repaid debt, plus the keeper's fee on top. Priced with
+        // the same TWAP as the health factor the trigger fires on.
+        let borrowed_price = Self::twap_price(e, borrowed_ticker.clone())?;
+        let collateral_price = Self::twap_price(e, collateral_ticker.clone())?;
+        let repaid_in_collateral = amount
+            .checked_mul(borrowed_price)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?
+            .checked_div(collateral_price)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        let keeper_fee = repaid_in_collateral
+            .checked_mul(TRIGGER_KEEPER_FEE)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?
+            .checked_div(FIXED_POINT_ONE)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        let collateral_to_keeper = repaid_in_collateral
+            .checked_add(keeper_fee)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+
+        let collateral_left = collateral_amount
+            .checked_sub(collateral_to_keeper)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        if collateral_left < 0 {
+            return Err(LoanManagerError::InvalidTrigger);
+        }
+
+        // A partial close hands back at most `fraction` of the collateral, and only what the
+        // loan does not need to get back above the trigger's health factor.
+        let collateral_to_borrower = match trigger.action {
+            TriggerAction::RepayFromCollateral => 0,
+            TriggerAction::PartialClose => {
+                let collateral_needed = Self::collateral_for_health_factor(
+                    trigger.health_factor,
+                    new_borrowed_amount,
+                    borrowed_price,
+                    collateral_price,
+                    Self::collateral_factor(e, &borrowed_from, &collateral_from)?,
+                )?;
+                collateral_amount
+                    .checked_mul(trigger.fraction)
+                    .ok_or(LoanManagerError::OverOrUnderFlow)?
+                    .checked_div(FIXED_POINT_ONE)
+                    .ok_or(LoanManagerError::OverOrUnderFlow)?
+                    .checked_sub(collateral_to_keeper)
+                    .ok_or(LoanManagerError::OverOrUnderFlow)?
+                    .min(collateral_left.saturating_sub(collateral_needed))
+                    .max(0)
+            }
+        };
+        let new_collateral_amount = collateral_left - collateral_to_borrower;
+
+        let new_health_factor = Self::calculate_loan_health_factor(
+            e,
+            borrowed_ticker,
+            new_borrowed_amount,
+            borrowed_from.clone(),
+            collateral_ticker,
+            new_collateral_amount,
+            collateral_from.clone(),
+        )?;
+        // A stop-loss has to leave the loan healthier than it found it.
+        if new_health_factor <= health_factor {
+            return Err(LoanManagerError::InvalidTrigger);
+        }
+
+        borrow_pool_client.repay(
+            &keeper,
+            &amount,
+            &unpaid_interest,
+            &loan_id.borrower_address,
+        );
+        collateral_pool_client.transfer_collateral_out(
+            &keeper,
+            &collateral_to_keeper,
+            &loan_id.borrower_address,
+        );
+        if collateral_to_borrower > 0 {
+            collateral_pool_client
+                .withdraw_collateral(&loan_id.borrower_address, &collateral_to_borrower);
+        }
+
+        let new_unpaid_interest = if amount < unpaid_interest {
+            unpaid_interest
+                .checked_sub(amount)
+                .ok_or(LoanManagerError::OverOrUnderFlow)?
+        } else {
+            0
+        };
+
+        let new_loan = Loan {
+            loan_id: loan_id.clone(),
+            borrowed_amount: new_borrowed_amount,
+            borrowed_from,
+            collateral_amount: new_collateral_amount,
+            collateral_from,
+            health_factor: new_health_factor,
+            unpaid_interest: new_unpaid_interest,
+            last_accrual,
+        };
+
+        // Triggers are one-shot.
+        storage::remove_trigger(e, &loan_id);
+        storage::write_loan(e, &loan_id, &new_loan);
+
+        storage::EventTriggerExecuted {
+            loan_id,
+            keeper,
+            action: trigger.action,
+            debt_repaid: amount,
+            collateral_to_keeper,
+            keeper_fee,
+            collateral_to_borrower,
+        }
+        .publish(e);
+
+        Ok(new_loan)
+    }
+
+    /// Liquidate `amount` of an unhealthy loan's debt. A loan liquidated in full is closed, the
+    /// returned loan has no debt left. Fails with `LiquidationFailed` if the borrow pool rejects
+    /// the repayment, e.g. because it is frozen or the liquidator's transfer fails.
+    pub fn liquidate(
+        e: Env,
+        user: Address,
+        loan_id: LoanId,
+        amount: i128,
+    ) -> Result<Loan, LoanManagerError> {
+        user.require_auth();
+
+        let liquidation = Self::prepare_liquidation(&e, loan_id, amount)?;
+        Self::execute_liquidation(&e, &user, liquidation)
+    }
+
+    /// Liquidate up to `MAX_LIQUIDATION_BATCH` loans in one call. Every entry is processed on
+    /// its own and gets an outcome in the returned list, so one bad entry does not abort the
+    /// batch.
+    pub fn liquidate_batch(
+        e: Env,
+        liquidator: Address,
+        liquidations: Vec<(LoanId, i128)>,
+    ) -> Result<Vec<LiquidationOutcome>, LoanManagerError> {
+        liquidator.require_auth();
+        if liquidations.len() > MAX_LIQUIDATION_BATCH {
+            return Err(LoanManagerError::InvalidAmount);
+        }
+
+        let mut outcomes = vec![&e];
+        for (loan_id, amount) in liquidations.iter() {
+            if storage::read_loan(&e, &loan_id).is_none() {
+                outcomes.push_back(LiquidationOutcome::LoanNotFound);
+                continue;
+            }
+
+            let outcome = match Self::prepare_liquidation(&e, loan_id, amount) {
+                Ok(liquidation) => match Self::liquidator_balance(&e, &liquidator, &liquidation) {
+                    Ok(balance) if balance < amount => LiquidationOutcome::InsufficientBalance,
+                    Ok(_) => match Self::execute_liquidation(&e, &liquidator, liquidation) {
+                        Ok(_) => LiquidationOutcome::Liquidated,
+                        Err(_) => LiquidationOutcome::Failed,
+                    },
+                    Err(_) => LiquidationOutcome::Failed,
+                },
+                Err(LoanManagerError::LoanNotLiquidatable) => LiquidationOutcome::NotLiquidatable,
+                Err(LoanManagerError::InvalidLiquidationAmount) => {
+                    LiquidationOutcome::AmountOutOfRange
+                }
+                Err(_) => LiquidationOutcome::Failed,
+            };
+            outcomes.push_back(outcome);
+        }
+
+        Ok(outcomes)
+    }
+
+    /// Balance the liquidator holds of the token a prepared liquidation repays.
+    fn liquidator_balance(
+        e: &Env,
+        liquidator: &Address,
+        liquidation: &Liquidation,
+    ) -> Result<i128, LoanManagerError> {
+        let borrow_pool_client = loan_pool::Client::new(e, &liquidation.loan.borrowed_from);
+        let token_address = Self::pool_result(borrow_pool_client.try_get_currency())?.token_address;
+        token::Client::new(e, &token_address)
+            .try_balance(liquidator)
+            .map_err(|_| LoanManagerError::PoolCallFailed)?
+            .map_err(|_| LoanManagerError::PoolCallFailed)
+    }
+
+    /// Accrue interest on a loan and check that it can be liquidated by `amount`. Nothing is
+    /// transferred, so a failed check leaves only the accrued interest behind.
+    fn prepare_liquidation(
+        e: &Env,
+        loan_id: LoanId,
+        amount: i128,
+    ) -> Result<Liquidation, LoanManagerError> {
+        // Accruing interest also recalculates the health factor with current prices.
+        let loan = Self::add_interest(e, loan_id)?;
+        Self::quote_liquidation(e, loan, amount)
+    }
+
+    /// Check that an up to date loan can be liquidated by `amount` and work out the result.
+    fn quote_liquidation(
+        e: &Env,
+        loan: Loan,
+        amount: i128,
+    ) -> Result<Liquidation, LoanManagerError> {
+        let Loan {
+            borrowed_amount,
+            borrowed_from,
+            collateral_from,
+            collateral_amount,
+            health_factor: health_factor_before_liquidation,
+            ..
+        } = loan.clone();
+
+        // Check that loan is for sure liquidatable at this moment. Fixed-rate loans past their
+        // maturity are liquidatable regardless of the health factor.
+        let is_matured = storage::read_fixed_rate(e, &loan.loan_id)
+            .is_some_and(|fixed_rate| e.ledger().timestamp() >= fixed_rate.maturity);
+        if health_factor_before_liquidation >= HEALTH_FACTOR_THRESHOLD && !is_matured {
+            return Err(LoanManagerError::LoanNotLiquidatable);
+        }
+        // The liquidation has to be more than 1% and less than 50% of the loan, and leave at
+        // least the pool's minimum debt behind. A loan that even the largest liquidation would
+        // leave below the minimum can only be liquidated in full.
+        let max_amount = borrowed_amount
+            .checked_div(2)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        let min_amount = borrowed_amount
+            .checked_div(100)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        let min_borrow = storage::read_loan_limits(e, &borrowed_from).min_borrow;
+        let closes_loan = amount == borrowed_amount && borrowed_amount - max_amount < min_borrow;
+        if !closes_loan
+            && (amount >= max_amount
+                || amount <= min_amount
+                || borrowed_amount - amount < min_borrow)
+        {
+            return Err(LoanManagerError::InvalidLiquidationAmount);
+        }
+
+        let borrow_pool_client = loan_pool::Client::new(e, &borrowed_from);
+        let collateral_pool_client = loan_pool::Client::new(e, &collateral_from);
+
+        let borrowed_ticker = Self::pool_result(borrow_pool_client.try_get_currency())?.ticker;
+        let collateral_ticker =
+            Self::pool_result(collateral_pool_client.try_get_currency())?.ticker;
+
+        let borrowed_price = Self::get_price(e, borrowed_ticker.clone())?;
+        let collateral_price = Self::get_price(e, collateral_ticker.clone())?;
+
+        // As multiplier = bonus rate + 1
+        let bonus = Self::liquidation_bonus(e, &loan)?
+            .checked_add(FIXED_POINT_ONE)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+
+        let liquidation_value = amount
+            .checked_mul(borrowed_price)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        // The liquidator gets at most all of the collateral.
+        let collateral_amount_bonus = liquidation_value
+            .checked_mul(bonus)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?
+            .checked_div(collateral_price)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?
+            .checked_div(10_000_000)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?
+            .min(collateral_amount);
+        let bonus_amount = liquidation_value
+            .checked_div(collateral_price)
+            .and_then(|base_amount| collateral_amount_bonus.checked_sub(base_amount))
+            .ok_or(LoanManagerError::OverOrUnderFlow)?
+            .max(0);
+
+        let new_borrowed_amount = borrowed_amount
+            .checked_sub(amount)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        let new_collateral_amount = collateral_amount
+            .checked_sub(collateral_amount_bonus)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+
+        if closes_loan {
+            // The rest of the collateral is returned to the borrower. A loan without debt has
+            // no health factor, it is reported as the largest possible one.
+            return Ok(Liquidation {
+                loan: loan.clone(),
+                amount,
+                collateral_amount_bonus,
+                bonus_amount,
+                protocol_bonus: Self::protocol_bonus(&collateral_pool_client, bonus_amount)?,
+                new_loan: Loan {
+                    borrowed_amount: 0,
+                    collateral_amount: new_collateral_amount,
+                    health_factor: i128::MAX,
+                    unpaid_interest: 0,
+                    ..loan
+                },
+            });
+        }
+
+        let new_health_factor = Self::calculate_loan_health_factor(
+            e,
+            borrowed_ticker,
+            new_borrowed_amount,
+            borrowed_from.clone(),
+            collateral_ticker,
+            new_collateral_amount,
+            collateral_from,
+        )?;
+
+        if new_health_factor < health_factor_before_liquidation {
+            return Err(LoanManagerError::InvalidLiquidation);
+        }
+
+        let new_loan = Loan {
+            borrowed_amount: new_borrowed_amount,
+            collateral_amount: new_collateral_amount,
+            health_factor: new_health_factor,
+            // The pool books the repaid interest as revenue, like in `repaid_loan`.
+            unpaid_interest: loan.unpaid_interest - amount.min(loan.unpaid_interest),
+            ..loan.clone()
+        };
+
+        Ok(Liquidation {
+            loan,
+            amount,
+            collateral_amount_bonus,
+            bonus_amount,
+            protocol_bonus: Self::protocol_bonus(&collateral_pool_client, bonus_amount)?,
+            new_loan,
+        })
+    }
+
+    /// TWAP price of a token, the price health factors are computed with.
+    fn twap_price(e: &Env, ticker: Symbol) -> Result<i128, LoanManagerError> {
+        oracle::Client::new(e, &storage::read_oracle(e)?)
+            .try_twap(&Asset::Other(ticker), &TWAP_DATA_POINTS)
+            .map_err(|_| LoanManagerError::NoLastPrice)?
+            .map_err(|_| LoanManagerError::NoLastPrice)?
+            .ok_or(LoanManagerError::NoLastPrice)
+    }
+
+    /// Result of a `try_` call to a pool setter. The pool rejecting the value with `rejected` is
+    /// reported as `error`, any other failure aborts with the error the call failed with.
+    fn pool_setter_result<T>(
+        e: &Env,
+        result: Result<T, Result<loan_pool::LoanPoolError, InvokeError>>,
+        rejected: loan_pool::LoanPoolError,
+        error: LoanManagerError,
+    ) -> Result<(), LoanManagerError> {
+        match result {
+            Ok(_) => Ok(()),
+            Err(Ok(pool_error)) if pool_error == rejected => Err(error),
+            Err(Ok(pool_error)) => panic_with_error!(e, pool_error),
+            Err(Err(InvokeError::Contract(code))) => {
+                panic_with_error!(e, Error::from_contract_error(code))
+            }
+            Err(Err(InvokeError::Abort)) => panic_with_error!(
+                e,
+                Error::from_type_and_code(ScErrorType::Context, ScErrorCode::InvalidAction)
+            ),
+        }
+    }
+
+    /// Result of a `try_` call to a pool, with any failure of the call reported as
+    /// `PoolCallFailed`.
+    fn pool_result<T, C, E>(result: Result<Result<T, C>, E>) -> Result<T, LoanManagerError> {
+        result
+            .map_err(|_| LoanManagerError::PoolCallFailed)?
+            .map_err(|_| LoanManagerError::PoolCallFailed)
+    }
+
+    /// Health factor of a loan at the given prices.
+    fn health_factor_at_prices(
+        borrowed_amount: i128,
+        borrowed_price: i128,
+        collateral_amount: i128,
+        collateral_price: i128,
+        collateral_factor: i128,
+    ) -> Result<i128, LoanManagerError> {
+        const DECIMAL_TO_INT_MULTIPLIER: i128 = 10000000;
+
+        let collateral_value = collateral_price
+            .checked_mul(collateral_amount)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?
+            .checked_mul(collateral_factor)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?
+            .checked_div(DECIMAL_TO_INT_MULTIPLIER)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        let borrowed_value = borrowed_price
+            .checked_mul(borrowed_amount)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+
+        collateral_value
+            .checked_mul(DECIMAL_TO_INT_MULTIPLIER)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?
+            .checked_div(borrowed_value)
+            .ok_or(LoanManagerError::OverOrUnderFlow)
+    }
+
+    /// Least collateral that gives a debt of `borrowed_amount` a health factor above
+    /// `health_factor`, rounded the same way as `calculate_health_factor`.
+    fn collateral_for_health_factor(
+        health_factor: i128,
+        borrowed_amount: i128,
+        borrowed_price: i128,
+        collateral_price: i128,
+        collateral_factor: i128,
+    ) -> Result<i128, LoanManagerError> {
+        // Weighted collateral value needed, rounded up.
+        let collateral_value = (health_factor + 1)
+            .checked_mul(borrowed_price)
+            .and_then(|value| value.checked_mul(borrowed_amount))
+            .ok_or(LoanManagerError::OverOrUnderFlow)?
+            .checked_add(FIXED_POINT_ONE - 1)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?
+            / FIXED_POINT_ONE;
+        let weighted_price = collateral_price
+            .checked_mul(collateral_factor)
+            .filter(|price| *price > 0)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        collateral_value
+            .checked_mul(FIXED_POINT_ONE)
+            .and_then(|value| value.checked_add(weighted_price - 1))
+            .ok_or(LoanManagerError::OverOrUnderFlow)
+            .map(|value| value / weighted_price)
+    }
+
+    /// The protocol's share of a liquidation bonus, rounded down like the collateral pool does
+    /// when it pays the bonus out.
+    fn protocol_bonus(
+        collateral_pool_client: &loan_pool::Client,
+        bonus: i128,
+    ) -> Result<i128, LoanManagerError> {
+        bonus
+            .checked_mul(Self::pool_result(
+                collateral_pool_client.try_get_liquidation_fee_share(),
+            )?)
+            .map(|share| share / FIXED_POINT_ONE)
+            .ok_or(LoanManagerError::OverOrUnderFlow)
+    }
+
+    /// Bonus rate a liquidator gets on the collateral of a loan right now.
+    fn liquidation_bonus(e: &Env, loan: &Loan) -> Result<i128, LoanManagerError> {
+        // Loans in efficiency mode have a fixed bonus of their own.
+        if let Some(category) = Self::asset_category(e, &loan.borrowed_from, &loan.collateral_from)
+        {
+            return Ok(category.liquidation_bonus);
+        }
+
+        let Some(LiquidationAuction {
+            min_bonus,
+            max_bonus,
+            duration,
+        }) = storage::read_liquidation_auction(e)
+        else {
+            // bonus rate = (1-collateralfactor) / 2 = e.g. 2.5-10 %
+            let collateral_pool_client = loan_pool::Client::new(e, &loan.collateral_from);
+            return FIXED_POINT_ONE
+                .checked_sub(Self::pool_result(
+                    collateral_pool_client.try_get_collateral_factor(),
+                )?)
+                .ok_or(LoanManagerError::OverOrUnderFlow)?
+                .checked_div(2_i128)
+                .ok_or(LoanManagerError::OverOrUnderFlow);
+        };
+
+        // A loan that has not been seen liquidatable yet would start its auction now.
+        let now = e.ledger().timestamp();
+        let since = storage::read_liquidatable_since(e, &loan.loan_id).unwrap_or(now);
+        let elapsed = now.saturating_sub(since).min(duration);
+
+        max_bonus
+            .checked_sub(min_bonus)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?
+            .checked_mul(i128::from(elapsed))
+            .ok_or(LoanManagerError::OverOrUnderFlow)?
+            .checked_div(i128::from(duration))
+            .ok_or(LoanManagerError::OverOrUnderFlow)?
+            .checked_add(min_bonus)
+            .ok_or(LoanManagerError::OverOrUnderFlow)
+    }
+
+    /// Asset category shared by the borrow and the collateral pool, if any.
+    fn asset_category(
+        e: &Env,
+        borrow_pool: &Address,
+        collateral_pool: &Address,
+    ) -> Option<AssetCategory> {
+        let category_id = storage::read_pool_category(e, collateral_pool)?;
+        if storage::read_pool_category(e, borrow_pool)? != category_id {
+            return None;
+        }
+        storage::read_asset_category(e, category_id)
+    }
+
+    /// Collateral factor of a loan, taking efficiency mode into account.
+    fn collateral_factor(
+        e: &Env,
+        borrow_pool: &Address,
+        collateral_pool: &Address,
+    ) -> Result<i128, LoanManagerError> {
+        match Self::asset_category(e, borrow_pool, collateral_pool) {
+            Some(category) => Ok(category.collateral_factor),
+            None => Self::pool_result(
+                loan_pool::Client::new(e, collateral_pool).try_get_collateral_factor(),
+            ),
+        }
+    }
+
+    /// Move the tokens of a prepared liquidation and store the liquidated loan. The debt is
+    /// repaid first, if the borrow pool rejects it nothing has moved and the liquidation fails
+    /// without aborting the caller.
+    fn execute_liquidation(
+        e: &Env,
+        liquidator: &Address,
+        liquidation: Liquidation,
+    ) -> Result<Loan, LoanManagerError> {
+        let Liquidation {
+            loan,
+            amount,
+            collateral_amount_bonus,
+            bonus_amount,
+            protocol_bonus: _,
+            new_loan,
+        } = liquidation;
+
+        let borrow_pool_client = loan_pool::Client::new(e, &loan.borrowed_from);
+        let collateral_pool_client = loan_pool::Client::new(e, &loan.collateral_from);
+
+        borrow_pool_client
+            .try_liquidate(
+                liquidator,
+                &amount,
+                &loan.unpaid_interest,
+                &loan.loan_id.borrower_address,
+            )
+            .map_err(|_| LoanManagerError::LiquidationFailed)?
+            .map_err(|_| LoanManagerError::LiquidationFailed)?;
+        // From here on the debt is repaid, so a failure has to revert the whole transaction.
+
+        let treasury = match storage::read_treasury(e) {
+            Some(treasury) => treasury,
+            None => storage::read_admin(e)?,
+        };
+        let protocol_bonus = collateral_pool_client.liquidate_transfer_collateral(
+            liquidator,
+            &collateral_amount_bonus,
+            &bonus_amount,
+            &loan.loan_id.borrower_address,
+            &treasury,
+        );
+
+        if new_loan.borrowed_amount == 0 {
+            if new_loan.collateral_amount > 0 {
+                collateral_pool_client.withdraw_collateral(
+                    &loan.loan_id.borrower_address,
+                    &new_loan.collateral_amount,
+                );
+            }
+            storage::delete_loan(e, &loan.loan_id);
+        } else {
+            storage::write_loan(e, &loan.loan_id, &new_loan);
+        }
+
+        storage::EventLoanLiquidated {
+            loan_id: loan.loan_id.clone(),
+            liquidator: liquidator.clone(),
+            debt_repaid: amount,
+            collateral_seized: collateral_amount_bonus,
+            bonus_paid: bonus_amount,
+            protocol_bonus,
+            protocol_fee: Self::protocol_fee(amount, loan.unpaid_interest),
+            health_factor_before: loan.health_factor,
+            health_factor_after: new_loan.health_factor,
+        }
+        .publish(e);
+
+        Ok(new_loan)
+    }
+
+    /// Repay `amount` of a loan with tokens from `payer`. Returns the debt before and after.
+    fn repay_loan(
+        e: &Env,
+        payer: &Address,
+        loan_id: LoanId,
+        amount: i128,
+    ) -> Result<(i128, i128), LoanManagerError> {
+        let loan = Self::add_interest(e, loan_id)?;
+        let borrowed_amount = loan.borrowed_amount;
+
+        assert!(
+            amount <= borrowed_amount,
+            "Amount can not be greater than borrowed amount!"
+        );
+
+        let new_loan = Self::repay_accrued_loan(e, payer, loan, amount)?;
+        Ok((borrowed_amount, new_loan.borrowed_amount))
+    }
+
+    /// Repay `amount` of a loan whose interest is up to date and store the result.
+    fn repay_accrued_loan(
+        e: &Env,
+        payer: &Address,
+        loan: Loan,
+        amount: i128,
+    ) -> Result<Loan, LoanManagerError> {
+        let loan_id = loan.loan_id.clone();
+        let borrow_pool_client = loan_pool::Client::new(e, &loan.borrowed_from);
+        borrow_pool_client.repay(
+            payer,
+            &amount,
+            &loan.unpaid_interest,
+            &loan_id.borrower_address,
+        );
+
+        let new_loan = Self::repaid_loan(e, loan, amount)?;
+        storage::write_loan(e, &loan_id, &new_loan);
+        Ok(new_loan)
+    }
+
+    /// Repay a loan whose interest is up to date in full with tokens from `payer`, return its
+    /// collateral to the borrower and delete it. Returns the repaid debt.
+    fn close_loan(
+        e: &Env,
+        payer: &Address,
+        loan: Loan,
+        max_allowed_amount: i128,
+    ) -> Result<i128, LoanManagerError> {
+        let Loan {
+            loan_id,
+            borrowed_amount,
+            borrowed_from,
+            collateral_amount,
+            collateral_from,
+            unpaid_interest,
+            ..
+        } = loan;
+        let user = loan_id.borrower_address.clone();
+
+        let borrow_pool_client = loan_pool::Client::new(e, &borrowed_from);
+        borrow_pool_client.repay_and_close(
+            payer,
+            &borrowed_amount,
+            &max_allowed_amount,
+            &unpaid_interest,
+            &user,
+        );
+
+        if collateral_amount > 0 {
+            let collateral_pool_client = loan_pool::Client::new(e, &collateral_from);
+            collateral_pool_client.withdraw_collateral(&user, &collateral_amount);
+        }
+
+        storage::delete_loan(e, &loan_id);
+        Ok(borrowed_amount)
+    }
+
+    fn require_trusted_pools(
+        e: &Env,
+        borrowed_from: &Address,
+        collateral_from: &Address,
+    ) -> Result<(), LoanManagerError> {
+        let pool_addresses = storage::read_pool_addresses(e);
+        if !pool_addresses.contains(borrowed_from) {
+            return Err(LoanManagerError::InvalidLoanToken);
+        }
+        if !pool_addresses.contains(collateral_from) {
+            return Err(LoanManagerError::InvalidCollateralToken);
+        }
+        Ok(())
+    }
+
+    /// Check a new loan against the minimums of its pools.
+    fn require_loan_limits(
+        e: &Env,
+        borrowed: i128,
+        borrowed_from: &Address,
+        collateral: i128,
+        collateral_from: &Address,
+    ) -> Result<(), LoanManagerError> {
+        if borrowed < storage::read_loan_limits(e, borrowed_from).min_borrow
+            || collateral < storage::read_loan_limits(e, collateral_from).min_collateral
+        {
+            return Err(LoanManagerError::LoanBelowMinimum);
+        }
+        Ok(())
+    }
+
+    /// Validate and open a loan for `user`.
+    fn open_loan(
+        e: &Env,
+        user: Address,
+        borrowed: i128,
+        borrowed_from: Address,
+        collateral: i128,
+        collateral_from: Address,
+    ) -> Result<Loan, LoanManagerError> {
+        Self::require_trusted_pools(e, &borrowed_from, &collateral_from)?;
+        Self::require_loan_limits(e, borrowed, &borrowed_from, collateral, &collateral_from)?;
+
+        let collateral_pool_client = loan_pool::Client::new(e, &collateral_from);
+        let borrow_pool_client = loan_pool::Client::new(e, &borrowed_from);
+
+        let token_currency = borrow_pool_client.get_currency();
+        let collateral_currency = collateral_pool_client.get_currency();
+        let health_factor: i128 = Self::calculate_loan_health_factor(
+            e,
+            token_currency.ticker,
+            borrowed,
+            borrowed_from.clone(),
+            collateral_currency.ticker,
+            collateral,
+            collateral_from.clone(),
+        )?;
+
+        // Health factor has to be over 1.0 for the loan to be initialized.
+        assert!(
+            health_factor > HEALTH_FACTOR_THRESHOLD,
+            "Health factor must be over {HEALTH_FACTOR_THRESHOLD} to create a new loan!"
+        );
+
+        // Deposit collateral
+        let collateral_amount = collateral_pool_client.deposit_collateral(&user, &collateral);
+
+        // Borrow the funds
+        let borrowed_amount = borrow_pool_client.borrow(&user, &borrowed);
+
+        let unpaid_interest = 0;
+
+        let new_loan = NewLoan {
+            borrower_address: user.clone(),
+            borrowed_amount,
+            borrowed_from,
+            collateral_amount,
+            collateral_from,
+            health_factor,
+            unpaid_interest,
+            last_accrual: borrow_pool_client.get_accrual(),
+        };
+
+        let loan = storage::create_loan(e, user.clone(), new_loan);
+
+        Ok(loan)
+    }
+
+    /// Scale a debt from the accrual it was last updated at to `current_accrual`. The debt is
+    /// multiplied before dividing so that no precision of the accrual index is lost.
+    fn accrued_amount(
+        amount: i128,
+        last_accrual: i128,
+        current_accrual: i128,
+    ) -> Result<i128, LoanManagerError> {
+        amount
+            .checked_mul(current_accrual)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?
+            .checked_div(last_accrual)
+            .ok_or(LoanManagerError::OverOrUnderFlow)
+    }
+
+    /// Add the interest of a fixed-rate loan since its last update. Time past maturity accrues
+    /// at the locked rate plus the penalty rate.
+    fn fixed_rate_accrued_amount(
+        e: &Env,
+        amount: i128,
+        fixed_rate: &FixedRate,
+    ) -> Result<i128, LoanManagerError> {
+        let now = e.ledger().timestamp();
+        let last_updated = fixed_rate.last_updated.min(now);
+        let regular_until = now.min(fixed_rate.maturity).max(last_updated);
+        let regular_seconds = i128::from(regular_until - last_updated);
+        let penalty_seconds = i128::from(now - regular_until);
+
+        let penalty_rate = fixed_rate
+            .rate
+            .checked_add(fixed_rate.penalty_rate)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        let rate_seconds = fixed_rate
+            .rate
+            .checked_mul(regular_seconds)
+            .and_then(|regular| {
+                penalty_rate
+                    .checked_mul(penalty_seconds)
+                    .and_then(|penalty| regular.checked_add(penalty))
+            })
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+
+        let interest = amount
+            .checked_mul(rate_seconds)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?
+            .checked_div(
+                i128::from(SECONDS_IN_YEAR)
+                    .checked_mul(FIXED_POINT_ONE)
+                    .ok_or(LoanManagerError::OverOrUnderFlow)?,
+            )
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        amount
+            .checked_add(interest)
+            .ok_or(LoanManagerError::OverOrUnderFlow)
+    }
+
+    /// Loan with interest accrued up to the current ledger, computed without writing anything.
+    fn accrued_loan(e: &Env, loan: Loan) -> Result<Loan, LoanManagerError> {
+        let borrow_pool_client = loan_pool::Client::new(e, &loan.borrowed_from);
+        let collateral_pool_client = loan_pool::Client::new(e, &loan.collateral_from);
+
+        let (borrowed_amount, unpaid_interest, current_accrual) = Self::accrued_debt(e, &loan)?;
+        let health_factor = Self::calculate_loan_health_factor(
+            e,
+            borrow_pool_client.get_currency().ticker,
+            borrowed_amount,
+            loan.borrowed_from.clone(),
+            collateral_pool_client.get_currency().ticker,
+            loan.collateral_amount,
+            loan.collateral_from.clone(),
+        )?;
+
+        Ok(Loan {
+            borrowed_amount,
+            unpaid_interest,
+            health_factor,
+            last_accrual: current_accrual,
+            ..loan
+        })
+    }
+
+    /// Debt, unpaid interest and accrual index of a loan with interest accrued up to the current
+    /// ledger.
+    fn accrued_debt(e: &Env, loan: &Loan) -> Result<(i128, i128, i128), LoanManagerError> {
+        let (borrowed_amount, current_accrual) = match storage::read_fixed_rate(e, &loan.loan_id) {
+            Some(fixed_rate) => (
+                Self::fixed_rate_accrued_amount(e, loan.borrowed_amount, &fixed_rate)?,
+                loan.last_accrual,
+            ),
+            None => {
+                let current_accrual =
+                    loan_pool::Client::new(e, &loan.borrowed_from).get_current_accrual();
+                (
+                    Self::accrued_amount(loan.borrowed_amount, loan.last_accrual, current_accrual)?,
+                    current_accrual,
+                )
+            }
+        };
+        let unpaid_interest = borrowed_amount
+            .checked_sub(loan.borrowed_amount)
+            .and_then(|change| loan.unpaid_interest.checked_add(change))
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        Ok((borrowed_amount, unpaid_interest, current_accrual))
+    }
+
+    /// Loan after `amount` of its debt is repaid. Interest is paid off first.
+    fn repaid_loan(e: &Env, loan: Loan, amount: i128) -> Result<Loan, LoanManagerError> {
+        let new_unpaid_interest = if amount < loan.unpaid_interest {
+            loan.unpaid_interest
+                .checked_sub(amount)
+                .ok_or(LoanManagerError::OverOrUnderFlow)?
+        } else {
+            0
+        };
+
+        let new_borrowed_amount = loan
+            .borrowed_amount
+            .checked_sub(amount)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        // A loan left with less than the pool's minimum debt has to be closed instead.
+        if new_borrowed_amount < storage::read_loan_limits(e, &loan.borrowed_from).min_borrow {
+            return Err(LoanManagerError::DustRemaining);
+        }
+
+        let borrow_pool_client = loan_pool::Client::new(e, &loan.borrowed_from);
+        let collateral_pool_client = loan_pool::Client::new(e, &loan.collateral_from);
+        let new_health_factor = Self::calculate_loan_health_factor(
+            e,
+            borrow_pool_client.get_currency().ticker,
+            new_borrowed_amount,
+            loan.borrowed_from.clone(),
+            collateral_pool_client.get_currency().ticker,
+            loan.collateral_amount,
+            loan.collateral_from.clone(),
+        )?;
+
+        Ok(Loan {
+            borrowed_amount: new_borrowed_amount,
+            health_factor: new_health_factor,
+            unpaid_interest: new_unpaid_interest,
+            ..loan
+        })
+    }
+
+    /// Loan with its collateral changed to `new_collateral_amount`.
+    fn loan_with_collateral(
+        e: &Env,
+        loan: Loan,
+        new_collateral_amount: i128,
+    ) -> Result<Loan, LoanManagerError> {
+        let borrow_pool_client = loan_pool::Client::new(e, &loan.borrowed_from);
+        let collateral_pool_client = loan_pool::Client::new(e, &loan.collateral_from);
+        let new_health_factor = Self::calculate_loan_health_factor(
+            e,
+            borrow_pool_client.get_currency().ticker,
+            loan.borrowed_amount,
+            loan.borrowed_from.clone(),
+            collateral_pool_client.get_currency().ticker,
+            new_collateral_amount,
+            loan.collateral_from.clone(),
+        )?;
+
+        Ok(Loan {
+            collateral_amount: new_collateral_amount,
+            health_factor: new_health_factor,
+            ..loan
+        })
+    }
+
+    /// Share of a repayment the pools send to the manager as revenue. Only the interest part of
+    /// the repayment is charged.
+    fn protocol_fee(amount: i128, unpaid_interest: i128) -> i128 {
+        amount.min(unpaid_interest) / 10
+    }
+
+    /// Preview of an operation that leaves `loan` behind and moves no tokens.
+    fn loan_preview(loan: Loan) -> OperationPreview {
+        OperationPreview {
+            borrowed_amount: loan.borrowed_amount,
+            collateral_amount: loan.collateral_amount,
+            unpaid_interest: loan.unpaid_interest,
+            health_factor: Some(loan.health_factor),
+            amount_in: 0,
+            amount_out: 0,
+            collateral_in: 0,
+            collateral_out: 0,
+            protocol_fee: 0,
+            liquidation_bonus: 0,
+            protocol_bonus: 0,
+        }
+    }
+
+    fn loan_summary(e: &Env, loan: Loan) -> Result<LoanSummary, LoanManagerError> {
+        let (borrowed_amount, unpaid_interest, _) = Self::accrued_debt(e, &loan)?;
+        let Loan {
+            loan_id,
+            borrowed_from,
+            collateral_amount,
+            collateral_from,
+            ..
+        } = loan;
+        let borrow_pool_client = loan_pool::Client::new(e, &borrowed_from);
+        let collateral_pool_client = loan_pool::Client::new(e, &collateral_from);
+
+        // The health factor is worked out from the same prices as the values below.
+        let price = Self::twap_price(e, borrow_pool_client.get_currency().ticker)?;
+        let collateral_price = Self::twap_price(e, collateral_pool_client.get_currency().ticker)?;
+        let collateral_factor = Self::collateral_factor(e, &borrowed_from, &collateral_from)?;
+        let health_factor = Self::health_factor_at_prices(
+            borrowed_amount,
+            price,
+            collateral_amount,
+            collateral_price,
+            collateral_factor,
+        )?;
+
+        let borrowed_value = price
+            .checked_mul(borrowed_amount)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        let collateral_value = collateral_price
+            .checked_mul(collateral_amount)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        let borrowing_power = collateral_value
+            .checked_mul(collateral_factor)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?
+            .checked_div(FIXED_POINT_ONE)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+
+        // Health factor is exactly 1.0 when the weighted collateral value equals the debt value.
+        // Without weighted collateral no price makes the loan healthy.
+        let weighted_collateral = collateral_amount
+            .checked_mul(collateral_factor)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        let liquidation_price = if weighted_collateral == 0 {
+            None
+        } else {
+            Some(
+                borrowed_value
+                    .checked_mul(FIXED_POINT_ONE)
+                    .ok_or(LoanManagerError::OverOrUnderFlow)?
+                    .checked_div(weighted_collateral)
+                    .ok_or(LoanManagerError::OverOrUnderFlow)?,
+            )
+        };
+
+        // Largest debt that still keeps the health factor above the threshold.
+        let max_borrowed_amount = borrowing_power
+            .checked_mul(FIXED_POINT_ONE)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?
+            .checked_div(
+                (HEALTH_FACTOR_THRESHOLD + 1)
+                    .checked_mul(price)
+                    .ok_or(LoanManagerError::OverOrUnderFlow)?,
+            )
+            .ok_or(LoanManagerError::OverOrUnderFlow)?;
+        let max_additional_borrow = max_borrowed_amount
+            .checked_sub(borrowed_amount)
+            .ok_or(LoanManagerError::OverOrUnderFlow)?
+            .max(0);
+
+        Ok(LoanSummary {
+            loan_id,
+            borrowed_from,
+            collateral_from,
+            borrowed_amount,
+            unpaid_interest,
+            collateral_amount,
+            borrowed_value,
+            collateral_value,
+            borrowing_power,
+            health_factor,
+            liquidation_price,
+            max_additional_borrow,
+        })
+    }
+
+    fn require_borrower_or_operator(
+        e: &Env,
+        caller: &Address,
+        loan_id: &LoanId,
+        scope: OperatorScope,
+    ) -> Result<(), LoanManagerError> {
+        caller.require_auth();
+        if *caller == loan_id.borrower_address
+            || storage::is_operator(e, &loan_id.borrower_address, caller, scope)
+        {
+            Ok(())
+        } else {
+            Err(LoanManagerError::Unauthorized)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::contract::loan_pool::{PoolState, Positions};
+
+    use super::*;
+    use loan_pool::Currency;
+    use soroban_sdk::{
+        events::Event as _,
+        symbol_short,
+        testutils::{storage::Persistent as _, Address as _, Events as _, Ledger},
+        token::{Client as TokenClient, StellarAssetClient},
+        vec,
+        xdr::ToXdr,
+        Env,
+    };
+    mod loan_manager {
+        soroban_sdk::contractimport!(file = "../../target/wasm32v1-none/release/loan_manager.wasm");
+    }
+
+    mod dex_mock {
+        soroban_sdk::contractimport!(file = "../../target/wasm32v1-none/release/dex_mock.wasm");
+    }
+
+    fn assert_pool_invariants(e: &Env, manager_client: &LoanManagerClient) {
+        let pools = e.as_contract(&manager_client.address, || storage::read_pool_addresses(e));
+        for pool in pools.iter() {
+            let report = loan_pool::Client::new(e, &pool).check_invariants();
+            assert!(report.holds, "{report:?}");
+        }
+    }
+
+    // The pool has no entry point to change its status, so write it to the pool's storage.
+    fn freeze_pool(e: &Env, pool: &Address) {
+        e.as_contract(pool, || {
+            e.storage().persistent().set(
+                &vec![e, Symbol::new(e, "PoolStatus")],
+                &loan_pool::PoolStatus::Frozen,
+            )
+        });
+    }
+
+    #[test]
+    fn initialize() {
+        let e = Env::default();
+        let admin = Address::generate(&e);
+        let oracle = Address::generate(&e);
+        let manager_addr = e.register(LoanManager, ());
+        let manager_client = LoanManagerClient::new(&e, &manager_addr);
+
+        assert!(manager_client.try_initialize(&admin, &oracle).is_ok());
+    }
+
+    #[test]
+    fn cannot_re_initialize() {
+        let e = Env::default();
+        let admin = Address::generate(&e);
+        let oracle = Address::generate(&e);
+
+        let contract_id = e.register(LoanManager, ());
+        let client = LoanManagerClient::new(&e, &contract_id);
+
+        client.initialize(&admin, &oracle);
+
+        assert!(client.try_initialize(&admin, &oracle).is_err())
+    }
+
+    #[test]
+    fn deploy_pool() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths();
+
+        // ACT
+        // Deploy contract using loan_manager as factory
+        let TestEnv {
+            manager_client,
+            pool_usdc_client,
+            pool_eurc_client,
+            ..
+        } = setup_test_env(&e);
+
+        // ASSERT
+        // No authorizations needed - the contract acts as a factory.
+        // assert_eq!(e.auths(), &[]);
+
+        // Invoke contract to check that it is initialized.
+        let usdc_balance = pool_usdc_client.get_contract_balance();
+        assert_eq!(usdc_balance, 1000);
+        let eurc_balance = pool_eurc_client.get_contract_balance();
+        assert_eq!(eurc_balance, 1000);
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn upgrade_manager_and_pool() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths();
+
+        let TestEnv { manager_client, .. } = setup_test_env(&e);
+        let manager_wasm_hash = e.deployer().upload_contract_wasm(loan_pool::WASM);
+        let pool_wasm_hash = e.deployer().upload_contract_wasm(loan_pool::WASM);
+
+        // ACT
+        manager_client.upgrade(&manager_wasm_hash, &pool_wasm_hash);
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn cannot_create_loan_untrusted_loan_pool() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths();
+        let TestEnv {
+            admin,
+            user,
+            manager_addr,
+            manager_client,
+            pool_xlm_addr,
+            ..
+        } = setup_test_env(&e);
+
+        // Set up a pool that's not trusted by loan manager
+        let ticker = Symbol::new(&e, "XLM");
+        let token_address = e
+            .register_stellar_asset_contract_v2(admin.clone())
+            .address();
+        let pool_addr = e.register(loan_pool::WASM, ());
+        let pool_client = loan_pool::Client::new(&e, &pool_addr);
+        pool_client.initialize(
+            &manager_addr,
+            &Currency {
+                ticker,
+                token_address,
+            },
+            &8_000_000,
+        );
+
+        // ACT
+        let res = manager_client.try_create_loan(&user, &10, &pool_xlm_addr, &100, &pool_addr);
+        assert!(res.is_err());
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn cannot_create_loan_untrusted_collateral_pool() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths();
+        let TestEnv {
+            admin,
+            user,
+            manager_addr,
+            manager_client,
+            pool_xlm_addr,
+            ..
+        } = setup_test_env(&e);
+
+        // Set up a pool that's not trusted by loan manager
+        let ticker = Symbol::new(&e, "XLM");
+        let token_address = e
+            .register_stellar_asset_contract_v2(admin.clone())
+            .address();
+        let pool_addr = e.register(loan_pool::WASM, ());
+        let pool_client = loan_pool::Client::new(&e, &pool_addr);
+        pool_client.initialize(
+            &manager_addr,
+            &Currency {
+                ticker,
+                token_address,
+            },
+            &8_000_000,
+        );
+
+        // ACT
+        let res = manager_client.try_create_loan(&user, &10, &pool_addr, &100, &pool_xlm_addr);
+        assert!(res.is_err());
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn withdraw_revenue_as_admin() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000;
+            li.timestamp = 1;
+            li.min_persistent_entry_ttl = 1_000_000;
+            li.min_temp_entry_ttl = 1_000_000;
+            li.max_entry_ttl = 1_000_001;
+        });
+
+        let TestEnv {
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            xlm_token_client,
+            usdc_token_client,
+            manager_addr,
+            pool_xlm_client,
+            xlm_asset_client,
+            usdc_asset_client,
+            admin,
+            reflector_addr,
+            ..
+        } = setup_test_env(&e);
+
+        xlm_asset_client.mint(&admin, &9_001);
+        pool_xlm_client.deposit(&admin, &9_001);
+        usdc_asset_client.mint(&user, &100_000);
+
+        // Create a loan.
+        let loan =
+            manager_client.create_loan(&user, &1_000, &pool_xlm_addr, &100_000, &pool_usdc_addr);
+
+        // Move in time
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 100_000;
+            li.timestamp = 1 + 31_556_926;
+        });
+
+        e.register_at(&reflector_addr, oracle::WASM, ());
+
+        // ASSERT
+        assert_eq!(xlm_token_client.balance(&user), 2_000);
+        assert_eq!(usdc_token_client.balance(&user), 0);
+
+        let user_loan = manager_client.get_loan(&loan.loan_id);
+
+        assert_eq!(user_loan.borrowed_amount, 1_000);
+        assert_eq!(user_loan.collateral_amount, 100_000);
+
+        manager_client.repay(&user, &loan.loan_id, &100);
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 100_000 + 1;
+        });
+
+        let user_loan = manager_client.get_loan(&loan.loan_id);
+
+        assert_eq!(user_loan.borrowed_amount, 930);
+        assert_eq!(user_loan.collateral_amount, 100_000);
+        assert_eq!(xlm_token_client.balance(&manager_addr), 3);
+
+        manager_client
+            .admin_withdraw_revenue(&1_i128, &pool_xlm_client.get_currency().token_address);
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    #[should_panic(expected = "Error(Contract, #12)")]
+    fn withdraw_new_user_after_pool_has_yield() {
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000;
+            li.timestamp = 1;
+            li.min_persistent_entry_ttl = 10_000_000;
+            li.min_temp_entry_ttl = 1_000_000;
+            li.max_entry_ttl = 1_000_001;
+        });
+
+        let TestEnv {
+            user,
+            admin,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            xlm_token_client,
+            usdc_token_client,
+            pool_usdc_client,
+            reflector_addr,
+            usdc_asset_client,
+            ..
+        } = setup_test_env(&e);
+
+        // ACT
+        // Create a loan.
+        let mut loan =
+            manager_client.create_loan(&user, &100, &pool_usdc_addr, &500, &pool_xlm_addr);
+        assert_eq!(pool_usdc_client.get_available_balance(), 900);
+
+        // Move in time
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 100_000;
+            li.timestamp = 1 + 31_556_926;
+        });
+
+        // A new instance of reflector mock needs to be created, they only live for one ledger.
+        e.register_at(&reflector_addr, oracle::WASM, ());
+
+        // ASSERT
+        assert_eq!(xlm_token_client.balance(&user), 500);
+        assert_eq!(usdc_token_client.balance(&user), 100);
+
+        loan = manager_client.get_loan(&loan.loan_id);
+
+        assert_eq!(loan.borrowed_amount, 100);
+        assert_eq!(loan.collateral_amount, 500);
+
+        manager_client.repay(&user, &loan.loan_id, &50);
+        loan = manager_client.get_loan(&loan.loan_id);
+        assert_eq!(loan.borrowed_amount, 52);
+
+        assert_eq!((52, 2), manager_client.repay(&user, &loan.loan_id, &50));
+        assert_eq!(1000, pool_usdc_client.get_available_balance());
+        assert_eq!(1002, pool_usdc_client.get_contract_balance());
+        assert_eq!(1000, pool_usdc_client.get_total_balance_shares());
+
+        // Create a new user that should not be able to withdraw more than what they have deposited even if the pool already has interest
+        let new_user = Address::generate(&e);
+        usdc_asset_client.mint(&new_user, &1_000);
+
+        pool_usdc_client.deposit(&new_user, &1000);
+        assert_eq!(2002, pool_usdc_client.get_contract_balance());
+        let positions_new_user = Positions {
+            collateral: 0,
+            liabilities: 0,
+            receivable_shares: 998,
+        };
+        assert_eq!(
+            positions_new_user,
+            pool_usdc_client.get_user_positions(&new_user)
+        );
+
+        let test_positions_admin = Positions {
+            collateral: 0,
+            liabilities: 0,
+            receivable_shares: 1000,
+        };
+        assert_eq!(
+            test_positions_admin,
+            pool_usdc_client.get_user_positions(&admin)
+        );
+        let pool_state = PoolState {
+            annual_interest_rate: 200887,
+            available_balance_tokens: 2000,
+            total_balance_shares: 1998,
+            total_balance_tokens: 2002,
+        };
+        assert_eq!(pool_state, pool_usdc_client.get_pool_state());
+
+        // Should panic because the user has no xlm to withdraw
+        assert_pool_invariants(&e, &manager_client);
+
+        pool_usdc_client.withdraw(&new_user, &1002);
+    }
+
+    #[test]
+    fn create_loan() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+
+        let TestEnv {
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            pool_eurc_addr,
+            xlm_token_client,
+            usdc_token_client,
+            eurc_token_client,
+            ..
+        } = setup_test_env(&e);
+
+        // ACT
+        manager_client.create_loan(&user, &10, &pool_usdc_addr, &100, &pool_xlm_addr);
+        manager_client.create_loan(&user, &30, &pool_eurc_addr, &300, &pool_xlm_addr);
+
+        // ASSERT
+        assert_eq!(xlm_token_client.balance(&user), 600);
+        assert_eq!(usdc_token_client.balance(&user), 10);
+        assert_eq!(eurc_token_client.balance(&user), 30);
+
+        let loans = manager_client.get_loans(&user);
+        assert_eq!(loans.len(), 2);
+
+        let loan_usdc = loans.get(0).unwrap();
+        assert_eq!(loan_usdc.borrowed_amount, 10);
+        assert_eq!(loan_usdc.collateral_amount, 100);
+        assert_eq!(loan_usdc.borrowed_from, pool_usdc_addr);
+        assert_eq!(loan_usdc.collateral_from, pool_xlm_addr);
+
+        let loan_eurc = loans.get(1).unwrap();
+        assert_eq!(loan_eurc.borrowed_amount, 30);
+        assert_eq!(loan_eurc.collateral_amount, 300);
+        assert_eq!(loan_eurc.borrowed_from, pool_eurc_addr);
+        assert_eq!(loan_eurc.collateral_from, pool_xlm_addr);
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn add_interest() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000;
+            li.timestamp = 1;
+            li.min_persistent_entry_ttl = 10_000_000;
+            li.min_temp_entry_ttl = 1_000_000;
+            li.max_entry_ttl = 1_000_001;
+        });
+
+        let TestEnv {
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            xlm_token_client,
+            usdc_token_client,
+            reflector_addr,
+            ..
+        } = setup_test_env(&e);
+
+        // ACT
+
+        // Create a loan.
+        let mut loan =
+            manager_client.create_loan(&user, &100, &pool_usdc_addr, &1000, &pool_xlm_addr);
+
+        // Here borrowed amount should be the same as time has not moved. add_interest() is only called to store the LastUpdate sequence number.
+        assert_eq!(loan.borrowed_amount, 100);
+        assert_eq!(loan.health_factor, 80_000_000);
+        assert_eq!(xlm_token_client.balance(&user), 0);
+        assert_eq!(usdc_token_client.balance(&user), 100);
+
+        // Move time
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 100_000;
+            li.timestamp = 1 + 31_556_926;
+        });
+
+        // A new instance of reflector mock needs to be created, they only live for one ledger.
+        e.register_at(&reflector_addr, oracle::WASM, ());
+
+        loan = manager_client.add_interest(&loan.loan_id);
+
+        assert_eq!(loan.borrowed_amount, 102);
+        assert_eq!(loan.health_factor, 78_431_372);
+        assert_eq!(loan.collateral_amount, 1000);
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn account_summary() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000;
+            li.timestamp = 1;
+            li.min_persistent_entry_ttl = 10_000_000;
+            li.min_temp_entry_ttl = 1_000_000;
+            li.max_entry_ttl = 1_000_001;
+        });
+
+        let TestEnv {
+            admin,
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            reflector_addr,
+            ..
+        } = setup_test_env(&e);
+
+        let loan = manager_client.create_loan(&user, &100, &pool_usdc_addr, &1000, &pool_xlm_addr);
+
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 100_000;
+            li.timestamp = 1 + 31_556_926;
+        });
+        e.register_at(&reflector_addr, oracle::WASM, ());
+        let reflector_client = oracle::Client::new(&e, &reflector_addr);
+        for (ticker, price) in [("XLM", 2_000_000), ("USDC", 10_000_000)] {
+            reflector_client.update_price(
+                &Asset::Other(Symbol::new(&e, ticker)),
+                &oracle::PriceData {
+                    price,
+                    timestamp: 1 + 31_556_926,
+                },
+            );
+        }
+
+        // ACT
+        let summary = manager_client.get_account_summary(&user);
+
+        // ASSERT
+        assert_eq!(summary.loans.len(), 1);
+        let loan_summary = summary.loans.get(0).unwrap();
+        assert_eq!(loan_summary.loan_id, loan.loan_id);
+        assert_eq!(loan_summary.borrowed_amount, 102);
+        assert_eq!(loan_summary.unpaid_interest, 2);
+        assert_eq!(loan_summary.collateral_amount, 1000);
+        assert_eq!(loan_summary.borrowed_value, 1_020_000_000);
+        assert_eq!(loan_summary.collateral_value, 2_000_000_000);
+        assert_eq!(loan_summary.borrowing_power, 1_600_000_000);
+        assert_eq!(loan_summary.health_factor, 15_686_274);
+        assert_eq!(loan_summary.liquidation_price, Some(1_275_000));
+        assert_eq!(loan_summary.max_additional_borrow, 57);
+        assert_eq!(summary.total_borrowed_value, 1_020_000_000);
+        assert_eq!(summary.total_collateral_value, 2_000_000_000);
+        assert_eq!(summary.total_borrowing_power, 1_600_000_000);
+
+        // The summary does not write the accrued interest.
+        assert_eq!(manager_client.get_loan(&loan.loan_id), loan);
+        let accrued = manager_client.add_interest(&loan.loan_id);
+        assert_eq!(accrued.borrowed_amount, loan_summary.borrowed_amount);
+        assert_eq!(accrued.unpaid_interest, loan_summary.unpaid_interest);
+        assert_eq!(accrued.health_factor, loan_summary.health_factor);
+
+        // A loan without collateral has no liquidation price.
+        e.as_contract(&manager_client.address, || {
+            storage::write_loan(
+                &e,
+                &loan.loan_id,
+                &Loan {
+                    collateral_amount: 0,
+                    ..accrued.clone()
+                },
+            )
+        });
+        let loan_summary = manager_client
+            .get_account_summary(&user)
+            .loans
+            .get(0)
+            .unwrap();
+        assert_eq!(loan_summary.health_factor, 0);
+        assert_eq!(loan_summary.liquidation_price, None);
+        assert_eq!(loan_summary.max_additional_borrow, 0);
+        e.as_contract(&manager_client.address, || {
+            storage::write_loan(&e, &loan.loan_id, &accrued)
+        });
+
+        let empty = manager_client.get_account_summary(&admin);
+        assert_eq!(empty.loans.len(), 0);
+        assert_eq!(empty.total_borrowed_value, 0);
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn repay() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000;
+            li.timestamp = 1;
+            li.min_persistent_entry_ttl = 1_000_000;
+            li.min_temp_entry_ttl = 1_000_000;
+            li.max_entry_ttl = 1_000_001;
+        });
+
+        let TestEnv {
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            pool_eurc_addr,
+            pool_usdc_client,
+            pool_eurc_client,
+            xlm_token_client,
+            usdc_token_client,
+            reflector_addr,
+            eurc_token_client,
+            ..
+        } = setup_test_env(&e);
+
+        // ACT
+        // Create a loan.
+        let mut loan_usdc =
+            manager_client.create_loan(&user, &100, &pool_usdc_addr, &500, &pool_xlm_addr);
+        let mut loan_eurc =
+            manager_client.create_loan(&user, &100, &pool_eurc_addr, &500, &pool_xlm_addr);
+
+        // Move in time
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 100_000;
+            li.timestamp = 1 + 31_556_926;
+        });
+
+        // A new instance of reflector mock needs to be created, they only live for one ledger.
+        e.register_at(&reflector_addr, oracle::WASM, ());
+
+        // ASSERT
+        assert_eq!(xlm_token_client.balance(&user), 0);
+        assert_eq!(usdc_token_client.balance(&user), 100);
+        assert_eq!(eurc_token_client.balance(&user), 100);
+
+        loan_usdc = manager_client.get_loan(&loan_usdc.loan_id);
+        assert_eq!(loan_usdc.borrowed_amount, 100);
+        assert_eq!(loan_usdc.collateral_amount, 500);
+
+        manager_client.repay(&user, &loan_usdc.loan_id, &50);
+        loan_usdc = manager_client.get_loan(&loan_usdc.loan_id);
+        assert_eq!(loan_usdc.borrowed_amount, 52);
+        assert_eq!(loan_usdc.collateral_amount, 500);
+
+        assert_eq!(
+            (52, 2),
+            manager_client.repay(&user, &loan_usdc.loan_id, &50)
+        );
+        assert_eq!(1000, pool_usdc_client.get_available_balance());
+        assert_eq!(1002, pool_usdc_client.get_contract_balance());
+        assert_eq!(1000, pool_usdc_client.get_total_balance_shares());
+
+        loan_eurc = manager_client.get_loan(&loan_eurc.loan_id);
+        assert_eq!(loan_eurc.borrowed_amount, 100);
+        assert_eq!(loan_eurc.collateral_amount, 500);
+        assert_eq!(900, pool_eurc_client.get_available_balance());
+        assert_eq!(1000, pool_eurc_client.get_contract_balance());
+        assert_eq!(1000, pool_eurc_client.get_total_balance_shares());
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn repay_for() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+
+        let TestEnv {
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            pool_usdc_client,
+            usdc_asset_client,
+            usdc_token_client,
+            ..
+        } = setup_test_env(&e);
+        let guarantor = Address::generate(&e);
+        usdc_asset_client.mint(&guarantor, &60);
+
+        let loan = manager_client.create_loan(&user, &100, &pool_usdc_addr, &1000, &pool_xlm_addr);
+
+        // ACT
+        let result = manager_client.repay_for(&guarantor, &loan.loan_id, &60);
+
+        // ASSERT
+        assert_eq!(result, (100, 40));
+        assert_eq!(manager_client.get_loan(&loan.loan_id).borrowed_amount, 40);
+        assert_eq!(usdc_token_client.balance(&guarantor), 0);
+        assert_eq!(usdc_token_client.balance(&user), 100);
+        assert_eq!(pool_usdc_client.get_user_positions(&user).liabilities, 40);
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn deleverage() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+
+        let test_env = setup_test_env(&e);
+        let (router, dex_client) = setup_dex(&e, &test_env, 0);
+        let TestEnv {
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            usdc_asset_client,
+            usdc_token_client,
+            xlm_token_client,
+            ..
+        } = test_env;
+        usdc_asset_client.mint(&router, &1_000);
+
+        let loan = manager_client.create_loan(&user, &100, &pool_usdc_addr, &1000, &pool_xlm_addr);
+        // The borrowed tokens are spent elsewhere.
+        usdc_token_client.transfer(&user, Address::generate(&e), &100);
+
+        // ACT
+        let deleveraged = manager_client.deleverage(&loan.loan_id, &60, &60).unwrap();
+
+        // ASSERT
+        assert_eq!(deleveraged.borrowed_amount, 40);
+        assert_eq!(deleveraged.collateral_amount, 940);
+        assert_eq!(usdc_token_client.balance(&user), 0);
+        assert_eq!(xlm_token_client.balance(&router), 60);
+
+        assert_eq!(
+            manager_client.try_deleverage(&loan.loan_id, &10, &11),
+            Err(Ok(LoanManagerError::SlippageExceeded))
+        );
+        // The router's reported output is not trusted.
+        dex_client.set_overreport(&1_000);
+        assert_eq!(
+            manager_client.try_deleverage(&loan.loan_id, &10, &11),
+            Err(Ok(LoanManagerError::SlippageExceeded))
+        );
+        dex_client.set_overreport(&0);
+
+        // Selling more collateral than the debt is worth closes the loan.
+        assert_eq!(manager_client.deleverage(&loan.loan_id, &50, &50), None);
+        assert_eq!(usdc_token_client.balance(&user), 10);
+        assert_eq!(xlm_token_client.balance(&user), 890);
+        assert_eq!(
+            manager_client.try_get_loan(&loan.loan_id),
+            Err(Ok(LoanManagerError::LoanNotFound))
+        );
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn create_leveraged_loan() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+
+        let test_env = setup_test_env(&e);
+        // Pays out half a percent less than the oracle price.
+        let (router, dex_client) = setup_dex(&e, &test_env, 50_000);
+        let TestEnv {
+            admin,
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            pool_usdc_client,
+            xlm_asset_client,
+            xlm_token_client,
+            usdc_token_client,
+            ..
+        } = test_env;
+        pool_usdc_client.deposit(&admin, &5_000);
+        xlm_asset_client.mint(&router, &10_000);
+
+        // ACT
+        let loan = manager_client.create_leveraged_loan(
+            &user,
+            &1_000,
+            &pool_xlm_addr,
+            &pool_usdc_addr,
+            &LeverageTarget {
+                leverage: 20_000_000,  // 2x
+                max_slippage: 100_000, // 1%
+            },
+        );
+
+        // ASSERT
+        assert_eq!(loan.borrowed_amount, 1_000);
+        assert_eq!(loan.collateral_amount, 1_995);
+        assert_eq!(loan.health_factor, 15_960_000);
+        assert_eq!(xlm_token_client.balance(&user), 0);
+        assert_eq!(usdc_token_client.balance(&user), 0);
+        assert_eq!(usdc_token_client.balance(&router), 1_000);
+
+        xlm_asset_client.mint(&user, &1_000);
+        assert_eq!(
+            manager_client.try_create_leveraged_loan(
+                &user,
+                &1_000,
+                &pool_xlm_addr,
+                &pool_usdc_addr,
+                &LeverageTarget {
+                    leverage: 20_000_000,
+                    max_slippage: 40_000, // 0.4%
+                },
+            ),
+            Err(Ok(LoanManagerError::SlippageExceeded))
+        );
+        // The router's reported output is not trusted.
+        dex_client.set_overreport(&1_000);
+        assert_eq!(
+            manager_client.try_create_leveraged_loan(
+                &user,
+                &1_000,
+                &pool_xlm_addr,
+                &pool_usdc_addr,
+                &LeverageTarget {
+                    leverage: 20_000_000,
+                    max_slippage: 40_000,
+                },
+            ),
+            Err(Ok(LoanManagerError::SlippageExceeded))
+        );
+        dex_client.set_overreport(&0);
+
+        // With a collateral factor of 80%, 5x leverage leaves a health factor of exactly 1.0.
+        dex_client.set_slippage(&0);
+        assert_eq!(
+            manager_client.try_create_leveraged_loan(
+                &user,
+                &1_000,
+                &pool_xlm_addr,
+                &pool_usdc_addr,
+                &LeverageTarget {
+                    leverage: 50_000_000,
+                    max_slippage: 100_000,
+                },
+            ),
+            Err(Ok(LoanManagerError::HealthFactorTooLow))
+        );
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn deleverage_keeps_loan_healthy() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+
+        let test_env = setup_test_env(&e);
+        // Pays out a twentieth of the input.
+        let (router, _) = setup_dex(&e, &test_env, 9_500_000);
+        let TestEnv {
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            usdc_asset_client,
+            ..
+        } = test_env;
+        usdc_asset_client.mint(&router, &1_000);
+
+        let loan = manager_client.create_loan(&user, &100, &pool_usdc_addr, &1000, &pool_xlm_addr);
+
+        // ACT & ASSERT
+        assert_eq!(
+            manager_client.try_deleverage(&loan.loan_id, &990, &49),
+            Err(Ok(LoanManagerError::HealthFactorTooLow))
+        );
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn set_swap_router() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+
+        let test_env = setup_test_env(&e);
+        let TestEnv {
+            user,
+            manager_addr,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            reflector_addr,
+            ..
+        } = &test_env;
+        let loan = manager_client.create_loan(user, &100, pool_usdc_addr, &1000, pool_xlm_addr);
+        assert_eq!(
+            manager_client.try_deleverage(&loan.loan_id, &60, &60),
+            Err(Ok(LoanManagerError::SwapRouterNotFound))
+        );
+
+        // ACT
+        let (router, _) = setup_dex(&e, &test_env, 0);
+
+        // ASSERT
+        assert!(e.events().all().contains((
+            manager_addr.clone(),
+            storage::EventSwapRouterSet {
+                router: router.clone()
+            }
+            .topics(&e),
+            storage::EventSwapRouterSet {
+                router: router.clone()
+            }
+            .data(&e),
+        )));
+        assert_eq!(manager_client.get_swap_router(), router);
+
+        for invalid in [
+            Address::generate(&e),
+            manager_addr.clone(),
+            reflector_addr.clone(),
+            pool_xlm_addr.clone(),
+        ] {
+            assert_eq!(
+                manager_client.try_set_swap_router(&invalid),
+                Err(Ok(LoanManagerError::InvalidSwapRouter))
+            );
+        }
+        assert_eq!(manager_client.get_swap_router(), router);
+
+        assert_pool_invariants(&e, manager_client);
+    }
+
+    #[test]
+    fn repay_and_close() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000;
+            li.timestamp = 1;
+            li.min_persistent_entry_ttl = 1_000_000;
+            li.min_temp_entry_ttl = 1_000_000;
+            li.max_entry_ttl = 1_000_001;
+        });
+
+        let TestEnv {
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            xlm_token_client,
+            usdc_token_client,
+            reflector_addr,
+            pool_usdc_client,
+            usdc_asset_client,
+            ..
+        } = setup_test_env(&e);
+
+        // ACT
+        // Create a loan.
+        let loan = manager_client.create_loan(&user, &100, &pool_usdc_addr, &300, &pool_xlm_addr);
+
+        // Move in time
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 100_000;
+            li.timestamp = 1 + 31_556_926;
+        });
+
+        // A new instance of reflector mock needs to be created, they only live for one ledger.
+        e.register_at(&reflector_addr, oracle::WASM, ());
+
+        // ASSERT
+        assert_eq!(xlm_token_client.balance(&user), 700);
+        assert_eq!(usdc_token_client.balance(&user), 100);
+
+        let loans = manager_client.get_loans(&user);
+        assert_eq!(loans.len(), 1);
+
+        // mint the user some money so they can repay.
+        usdc_asset_client.mint(&user, &45);
+        manager_client.repay_and_close_manager(&user, &145, &loan.loan_id);
+
+        let loans = manager_client.get_loans(&user);
+        assert_eq!(loans.len(), 0);
+        assert_eq!(1002, pool_usdc_client.get_available_balance());
+        assert_eq!(1002, pool_usdc_client.get_contract_balance());
+        assert_eq!(1000, pool_usdc_client.get_total_balance_shares());
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn repay_and_close_with_multiple_loans() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000;
+            li.timestamp = 1;
+            li.min_persistent_entry_ttl = 1_000_000;
+            li.min_temp_entry_ttl = 1_000_000;
+            li.max_entry_ttl = 1_000_001;
+        });
+
+        let TestEnv {
+            user,
+            manager_client,
+            usdc_asset_client,
+            pool_usdc_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            pool_eurc_addr,
+            usdc_token_client,
+            reflector_addr,
+            ..
+        } = setup_test_env(&e);
+
+        // ACT
+        let mut usdc_loan =
+            manager_client.create_loan(&user, &100, &pool_usdc_addr, &300, &pool_xlm_addr);
+        manager_client.create_loan(&user, &100, &pool_eurc_addr, &300, &pool_xlm_addr);
+
+        // Move in time
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 100_000;
+            li.timestamp = 1 + 31_556_926;
+        });
+
+        // ASSERT
+        // A new instance of reflector mock needs to be created, they only live for one ledger.
+        e.register_at(&reflector_addr, oracle::WASM, ());
+
+        let loans = manager_client.get_loans(&user);
+        assert_eq!(loans.len(), 2);
+        usdc_loan = manager_client.get_loan(&usdc_loan.loan_id);
+
+        assert_eq!(usdc_loan.borrowed_amount, 100);
+        assert_eq!(usdc_loan.collateral_amount, 300);
+
+        // mint the user some money so they can repay.
+        usdc_asset_client.mint(&user, &45);
+        assert_eq!(
+            102,
+            manager_client.repay_and_close_manager(
+                &user,
+                &(usdc_loan.borrowed_amount + 45),
+                &usdc_loan.loan_id
+            )
+        );
+
+        assert_eq!(1002, pool_usdc_client.get_available_balance());
+        assert_eq!(1002, pool_usdc_client.get_contract_balance());
+        assert_eq!(1000, pool_usdc_client.get_total_balance_shares());
+        assert_eq!(43, usdc_token_client.balance(&user));
+
+        // The eurc loan should still be there after repaying the usdc loan
+        let loans = manager_client.get_loans(&user);
+        assert_eq!(loans.len(), 1);
+        let eurc_loan = loans.get(0).unwrap();
+        assert_eq!(eurc_loan.borrowed_amount, 100);
+        assert_eq!(eurc_loan.collateral_amount, 300);
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    #[should_panic(expected = "Amount can not be greater than borrowed amount!")]
+    fn repay_more_than_borrowed() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+        let TestEnv {
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            ..
+        } = setup_test_env(&e);
+
+        // ACT
+        // Create a loan.
+        let loan = manager_client.create_loan(&user, &100, &pool_usdc_addr, &1000, &pool_xlm_addr);
+
+        assert_pool_invariants(&e, &manager_client);
+
+        manager_client.repay(&user, &loan.loan_id, &2_000);
+    }
+
+    #[test]
+    fn operator_repay() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+        let TestEnv {
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            usdc_asset_client,
+            usdc_token_client,
+            ..
+        } = setup_test_env(&e);
+        let operator = Address::generate(&e);
+        usdc_asset_client.mint(&operator, &50);
+
+        let loan = manager_client.create_loan(&user, &100, &pool_usdc_addr, &1000, &pool_xlm_addr);
+
+        // ACT
+        manager_client.approve_operator(&user, &operator, &OperatorScope::Repay);
+        manager_client.repay(&operator, &loan.loan_id, &50);
+
+        // ASSERT
+        assert!(manager_client.is_operator(&user, &operator, &OperatorScope::Repay));
+        assert_eq!(manager_client.get_loan(&loan.loan_id).borrowed_amount, 50);
+        assert_eq!(usdc_token_client.balance(&operator), 0);
+        assert_eq!(usdc_token_client.balance(&user), 100);
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn operator_add_collateral() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+        let TestEnv {
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_xlm_client,
+            pool_usdc_addr,
+            xlm_asset_client,
+            xlm_token_client,
+            ..
+        } = setup_test_env(&e);
+        let operator = Address::generate(&e);
+        xlm_asset_client.mint(&operator, &200);
+
+        let loan = manager_client.create_loan(&user, &100, &pool_usdc_addr, &500, &pool_xlm_addr);
+
+        // ACT
+        manager_client.approve_operator(&user, &operator, &OperatorScope::AddCollateral);
+        let loan = manager_client.add_collateral(&operator, &loan.loan_id, &200);
+
+        // ASSERT
+        assert_eq!(loan.collateral_amount, 700);
+        assert_eq!(loan.health_factor, 56_000_000);
+        assert_eq!(xlm_token_client.balance(&operator), 0);
+        assert_eq!(pool_xlm_client.get_user_positions(&user).collateral, 700);
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn operator_withdraw_collateral() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+        let TestEnv {
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_xlm_client,
+            pool_usdc_addr,
+            xlm_token_client,
+            ..
+        } = setup_test_env(&e);
+        let operator = Address::generate(&e);
+
+        let loan = manager_client.create_loan(&user, &100, &pool_usdc_addr, &1000, &pool_xlm_addr);
+
+        // ACT
+        manager_client.approve_operator(&user, &operator, &OperatorScope::WithdrawCollateral);
+        let loan = manager_client.withdraw_collateral(&operator, &loan.loan_id, &500);
+
+        // ASSERT
+        // Withdrawn collateral always goes back to the borrower.
+        assert_eq!(loan.collateral_amount, 500);
+        assert_eq!(xlm_token_client.balance(&user), 500);
+        assert_eq!(xlm_token_client.balance(&operator), 0);
+        assert_eq!(pool_xlm_client.get_user_positions(&user).collateral, 500);
+
+        // The loan has to stay healthy after the withdrawal.
+        assert_eq!(
+            manager_client.try_withdraw_collateral(&operator, &loan.loan_id, &400),
+            Err(Ok(LoanManagerError::HealthFactorTooLow))
+        );
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn operator_close() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+        let TestEnv {
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            usdc_asset_client,
+            usdc_token_client,
+            xlm_token_client,
+            ..
+        } = setup_test_env(&e);
+        let operator = Address::generate(&e);
+        usdc_asset_client.mint(&operator, &110);
+
+        let loan = manager_client.create_loan(&user, &100, &pool_usdc_addr, &300, &pool_xlm_addr);
+
+        // ACT
+        manager_client.approve_operator(&user, &operator, &OperatorScope::Close);
+        manager_client.repay_and_close_manager(&operator, &110, &loan.loan_id);
+
+        // ASSERT
+        assert_eq!(manager_client.get_loans(&user).len(), 0);
+        assert_eq!(usdc_token_client.balance(&operator), 10);
+        assert_eq!(usdc_token_client.balance(&user), 100);
+        assert_eq!(xlm_token_client.balance(&user), 1000);
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn operator_needs_matching_scope() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+        let TestEnv {
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            usdc_asset_client,
+            ..
+        } = setup_test_env(&e);
+        let operator = Address::generate(&e);
+        usdc_asset_client.mint(&operator, &200);
+
+        let loan = manager_client.create_loan(&user, &100, &pool_usdc_addr, &1000, &pool_xlm_addr);
+
+        // ACT & ASSERT
+        // Not approved at all.
+        assert_eq!(
+            manager_client.try_repay(&operator, &loan.loan_id, &10),
+            Err(Ok(LoanManagerError::Unauthorized))
+        );
+
+        // Approved for a different scope.
+        manager_client.approve_operator(&user, &operator, &OperatorScope::Repay);
+        assert_eq!(
+            manager_client.try_withdraw_collateral(&operator, &loan.loan_id, &10),
+            Err(Ok(LoanManagerError::Unauthorized))
+        );
+        assert_eq!(
+            manager_client.try_add_collateral(&operator, &loan.loan_id, &10),
+            Err(Ok(LoanManagerError::Unauthorized))
+        );
+        assert_eq!(
+            manager_client.try_repay_and_close_manager(&operator, &200, &loan.loan_id),
+            Err(Ok(LoanManagerError::Unauthorized))
+        );
+
+        // Revoked approval.
+        manager_client.revoke_operator(&user, &operator, &OperatorScope::Repay);
+        assert!(!manager_client.is_operator(&user, &operator, &OperatorScope::Repay));
+        assert_eq!(
+            manager_client.try_repay(&operator, &loan.loan_id, &10),
+            Err(Ok(LoanManagerError::Unauthorized))
+        );
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn transfer_loan() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+        let TestEnv {
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_xlm_client,
+            pool_usdc_addr,
+            pool_usdc_client,
+            usdc_asset_client,
+            ..
+        } = setup_test_env(&e);
+        let new_owner = Address::generate(&e);
+
+        let loan = manager_client.create_loan(&user, &100, &pool_usdc_addr, &1000, &pool_xlm_addr);
+
+        // ACT
+        let transferred = manager_client.transfer_loan(&loan.loan_id, &new_owner);
+
+        // ASSERT
+        assert_eq!(transferred.loan_id.borrower_address, new_owner);
+        assert_eq!(transferred.loan_id.nonce, 1);
+        assert_eq!(transferred.borrowed_amount, 100);
+        assert_eq!(transferred.collateral_amount, 1000);
+        assert_eq!(manager_client.get_loans(&user).len(), 0);
+        assert_eq!(manager_client.get_loans(&new_owner).len(), 1);
+        assert!(manager_client.try_get_loan(&loan.loan_id).is_err());
+
+        assert_eq!(pool_usdc_client.get_user_positions(&user).liabilities, 0);
+        assert_eq!(
+            pool_usdc_client.get_user_positions(&new_owner).liabilities,
+            100
+        );
+        assert_eq!(pool_xlm_client.get_user_positions(&user).collateral, 0);
+        assert_eq!(
+            pool_xlm_client.get_user_positions(&new_owner).collateral,
+            1000
+        );
+
+        // The new owner can manage the loan, the old owner can not.
+        assert_eq!(
+            manager_client.try_repay(&user, &transferred.loan_id, &10),
+            Err(Ok(LoanManagerError::Unauthorized))
+        );
+        usdc_asset_client.mint(&new_owner, &50);
+        manager_client.repay(&new_owner, &transferred.loan_id, &50);
+        assert_eq!(
+            manager_client
+                .get_loan(&transferred.loan_id)
+                .borrowed_amount,
+            50
+        );
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn cannot_transfer_loan_to_owner() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+        let TestEnv {
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            ..
+        } = setup_test_env(&e);
+
+        let loan = manager_client.create_loan(&user, &100, &pool_usdc_addr, &1000, &pool_xlm_addr);
+
+        // ACT & ASSERT
+        assert_eq!(
+            manager_client.try_transfer_loan(&loan.loan_id, &user),
+            Err(Ok(LoanManagerError::InvalidLoanTransfer))
+        );
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn execute_trigger_repay_from_collateral() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+        let TestEnv {
+            admin,
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_xlm_client,
+            pool_usdc_addr,
+            pool_usdc_client,
+            usdc_asset_client,
+            xlm_asset_client,
+            xlm_token_client,
+            ..
+        } = setup_test_env(&e);
+        let keeper = Address::generate(&e);
+        pool_usdc_client.deposit(&admin, &20_000);
+        xlm_asset_client.mint(&user, &13_000);
+        usdc_asset_client.mint(&keeper, &5_000);
+
+        let loan =
+            manager_client.create_loan(&user, &10_000, &pool_usdc_addr, &13_000, &pool_xlm_addr);
+        assert_eq!(loan.health_factor, 10_400_000);
+
+        // ACT & ASSERT
+        // The loan is healthier than the trigger level.
+        manager_client.set_trigger(
+            &loan.loan_id,
+            &Trigger {
+                health_factor: 10_200_000,
+                action: TriggerAction::RepayFromCollateral,
+                fraction: 5_000_000,
+            },
+        );
+        assert_eq!(
+            manager_client.try_execute_trigger(&keeper, &loan.loan_id),
+            Err(Ok(LoanManagerError::TriggerNotMet))
+        );
+
+        let trigger = Trigger {
+            health_factor: 11_000_000,
+            action: TriggerAction::RepayFromCollateral,
+            fraction: 5_000_000,
+        };
+        manager_client.set_trigger(&loan.loan_id, &trigger);
+        assert_eq!(manager_client.get_trigger(&loan.loan_id), Some(trigger));
+
+        let loan = manager_client.execute_trigger(&keeper, &loan.loan_id);
+
+        // Keeper repaid half of the debt and got the same value in collateral plus 0.5%.
+        assert_eq!(loan.borrowed_amount, 5_000);
+        assert_eq!(loan.collateral_amount, 7_975);
+        assert_eq!(loan.health_factor, 12_760_000);
+        assert_eq!(xlm_token_client.balance(&keeper), 5_025);
+        assert_eq!(pool_xlm_client.get_user_positions(&user).collateral, 7_975);
+        assert_eq!(
+            pool_usdc_client.get_user_positions(&user).liabilities,
+            5_000
+        );
+        assert_eq!(manager_client.get_trigger(&loan.loan_id), None);
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn execute_trigger_partial_close() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+        let TestEnv {
+            admin,
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            pool_usdc_client,
+            usdc_asset_client,
+            xlm_asset_client,
+            xlm_token_client,
+            ..
+        } = setup_test_env(&e);
+        let keeper = Address::generate(&e);
+        pool_usdc_client.deposit(&admin, &20_000);
+        xlm_asset_client.mint(&user, &13_000);
+        usdc_asset_client.mint(&keeper, &5_000);
+
+        let loan =
+            manager_client.create_loan(&user, &10_000, &pool_usdc_addr, &13_000, &pool_xlm_addr);
+        manager_client.set_trigger(
+            &loan.loan_id,
+            &Trigger {
+                health_factor: 11_000_000,
+                action: TriggerAction::PartialClose,
+                fraction: 5_000_000,
+            },
+        );
+
+        // ACT & ASSERT
+        // Repaying half of the debt would leave less than the pool's minimum.
+        manager_client.set_loan_limits(
+            &pool_usdc_addr,
+            &LoanLimits {
+                min_borrow: 6_000,
+                min_collateral: 0,
+            },
+        );
+        assert_eq!(
+            manager_client.try_execute_trigger(&keeper, &loan.loan_id),
+            Err(Ok(LoanManagerError::DustRemaining))
+        );
+        manager_client.set_loan_limits(&pool_usdc_addr, &LoanLimits::default());
+
+        let loan = manager_client.execute_trigger(&keeper, &loan.loan_id);
+
+        // Half of the debt is repaid. Of the collateral the keeper did not take, the borrower
+        // gets back what the loan does not need to stay above the trigger level.
+        assert_eq!(loan.borrowed_amount, 5_000);
+        assert_eq!(loan.collateral_amount, 6_877);
+        assert_eq!(loan.health_factor, 11_002_000);
+        assert_eq!(xlm_token_client.balance(&keeper), 5_025);
+        assert_eq!(xlm_token_client.balance(&user), 2_098);
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn cannot_set_invalid_trigger() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+        let TestEnv {
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            ..
+        } = setup_test_env(&e);
+        let loan = manager_client.create_loan(&user, &100, &pool_usdc_addr, &1000, &pool_xlm_addr);
+
+        // ACT & ASSERT
+        // A trigger at or below the liquidation level would never beat a liquidation.
+        assert_eq!(
+            manager_client.try_set_trigger(
+                &loan.loan_id,
+                &Trigger {
+                    health_factor: 10_000_000,
+                    action: TriggerAction::RepayFromCollateral,
+                    fraction: 5_000_000,
+                }
+            ),
+            Err(Ok(LoanManagerError::InvalidTrigger))
+        );
+        assert_eq!(
+            manager_client.try_set_trigger(
+                &loan.loan_id,
+                &Trigger {
+                    health_factor: 11_000_000,
+                    action: TriggerAction::PartialClose,
+                    fraction: 10_000_000,
+                }
+            ),
+            Err(Ok(LoanManagerError::InvalidTrigger))
+        );
+        assert_eq!(
+            manager_client.try_remove_trigger(&loan.loan_id),
+            Err(Ok(LoanManagerError::TriggerNotFound))
+        );
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn accrue_loans() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000;
+            li.timestamp = 1;
+            li.min_persistent_entry_ttl = 1_000_000;
+            li.min_temp_entry_ttl = 1_000_000;
+            li.max_entry_ttl = 1_000_001;
+        });
+
+        let TestEnv {
+            user,
+            manager_addr,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            pool_eurc_addr,
+            usdc_asset_client,
+            usdc_token_client,
+            reflector_addr,
+            ..
+        } = setup_test_env(&e);
+        let keeper = Address::generate(&e);
+
+        let usdc_loan =
+            manager_client.create_loan(&user, &100, &pool_usdc_addr, &300, &pool_xlm_addr);
+        let eurc_loan =
+            manager_client.create_loan(&user, &100, &pool_eurc_addr, &300, &pool_xlm_addr);
+        let closed_loan_id = LoanId {
+            borrower_address: user.clone(),
+            nonce: 99,
+        };
+
+        // A loan that can not be accrued, its collateral value overflows.
+        let broken_loan_id = LoanId {
+            borrower_address: user.clone(),
+            nonce: 100,
+        };
+        e.as_contract(&manager_addr, || {
+            storage::write_loan(
+                &e,
+                &broken_loan_id,
+                &Loan {
+                    loan_id: broken_loan_id.clone(),
+                    collateral_amount: i128::MAX,
+                    ..usdc_loan.clone()
+                },
+            )
+        });
+
+        // Fund the keeper reward from the manager's revenue.
+        usdc_asset_client.mint(&manager_addr, &100);
+        manager_client.set_keeper_reward(&usdc_token_client.address, &5, &30_000_000);
+
+        // Move in time
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 100_000;
+            li.timestamp = 1 + 31_556_926;
+        });
+
+        // A new instance of reflector mock needs to be created, they only live for one ledger.
+        e.register_at(&reflector_addr, oracle::WASM, ());
+
+        // ACT
+        let outcomes = manager_client.accrue_loans(
+            &keeper,
+            &vec![
+                &e,
+                usdc_loan.loan_id.clone(),
+                closed_loan_id,
+                broken_loan_id,
+                eurc_loan.loan_id.clone(),
+            ],
+        );
+
+        // ASSERT
+        assert_eq!(
+            outcomes,
+            vec![
+                &e,
+                AccrualOutcome::Rewarded,
+                AccrualOutcome::LoanNotFound,
+                AccrualOutcome::Failed,
+                AccrualOutcome::Rewarded,
+            ]
+        );
+        assert_eq!(
+            manager_client.get_loan(&usdc_loan.loan_id).borrowed_amount,
+            102
+        );
+        assert_eq!(
+            manager_client.get_loan(&eurc_loan.loan_id).borrowed_amount,
+            102
+        );
+        assert_eq!(usdc_token_client.balance(&keeper), 10);
+
+        // Nothing has accrued since, so there is nothing to reward.
+        assert_eq!(
+            manager_client.accrue_loans(&keeper, &vec![&e, usdc_loan.loan_id.clone()]),
+            vec![&e, AccrualOutcome::Accrued]
+        );
+        assert_eq!(usdc_token_client.balance(&keeper), 10);
+
+        // The debt grows again, but the loan was accrued too recently to earn the reward.
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 200_000;
+            li.timestamp = 1 + 31_556_926 + 15_778_463;
+        });
+        e.register_at(&reflector_addr, oracle::WASM, ());
+        assert_eq!(
+            manager_client.accrue_loans(&keeper, &vec![&e, usdc_loan.loan_id.clone()]),
+            vec![&e, AccrualOutcome::Accrued]
+        );
+        assert_eq!(
+            manager_client.get_loan(&usdc_loan.loan_id).borrowed_amount,
+            103
+        );
+        assert_eq!(usdc_token_client.balance(&keeper), 10);
+        assert_eq!(
+            manager_client.get_keeper_reward(),
+            Some(KeeperReward {
+                token_address: usdc_token_client.address.clone(),
+                amount_per_loan: 5,
+                min_interval: 30_000_000,
+            })
+        );
+
+        // Batches are capped.
+        let mut loan_ids = vec![&e];
+        for _ in 0..=MAX_ACCRUE_LOANS {
+            loan_ids.push_back(usdc_loan.loan_id.clone());
+        }
+        assert_eq!(
+            manager_client.try_accrue_loans(&keeper, &loan_ids),
+            Err(Ok(LoanManagerError::InvalidAmount))
+        );
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn accrue_loans_with_failing_pool() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000;
+            li.timestamp = 1;
+            li.min_persistent_entry_ttl = 1_000_000;
+            li.min_temp_entry_ttl = 1_000_000;
+            li.max_entry_ttl = 1_000_001;
+        });
+
+        let TestEnv {
+            user,
+            manager_addr,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            pool_eurc_addr,
+            usdc_asset_client,
+            usdc_token_client,
+            reflector_addr,
+            ..
+        } = setup_test_env(&e);
+        let keeper = Address::generate(&e);
+
+        let usdc_loan =
+            manager_client.create_loan(&user, &100, &pool_usdc_addr, &300, &pool_xlm_addr);
+        let eurc_loan =
+            manager_client.create_loan(&user, &100, &pool_eurc_addr, &300, &pool_xlm_addr);
+
+        // A frozen pool still accrues, a pool whose accrual index is lost can not.
+        freeze_pool(&e, &pool_usdc_addr);
+        e.as_contract(&pool_eurc_addr, || {
+            e.storage()
+                .persistent()
+                .remove(&vec![&e, Symbol::new(&e, "Accrual")])
+        });
+
+        // The manager holds less than the reward it owes.
+        usdc_asset_client.mint(&manager_addr, &3);
+        manager_client.set_keeper_reward(&usdc_token_client.address, &5, &30_000_000);
+
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 100_000;
+            li.timestamp = 1 + 31_556_926;
+        });
+        e.register_at(&reflector_addr, oracle::WASM, ());
+
+        // ACT
+        let outcomes = manager_client.accrue_loans(
+            &keeper,
+            &vec![&e, eurc_loan.loan_id.clone(), usdc_loan.loan_id.clone()],
+        );
+
+        // ASSERT
+        assert_eq!(
+            outcomes,
+            vec![&e, AccrualOutcome::Failed, AccrualOutcome::Rewarded]
+        );
+        assert_eq!(
+            manager_client.get_loan(&usdc_loan.loan_id).borrowed_amount,
+            102
+        );
+        assert_eq!(
+            manager_client.get_loan(&eurc_loan.loan_id).borrowed_amount,
+            100
+        );
+        assert_eq!(usdc_token_client.balance(&keeper), 3);
+        assert_eq!(usdc_token_client.balance(&manager_addr), 0); // The EURC pool's storage is broken on purpose, so the invariants are not checked.
+    }
+
+    #[test]
+    fn liquidate() {
+        // ARRANGE
+        let e = Env::default();
+
+        e.mock_all_auths_allowing_non_root_auth();
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000;
+            li.timestamp = 1;
+            li.min_persistent_entry_ttl = 1_000_000;
+            li.min_temp_entry_ttl = 1_000_000;
+            li.max_entry_ttl = 1_000_001;
+        });
+
+        let TestEnv {
+            admin,
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            pool_eurc_addr,
+            pool_usdc_client,
+            pool_eurc_client,
+            eurc_asset_client,
+            xlm_asset_client,
+            usdc_asset_client,
+            reflector_addr,
+            ..
+        } = setup_test_env(&e);
+
+        // print more money
+        usdc_asset_client.mint(&admin, &9_001);
+        eurc_asset_client.mint(&admin, &9_001);
+        xlm_asset_client.mint(&user, &30_000);
+        pool_usdc_client.deposit(&admin, &9_001);
+        pool_eurc_client.deposit(&admin, &9_001);
+
+        // ACT
+        // Create two loans, one to liquidate.
+        let mut usdc_loan =
+            manager_client.create_loan(&user, &10_000, &pool_usdc_addr, &12_505, &pool_xlm_addr);
+        let mut eurc_loan =
+            manager_client.create_loan(&user, &10_000, &pool_eurc_addr, &12_505, &pool_xlm_addr);
+
+        manager_client.add_interest(&usdc_loan.loan_id);
+        manager_client.add_interest(&eurc_loan.loan_id);
+
+        // Here borrowed amount should be the same as time has not moved. add_interest() is only called to store the LastUpdate sequence number.
+        assert_eq!(usdc_loan.borrowed_amount, 10_000);
+        assert_eq!(usdc_loan.health_factor, 10_004_000);
+
+        // Move time
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 10_000;
+            li.timestamp = 1 + 8_000_000;
+        });
+
+        // A new instance of reflector mock needs to be created, they only live for one ledger.
+        e.register_at(&reflector_addr, oracle::WASM, ());
+
+        manager_client.add_interest(&usdc_loan.loan_id);
+        manager_client.add_interest(&eurc_loan.loan_id);
+
+        usdc_loan = manager_client.get_loan(&usdc_loan.loan_id);
+
+        assert_eq!(usdc_loan.borrowed_amount, 10_789);
+        assert_eq!(usdc_loan.health_factor, 9_272_407);
+        assert_eq!(usdc_loan.collateral_amount, 12_505);
+
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 1_000;
+        });
+
+        e.register_at(&reflector_addr, oracle::WASM, ());
+
+        manager_client.liquidate(&admin, &usdc_loan.loan_id, &5_000);
+
+        let liquidated_event = storage::EventLoanLiquidated {
+            loan_id: usdc_loan.loan_id.clone(),
+            liquidator: admin.clone(),
+            debt_repaid: 5_000,
+            collateral_seized: 5_500,
+            bonus_paid: 500,
+            protocol_bonus: 0,
+            protocol_fee: 78,
+            health_factor_before: 9_272_407,
+            health_factor_after: 9_680_428,
+        };
+        assert!(e.events().all().contains((
+            manager_client.address.clone(),
+            liquidated_event.topics(&e),
+            liquidated_event.data(&e),
+        )));
+
+        usdc_loan = manager_client.get_loan(&usdc_loan.loan_id);
+        assert_eq!(usdc_loan.borrowed_amount, 5_789);
+        assert_eq!(usdc_loan.health_factor, 9_680_428);
+        assert_eq!(usdc_loan.collateral_amount, 7_005);
+
+        eurc_loan = manager_client.get_loan(&eurc_loan.loan_id);
+        assert_eq!(eurc_loan.borrowed_amount, 10_789);
+        assert_eq!(eurc_loan.health_factor, 9_272_407);
+        assert_eq!(eurc_loan.collateral_amount, 12_505);
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn liquidate_twice_books_interest_once() {
+        // ARRANGE
+        let e = Env::default();
+
+        e.mock_all_auths_allowing_non_root_auth();
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000;
+            li.timestamp = 1;
+            li.min_persistent_entry_ttl = 1_000_000;
+            li.min_temp_entry_ttl = 1_000_000;
+            li.max_entry_ttl = 1_000_001;
+        });
+
+        let TestEnv {
+            admin,
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            pool_usdc_client,
+            usdc_asset_client,
+            usdc_token_client,
+            xlm_asset_client,
+            reflector_addr,
+            ..
+        } = setup_test_env(&e);
+
+        usdc_asset_client.mint(&admin, &9_001);
+        xlm_asset_client.mint(&user, &30_000);
+        pool_usdc_client.deposit(&admin, &9_001);
+
+        let loan =
+            manager_client.create_loan(&user, &10_000, &pool_usdc_addr, &12_505, &pool_xlm_addr);
+
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 10_000;
+            li.timestamp = 1 + 8_000_000;
+        });
+        e.register_at(&reflector_addr, oracle::WASM, ());
+        manager_client.add_interest(&loan.loan_id);
+        assert_eq!(manager_client.get_loan(&loan.loan_id).unpaid_interest, 789);
+
+        // ACT
+        // The first liquidation pays 500 of the interest, the second the remaining 289.
+        manager_client.liquidate(&admin, &loan.loan_id, &500);
+        assert_eq!(manager_client.get_loan(&loan.loan_id).unpaid_interest, 289);
+        manager_client.liquidate(&admin, &loan.loan_id, &500);
+
+        // ASSERT
+        let loan = manager_client.get_loan(&loan.loan_id);
+        assert_eq!(loan.unpaid_interest, 0);
+
+        // Everything the pool books is backed by tokens it holds or lent out.
+        let state = pool_usdc_client.get_pool_state();
+        assert_eq!(
+            state.total_balance_tokens,
+            usdc_token_client.balance(&pool_usdc_addr) + loan.borrowed_amount
+        );
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn preview_liquidate() {
+        // ARRANGE
+        let e = Env::default();
+
+        e.mock_all_auths_allowing_non_root_auth();
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000;
+            li.timestamp = 1;
+            li.min_persistent_entry_ttl = 1_000_000;
+            li.min_temp_entry_ttl = 1_000_000;
+            li.max_entry_ttl = 1_000_001;
+        });
+
+        let TestEnv {
+            admin,
+            user,
+            manager_client,
+            manager_addr,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            pool_usdc_client,
+            usdc_asset_client,
+            usdc_token_client,
+            xlm_asset_client,
+            reflector_addr,
+            ..
+        } = setup_test_env(&e);
+
+        usdc_asset_client.mint(&admin, &9_001);
+        xlm_asset_client.mint(&user, &30_000);
+        pool_usdc_client.deposit(&admin, &9_001);
+
+        let loan =
+            manager_client.create_loan(&user, &10_000, &pool_usdc_addr, &12_505, &pool_xlm_addr);
+        assert_eq!(
+            manager_client.try_preview_liquidate(&loan.loan_id, &5_000),
+            Err(Ok(LoanManagerError::LoanNotLiquidatable))
+        );
+
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 10_000;
+            li.timestamp = 1 + 8_000_000;
+        });
+        e.register_at(&reflector_addr, oracle::WASM, ());
+
+        // ACT
+        let preview = manager_client.preview_liquidate(&loan.loan_id, &5_000);
+        assert_eq!(manager_client.get_loan(&loan.loan_id), loan);
+
+        let revenue_before = usdc_token_client.balance(&manager_addr);
+        let liquidated = manager_client.liquidate(&admin, &loan.loan_id, &5_000);
+
+        // ASSERT
+        assert_eq!(preview.amount_in, 5_000);
+        assert_eq!(preview.collateral_out, 5_500);
+        assert_eq!(preview.liquidation_bonus, 500);
+        assert_eq!(preview.protocol_fee, 78);
+        assert_eq!(preview.borrowed_amount, liquidated.borrowed_amount);
+        assert_eq!(preview.collateral_amount, liquidated.collateral_amount);
+        assert_eq!(preview.health_factor, Some(liquidated.health_factor));
+        assert_eq!(
+            usdc_token_client.balance(&manager_addr) - revenue_before,
+            preview.protocol_fee
+        );
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn bump_keeps_loan_alive() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000;
+            li.min_persistent_entry_ttl = 100;
+            li.min_temp_entry_ttl = 100;
+            li.max_entry_ttl = 1_000_000;
+        });
+
+        let TestEnv {
+            admin,
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_xlm_client,
+            pool_usdc_addr,
+            pool_usdc_client,
+            usdc_asset_client,
+            xlm_asset_client,
+            ..
+        } = setup_test_env(&e);
+
+        usdc_asset_client.mint(&admin, &1_000);
+        xlm_asset_client.mint(&user, &1_000);
+        pool_usdc_client.deposit(&admin, &1_000);
+        let loan = manager_client.create_loan(&user, &100, &pool_usdc_addr, &1_000, &pool_xlm_addr);
+
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 20 * storage::DAY_IN_LEDGERS;
+        });
+
+        // ACT
+        let bumped = manager_client.bump(&vec![
+            &e,
+            loan.loan_id.clone(),
+            LoanId {
+                borrower_address: user.clone(),
+                nonce: 99,
+            },
+        ]);
+
+        // ASSERT
+        assert_eq!(bumped, 1);
+        let ttl = e.as_contract(&manager_client.address, || {
+            e.storage()
+                .persistent()
+                .get_ttl(&storage::LoanManagerDataKey::Loan(loan.loan_id.clone()))
+        });
+        assert_eq!(ttl, storage::POSITIONS_BUMP_AMOUNT);
+
+        // Without the bump the loan and the positions would have been archived by now.
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 40 * storage::DAY_IN_LEDGERS;
+        });
+        assert_eq!(manager_client.get_loans(&user), vec![&e, loan]);
+        assert_eq!(pool_usdc_client.get_user_positions(&user).liabilities, 100);
+        assert_eq!(pool_xlm_client.get_user_positions(&user).collateral, 1_000);
+
+        let too_many = Vec::from_iter(
+            &e,
+            (0..=MAX_BUMP_LOANS as u64).map(|nonce| LoanId {
+                borrower_address: user.clone(),
+                nonce,
+            }),
+        );
+        assert_eq!(
+            manager_client.try_bump(&too_many),
+            Err(Ok(LoanManagerError::InvalidAmount))
+        );
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn bump_keeps_config_alive() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000;
+            li.min_persistent_entry_ttl = 100;
+            li.min_temp_entry_ttl = 100;
+            li.max_entry_ttl = 1_000_000;
+        });
+
+        let test_env = setup_test_env(&e);
+        let TestEnv {
+            admin,
+            user,
+            manager_client,
+            pool_usdc_addr,
+            usdc_token_client,
+            ..
+        } = &test_env;
+        let operator = Address::generate(&e);
+
+        setup_dex(&e, &test_env, 0);
+        manager_client.set_keeper_reward(&usdc_token_client.address, &5, &1_000);
+        manager_client.set_liquidation_auction(&LiquidationAuction {
+            min_bonus: 200_000,
+            max_bonus: 1_000_000,
+            duration: 1_000,
+        });
+        manager_client.set_treasury(admin);
+        manager_client.set_loan_limits(
+            pool_usdc_addr,
+            &LoanLimits {
+                min_borrow: 100,
+                min_collateral: 0,
+            },
+        );
+        manager_client.set_fixed_rate_config(
+            pool_usdc_addr,
+            &FixedRateConfig {
+                premium: 100_000,
+                penalty_rate: 500_000,
+            },
+        );
+        manager_client.set_asset_category(
+            &1,
+            &AssetCategory {
+                collateral_factor: 9_500_000,
+                liquidation_bonus: 200_000,
+            },
+        );
+        manager_client.set_pool_category(pool_usdc_addr, &Some(1));
+        manager_client.approve_operator(user, &operator, &OperatorScope::Repay);
+
+        let keys = [
+            storage::LoanManagerDataKey::SwapRouter,
+            storage::LoanManagerDataKey::KeeperReward,
+            storage::LoanManagerDataKey::LiquidationAuction,
+            storage::LoanManagerDataKey::Treasury,
+            storage::LoanManagerDataKey::LoanLimits(pool_usdc_addr.clone()),
+            storage::LoanManagerDataKey::FixedRateConfig(pool_usdc_addr.clone()),
+            storage::LoanManagerDataKey::AssetCategory(1),
+            storage::LoanManagerDataKey::PoolCategory(pool_usdc_addr.clone()),
+        ];
+        let operator_key = storage::LoanManagerDataKey::Operator(
+            user.clone(),
+            operator.clone(),
+            OperatorScope::Repay,
+        );
+        let ttl = |key: &storage::LoanManagerDataKey| {
+            e.as_contract(&manager_client.address, || {
+                e.storage().persistent().get_ttl(key)
+            })
+        };
+        // Every write extends the entry's TTL.
+        for key in keys.iter().chain([&operator_key]) {
+            assert_eq!(ttl(key), storage::POSITIONS_BUMP_AMOUNT);
+        }
+
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 20 * storage::DAY_IN_LEDGERS;
+        });
+
+        // ACT
+        manager_client.bump(&vec![&e]);
+        let is_operator = e.as_contract(&manager_client.address, || {
+            storage::is_operator(&e, user, &operator, OperatorScope::Repay)
+        });
+
+        // ASSERT
+        for key in keys.iter() {
+            assert_eq!(ttl(key), storage::POSITIONS_BUMP_AMOUNT);
+        }
+        // Checking an operator extends its approval.
+        assert!(is_operator);
+        assert_eq!(ttl(&operator_key), storage::POSITIONS_BUMP_AMOUNT);
+
+        assert_pool_invariants(&e, manager_client);
+    }
+
+    #[test]
+    fn liquidate_dust_loan_in_full() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000;
+            li.timestamp = 1;
+            li.min_persistent_entry_ttl = 1_000_000;
+            li.min_temp_entry_ttl = 1_000_000;
+            li.max_entry_ttl = 1_000_001;
+        });
+
+        let TestEnv {
+            admin,
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_xlm_client,
+            pool_usdc_addr,
+            pool_usdc_client,
+            usdc_asset_client,
+            xlm_asset_client,
+            xlm_token_client,
+            reflector_addr,
+            ..
+        } = setup_test_env(&e);
+
+        usdc_asset_client.mint(&admin, &20_000);
+        xlm_asset_client.mint(&user, &30_000);
+        pool_usdc_client.deposit(&admin, &9_001);
+        let loan =
+            manager_client.create_loan(&user, &10_000, &pool_usdc_addr, &12_505, &pool_xlm_addr);
+        let xlm_balance = xlm_token_client.balance(&user);
+        manager_client.set_loan_limits(
+            &pool_usdc_addr,
+            &LoanLimits {
+                min_borrow: 6_000,
+                min_collateral: 0,
+            },
+        );
+
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 10_000;
+            li.timestamp = 1 + 8_000_000;
+        });
+        e.register_at(&reflector_addr, oracle::WASM, ());
+
+        // ACT
+        // Even the largest partial liquidation would leave less than the minimum debt behind.
+        assert_eq!(
+            manager_client.try_liquidate(&admin, &loan.loan_id, &5_000),
+            Err(Ok(LoanManagerError::InvalidLiquidationAmount))
+        );
+        let liquidated = manager_client.liquidate(&admin, &loan.loan_id, &10_789);
+
+        // ASSERT
+        assert_eq!(liquidated.borrowed_amount, 0);
+        assert_eq!(liquidated.collateral_amount, 638);
+        assert_eq!(
+            manager_client.try_get_loan(&loan.loan_id),
+            Err(Ok(LoanManagerError::LoanNotFound))
+        );
+        assert_eq!(xlm_token_client.balance(&admin), 11_867);
+        assert_eq!(xlm_token_client.balance(&user), xlm_balance + 638);
+        assert_eq!(pool_xlm_client.get_user_positions(&user).collateral, 0);
+        assert_eq!(pool_usdc_client.get_user_positions(&user).liabilities, 0);
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn preview_create_loan_checks_loan_limits() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+
+        let TestEnv {
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            ..
+        } = setup_test_env(&e);
+        manager_client.set_loan_limits(
+            &pool_usdc_addr,
+            &LoanLimits {
+                min_borrow: 100,
+                min_collateral: 0,
+            },
+        );
+
+        // ACT & ASSERT
+        // The preview rejects the loan like `create_loan` does.
+        assert_eq!(
+            manager_client.try_preview_create_loan(&99, &pool_usdc_addr, &1_000, &pool_xlm_addr),
+            Err(Ok(LoanManagerError::LoanBelowMinimum))
+        );
+        assert_eq!(
+            manager_client.try_create_loan(&user, &99, &pool_usdc_addr, &1_000, &pool_xlm_addr),
+            Err(Ok(LoanManagerError::LoanBelowMinimum))
+        );
+
+        let preview =
+            manager_client.preview_create_loan(&100, &pool_usdc_addr, &1_000, &pool_xlm_addr);
+        let loan = manager_client.create_loan(&user, &100, &pool_usdc_addr, &1_000, &pool_xlm_addr);
+        assert_eq!(preview.borrowed_amount, loan.borrowed_amount);
+        assert_eq!(preview.health_factor, Some(loan.health_factor));
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn loan_limits() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+
+        let TestEnv {
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            ..
+        } = setup_test_env(&e);
+        let usdc_limits = LoanLimits {
+            min_borrow: 100,
+            min_collateral: 0,
+        };
+        manager_client.set_loan_limits(&pool_usdc_addr, &usdc_limits);
+        manager_client.set_loan_limits(
+            &pool_xlm_addr,
+            &LoanLimits {
+                min_borrow: 0,
+                min_collateral: 500,
+            },
+        );
+
+        // ACT & ASSERT
+        assert_eq!(manager_client.get_loan_limits(&pool_usdc_addr), usdc_limits);
+        assert_eq!(
+            manager_client.try_create_loan(&user, &99, &pool_usdc_addr, &1_000, &pool_xlm_addr),
+            Err(Ok(LoanManagerError::LoanBelowMinimum))
+        );
+        assert_eq!(
+            manager_client.try_create_loan(&user, &100, &pool_usdc_addr, &499, &pool_xlm_addr),
+            Err(Ok(LoanManagerError::LoanBelowMinimum))
+        );
+
+        let loan = manager_client.create_loan(&user, &150, &pool_usdc_addr, &1_000, &pool_xlm_addr);
+        assert_eq!(
+            manager_client.try_repay(&user, &loan.loan_id, &51),
+            Err(Ok(LoanManagerError::DustRemaining))
+        );
+        assert_eq!(manager_client.repay(&user, &loan.loan_id, &50), (150, 100));
+
+        assert_eq!(
+            manager_client.try_set_loan_limits(
+                &pool_usdc_addr,
+                &LoanLimits {
+                    min_borrow: -1,
+                    min_collateral: 0,
+                }
+            ),
+            Err(Ok(LoanManagerError::InvalidLoanLimits))
+        );
+        assert_eq!(
+            manager_client.try_set_loan_limits(&Address::generate(&e), &usdc_limits),
+            Err(Ok(LoanManagerError::AddressNotFound))
+        );
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn liquidate_with_protocol_fee_share() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000;
+            li.timestamp = 1;
+            li.min_persistent_entry_ttl = 1_000_000;
+            li.min_temp_entry_ttl = 1_000_000;
+            li.max_entry_ttl = 1_000_001;
+        });
+
+        let TestEnv {
+            admin,
+            user,
+            manager_client,
+            manager_addr,
+            pool_xlm_addr,
+            pool_xlm_client,
+            pool_usdc_addr,
+            pool_usdc_client,
+            usdc_asset_client,
+            xlm_asset_client,
+            xlm_token_client,
+            reflector_addr,
+            ..
+        } = setup_test_env(&e);
+
+        usdc_asset_client.mint(&admin, &9_001);
+        xlm_asset_client.mint(&user, &30_000);
+        pool_usdc_client.deposit(&admin, &9_001);
+        let loan =
+            manager_client.create_loan(&user, &10_000, &pool_usdc_addr, &12_505, &pool_xlm_addr);
+
+        manager_client.set_liquidation_fee_share(&pool_xlm_addr, &2_000_000); // 20%
+        assert_eq!(pool_xlm_client.get_liquidation_fee_share(), 2_000_000);
+        let treasury = Address::generate(&e);
+        manager_client.set_treasury(&treasury);
+        assert_eq!(manager_client.get_treasury(), Some(treasury.clone()));
+
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 10_000;
+            li.timestamp = 1 + 8_000_000;
+        });
+        e.register_at(&reflector_addr, oracle::WASM, ());
+
+        // ACT
+        let preview = manager_client.preview_liquidate(&loan.loan_id, &5_000);
+        let liquidated = manager_client.liquidate(&admin, &loan.loan_id, &5_000);
+
+        // ASSERT
+        let split_event = loan_pool::EventLiquidationBonusSplit {
+            loan_owner: user.clone(),
+            liquidator: admin.clone(),
+            liquidator_bonus: 400,
+            protocol_bonus: 100,
+        };
+        assert!(e.events().all().contains((
+            pool_xlm_addr.clone(),
+            split_event.topics(&e),
+            split_event.data(&e),
+        )));
+        assert_eq!(preview.collateral_out, 5_500);
+        assert_eq!(preview.liquidation_bonus, 500);
+        assert_eq!(preview.protocol_bonus, 100);
+        assert_eq!(liquidated.collateral_amount, 7_005);
+        assert_eq!(xlm_token_client.balance(&admin), 5_400);
+        assert_eq!(xlm_token_client.balance(&treasury), 100);
+        assert_eq!(xlm_token_client.balance(&manager_addr), 0);
+        assert_eq!(pool_xlm_client.get_user_positions(&user).collateral, 7_005);
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn cannot_set_invalid_liquidation_fee_share() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths();
+
+        let TestEnv {
+            manager_client,
+            pool_xlm_addr,
+            ..
+        } = setup_test_env(&e);
+
+        // ACT & ASSERT
+        assert_eq!(
+            manager_client.try_set_liquidation_fee_share(&pool_xlm_addr, &10_000_001),
+            Err(Ok(LoanManagerError::InvalidLiquidationFeeShare))
+        );
+        assert_eq!(
+            manager_client.try_set_liquidation_fee_share(&pool_xlm_addr, &-1),
+            Err(Ok(LoanManagerError::InvalidLiquidationFeeShare))
+        );
+        assert_eq!(
+            manager_client.try_set_liquidation_fee_share(&Address::generate(&e), &1_000_000),
+            Err(Ok(LoanManagerError::AddressNotFound))
+        );
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn set_interest_rate_params() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+
+        let TestEnv {
+            manager_client,
+            pool_usdc_addr,
+            pool_usdc_client,
+            pool_xlm_client,
+            ..
+        } = setup_test_env(&e);
+        let params = InterestRateParams {
+            base_rate: 0,
+            rate_at_panic: 400_000,
+            max_rate: 6_000_000,
+            panic_threshold: 8_000_000,
+        };
+
+        // ACT
+        manager_client.set_interest_rate_params(&pool_usdc_addr, &params);
+
+        // ASSERT
+        let pool_params = pool_usdc_client.get_interest_rate_params();
+        assert_eq!(pool_params.rate_at_panic, 400_000);
+        assert_eq!(pool_params.max_rate, 6_000_000);
+        assert_eq!(pool_params.panic_threshold, 8_000_000);
+        // Other pools keep their own curve.
+        assert_eq!(
+            pool_xlm_client.get_interest_rate_params().base_rate,
+            200_000
+        );
+
+        assert_eq!(
+            manager_client.try_set_interest_rate_params(
+                &pool_usdc_addr,
+                &InterestRateParams {
+                    max_rate: 300_000,
+                    ..params.clone()
+                }
+            ),
+            Err(Ok(LoanManagerError::InvalidInterestRateParams))
+        );
+        assert_eq!(
+            manager_client.try_set_interest_rate_params(
+                &pool_usdc_addr,
+                &InterestRateParams {
+                    panic_threshold: 10_000_000,
+                    ..params.clone()
+                }
+            ),
+            Err(Ok(LoanManagerError::InvalidInterestRateParams))
+        );
+        assert_eq!(
+            manager_client.try_set_interest_rate_params(&Address::generate(&e), &params),
+            Err(Ok(LoanManagerError::AddressNotFound))
+        );
+
+        assert_pool_invariants(&e, &manager_client);
+
+        // Other failures keep the pool's own error code.
+        e.as_contract(&pool_usdc_addr, || {
+            e.storage()
+                .persistent()
+                .remove(&vec![&e, Symbol::new(&e, "Accrual")])
+        });
+        let result = manager_client.try_set_interest_rate_params(&pool_usdc_addr, &params);
+        assert_eq!(
+            result.unwrap_err().map(|error| error as u32),
+            Ok(loan_pool::LoanPoolError::Accrual as u32)
+        );
+    }
+
+    #[test]
+    fn set_adaptive_rate_params() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+
+        let TestEnv {
+            manager_client,
+            pool_usdc_addr,
+            pool_usdc_client,
+            ..
+        } = setup_test_env(&e);
+        let params = AdaptiveRateParams {
+            target_utilization: 9_000_000,
+            initial_rate_at_target: 500_000,
+            min_rate_at_target: 100_000,
+            max_rate_at_target: 2_000_000,
+            adjustment_speed: 500_000_000,
+        };
+
+        // ACT
+        manager_client.set_adaptive_rate_params(&pool_usdc_addr, &params);
+
+        // ASSERT
+        assert_eq!(
+            pool_usdc_client.get_interest_rate_model(),
+            loan_pool::InterestRateModel::Adaptive
+        );
+        assert_eq!(pool_usdc_client.get_rate_at_target(), Some(500_000));
+        assert_eq!(
+            pool_usdc_client
+                .get_adaptive_rate_params()
+                .unwrap()
+                .target_utilization,
+            9_000_000
+        );
+
+        assert_eq!(
+            manager_client.try_set_adaptive_rate_params(
+                &pool_usdc_addr,
+                &AdaptiveRateParams {
+                    max_rate_at_target: 20_000_000,
+                    ..params.clone()
+                }
+            ),
+            Err(Ok(LoanManagerError::InvalidInterestRateParams))
+        );
+        assert_eq!(
+            manager_client.try_set_adaptive_rate_params(&Address::generate(&e), &params),
+            Err(Ok(LoanManagerError::AddressNotFound))
+        );
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn preview_loan_operations() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000;
+            li.timestamp = 1;
+            li.min_persistent_entry_ttl = 10_000_000;
+            li.min_temp_entry_ttl = 1_000_000;
+            li.max_entry_ttl = 1_000_001;
+        });
+
+        let TestEnv {
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            xlm_asset_client,
+            usdc_asset_client,
+            reflector_addr,
+            ..
+        } = setup_test_env(&e);
+        xlm_asset_client.mint(&user, &1_000);
+        usdc_asset_client.mint(&user, &1_000);
+
+        // ACT & ASSERT
+        let preview =
+            manager_client.preview_create_loan(&100, &pool_usdc_addr, &1000, &pool_xlm_addr);
+        let loan = manager_client.create_loan(&user, &100, &pool_usdc_addr, &1000, &pool_xlm_addr);
+        assert_eq!(preview.amount_out, 100);
+        assert_eq!(preview.collateral_in, 1000);
+        assert_eq!(preview.health_factor, Some(loan.health_factor));
+        assert_eq!(
+            manager_client.try_preview_create_loan(&1_000, &pool_usdc_addr, &1000, &pool_xlm_addr),
+            Err(Ok(LoanManagerError::HealthFactorTooLow))
+        );
+
+        // Accrue interest that the previews have to account for.
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 100_000;
+            li.timestamp = 1 + 31_556_926;
+        });
+        e.register_at(&reflector_addr, oracle::WASM, ());
+
+        let preview = manager_client.preview_add_collateral(&loan.loan_id, &500);
+        let updated = manager_client.add_collateral(&user, &loan.loan_id, &500);
+        assert_eq!(preview.collateral_in, 500);
+        assert_eq!(preview.borrowed_amount, 102);
+        assert_eq!(preview.collateral_amount, updated.collateral_amount);
+        assert_eq!(preview.health_factor, Some(updated.health_factor));
+
+        let preview = manager_client.preview_withdraw_collateral(&loan.loan_id, &700);
+        let updated = manager_client.withdraw_collateral(&user, &loan.loan_id, &700);
+        assert_eq!(preview.collateral_out, 700);
+        assert_eq!(preview.collateral_amount, updated.collateral_amount);
+        assert_eq!(preview.health_factor, Some(updated.health_factor));
+        assert_eq!(
+            manager_client.try_preview_withdraw_collateral(&loan.loan_id, &750),
+            Err(Ok(LoanManagerError::HealthFactorTooLow))
+        );
+
+        let preview = manager_client.preview_repay(&loan.loan_id, &50);
+        manager_client.repay(&user, &loan.loan_id, &50);
+        let updated = manager_client.get_loan(&loan.loan_id);
+        assert_eq!(preview.amount_in, 50);
+        assert_eq!(preview.protocol_fee, 0);
+        assert_eq!(preview.borrowed_amount, updated.borrowed_amount);
+        assert_eq!(preview.unpaid_interest, updated.unpaid_interest);
+        assert_eq!(preview.health_factor, Some(updated.health_factor));
+
+        let preview = manager_client.preview_repay_and_close(&loan.loan_id);
+        assert_eq!(preview.amount_in, 52);
+        assert_eq!(preview.collateral_out, 800);
+        assert_eq!(preview.health_factor, None);
+        manager_client.repay_and_close_manager(&user, &preview.amount_in, &loan.loan_id);
+        assert_eq!(
+            manager_client.try_get_loan(&loan.loan_id),
+            Err(Ok(LoanManagerError::LoanNotFound))
+        );
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn efficiency_mode() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+
+        let TestEnv {
+            admin,
+            user,
+            manager_client,
+            pool_usdc_addr,
+            pool_eurc_addr,
+            usdc_asset_client,
+            reflector_addr,
+            ..
+        } = setup_test_env(&e);
+        usdc_asset_client.mint(&user, &1_000);
+
+        let usdc = Symbol::new(&e, "USDC");
+        let eurc = Symbol::new(&e, "EURC");
+        let stablecoins = AssetCategory {
+            collateral_factor: 9_500_000, // 95%
+            liquidation_bonus: 200_000,   // 2%
+        };
+        manager_client.set_asset_category(&1, &stablecoins);
+        manager_client.set_pool_category(&pool_usdc_addr, &Some(1));
+
+        // Only one side of the loan is in the category.
+        assert_eq!(
+            manager_client.calculate_loan_health_factor(
+                &eurc,
+                &900,
+                &pool_eurc_addr,
+                &usdc,
+                &1_000,
+                &pool_usdc_addr
+            ),
+            8_888_888
+        );
+
+        // ACT
+        manager_client.set_pool_category(&pool_eurc_addr, &Some(1));
+        let loan =
+            manager_client.create_loan(&user, &900, &pool_eurc_addr, &1_000, &pool_usdc_addr);
+
+        // ASSERT
+        assert_eq!(loan.health_factor, 10_555_555);
+        assert_eq!(
+            manager_client.calculate_loan_health_factor(
+                &eurc,
+                &900,
+                &pool_eurc_addr,
+                &usdc,
+                &1_000,
+                &pool_usdc_addr
+            ),
+            10_555_555
+        );
+        // Without the borrow pool the category can not apply.
+        assert_eq!(
+            manager_client.calculate_health_factor(&eurc, &900, &usdc, &1_000, &pool_usdc_addr),
+            8_888_888
+        );
+        assert_eq!(manager_client.get_liquidation_bonus(&loan.loan_id), 200_000);
+
+        // USDC loses some of its value against EURC.
+        let reflector_client = oracle::Client::new(&e, &reflector_addr);
+        for (ticker, price) in [(usdc, 9_280_000), (eurc, 10_000_000)] {
+            reflector_client.update_price(
+                &Asset::Other(ticker),
+                &oracle::PriceData {
+                    price,
+                    timestamp: 1,
+                },
+            );
+        }
+
+        let before = manager_client.add_interest(&loan.loan_id);
+        assert_eq!(before.health_factor, 9_795_555);
+        let after = manager_client.liquidate(&admin, &loan.loan_id, &300);
+        assert_eq!(after.borrowed_amount, 600);
+        assert_eq!(after.collateral_amount, 671);
+        assert_eq!(after.health_factor, 9_859_226);
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn cannot_set_invalid_asset_category() {
+        // ARRANGE
         let e = Env::default();
-        let admin = Address::generate(&e);
-        let oracle = Address::generate(&e);
-        let manager_addr = e.register(LoanManager, ());
-        let manager_client = LoanManagerClient::new(&e, &manager_addr);
+        e.mock_all_auths();
+        let TestEnv {
+            manager_client,
+            pool_usdc_addr,
+            ..
+        } = setup_test_env(&e);
 
-        assert!(manager_client.try_initialize(&admin, &oracle).is_ok());
+        // ACT & ASSERT
+        // A liquidation with this bonus would lower the health factor.
+        assert_eq!(
+            manager_client.try_set_asset_category(
+                &1,
+                &AssetCategory {
+                    collateral_factor: 9_500_000,
+                    liquidation_bonus: 600_000,
+                }
+            ),
+            Err(Ok(LoanManagerError::InvalidAssetCategory))
+        );
+        assert_eq!(
+            manager_client.try_set_pool_category(&pool_usdc_addr, &Some(2)),
+            Err(Ok(LoanManagerError::InvalidAssetCategory))
+        );
+        assert_eq!(
+            manager_client.try_set_pool_category(&Address::generate(&e), &None),
+            Err(Ok(LoanManagerError::AddressNotFound))
+        );
+
+        assert_pool_invariants(&e, &manager_client);
     }
 
     #[test]
-    fn cannot_re_initialize() {
+    fn liquidate_with_auction_bonus() {
+        // ARRANGE
         let e = Env::default();
-        let admin = Address::generate(&e);
-        let oracle = Address::generate(&e);
 
-        let contract_id = e.register(LoanManager, ());
-        let client = LoanManagerClient::new(&e, &contract_id);
+        e.mock_all_auths_allowing_non_root_auth();
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000;
+            li.timestamp = 1;
+            li.min_persistent_entry_ttl = 1_000_000;
+            li.min_temp_entry_ttl = 1_000_000;
+            li.max_entry_ttl = 1_000_001;
+        });
 
-        client.initialize(&admin, &oracle);
+        let TestEnv {
+            admin,
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            pool_usdc_client,
+            usdc_asset_client,
+            xlm_asset_client,
+            reflector_addr,
+            ..
+        } = setup_test_env(&e);
 
-        assert!(client.try_initialize(&admin, &oracle).is_err())
+        usdc_asset_client.mint(&admin, &9_001);
+        xlm_asset_client.mint(&user, &30_000);
+        pool_usdc_client.deposit(&admin, &9_001);
+
+        let auction = LiquidationAuction {
+            min_bonus: 200_000,   // 2%
+            max_bonus: 1_000_000, // 10%
+            duration: 1_000,
+        };
+        manager_client.set_liquidation_auction(&auction);
+        assert_eq!(manager_client.get_liquidation_auction(), Some(auction));
+
+        let loan =
+            manager_client.create_loan(&user, &10_000, &pool_usdc_addr, &12_505, &pool_xlm_addr);
+
+        // Move time so that the loan becomes liquidatable, and let a keeper notice it.
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 10_000;
+            li.timestamp = 1 + 8_000_000;
+        });
+        e.register_at(&reflector_addr, oracle::WASM, ());
+        manager_client.add_interest(&loan.loan_id);
+        assert_eq!(manager_client.get_liquidation_bonus(&loan.loan_id), 200_000);
+
+        // ACT
+        // Halfway through the auction window.
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 10_001;
+            li.timestamp = 1 + 8_000_000 + 500;
+        });
+        e.register_at(&reflector_addr, oracle::WASM, ());
+        assert_eq!(manager_client.get_liquidation_bonus(&loan.loan_id), 600_000);
+
+        let before = manager_client.add_interest(&loan.loan_id);
+        let after = manager_client.liquidate(&admin, &loan.loan_id, &5_300);
+
+        // ASSERT
+        assert_eq!(before.collateral_amount - after.collateral_amount, 5_618);
+
+        // The liquidation brought the loan back above the threshold, so a new auction
+        // would start again from the minimum bonus.
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 10_002;
+            li.timestamp = 1 + 8_000_000 + 5_000;
+        });
+        e.register_at(&reflector_addr, oracle::WASM, ());
+        assert_eq!(manager_client.get_liquidation_bonus(&loan.loan_id), 200_000);
+
+        assert_pool_invariants(&e, &manager_client);
     }
 
     #[test]
-    fn deploy_pool() {
+    fn transfer_loan_keeps_auction_clock() {
         // ARRANGE
         let e = Env::default();
-        e.mock_all_auths();
 
-        // ACT
-        // Deploy contract using loan_manager as factory
+        e.mock_all_auths_allowing_non_root_auth();
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000;
+            li.timestamp = 1;
+            li.min_persistent_entry_ttl = 1_000_000;
+            li.min_temp_entry_ttl = 1_000_000;
+            li.max_entry_ttl = 1_000_001;
+        });
+
         let TestEnv {
+            admin,
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
             pool_usdc_client,
-            pool_eurc_client,
+            usdc_asset_client,
+            xlm_asset_client,
+            reflector_addr,
             ..
         } = setup_test_env(&e);
+        let new_owner = Address::generate(&e);
+
+        usdc_asset_client.mint(&admin, &9_001);
+        xlm_asset_client.mint(&user, &30_000);
+        pool_usdc_client.deposit(&admin, &9_001);
+
+        manager_client.set_liquidation_auction(&LiquidationAuction {
+            min_bonus: 200_000,   // 2%
+            max_bonus: 1_000_000, // 10%
+            duration: 1_000,
+        });
+
+        let loan =
+            manager_client.create_loan(&user, &10_000, &pool_usdc_addr, &12_505, &pool_xlm_addr);
+
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 10_000;
+            li.timestamp = 1 + 8_000_000;
+        });
+        e.register_at(&reflector_addr, oracle::WASM, ());
+        manager_client.add_interest(&loan.loan_id);
+
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 10_001;
+            li.timestamp = 1 + 8_000_000 + 500;
+        });
+        e.register_at(&reflector_addr, oracle::WASM, ());
+        assert_eq!(manager_client.get_liquidation_bonus(&loan.loan_id), 600_000);
+
+        // ACT
+        let transferred = manager_client.transfer_loan(&loan.loan_id, &new_owner);
 
         // ASSERT
-        // No authorizations needed - the contract acts as a factory.
-        // assert_eq!(e.auths(), &[]);
+        // The auction goes on where it was instead of starting over at the minimum bonus.
+        assert_eq!(
+            manager_client.get_liquidation_bonus(&transferred.loan_id),
+            600_000
+        );
+        e.as_contract(&manager_client.address, || {
+            assert_eq!(
+                storage::read_liquidatable_since(&e, &transferred.loan_id),
+                Some(1 + 8_000_000)
+            );
+            assert_eq!(
+                storage::read_loan_accrued_at(&e, &transferred.loan_id),
+                Some(1 + 8_000_000 + 500)
+            );
+        });
 
-        // Invoke contract to check that it is initialized.
-        let usdc_balance = pool_usdc_client.get_contract_balance();
-        assert_eq!(usdc_balance, 1000);
-        let eurc_balance = pool_eurc_client.get_contract_balance();
-        assert_eq!(eurc_balance, 1000);
+        assert_pool_invariants(&e, &manager_client);
     }
 
     #[test]
-    fn upgrade_manager_and_pool() {
+    fn cannot_set_invalid_liquidation_auction() {
         // ARRANGE
         let e = Env::default();
         e.mock_all_auths();
-
         let TestEnv { manager_client, .. } = setup_test_env(&e);
-        let manager_wasm_hash = e.deployer().upload_contract_wasm(loan_pool::WASM);
-        let pool_wasm_hash = e.deployer().upload_contract_wasm(loan_pool::WASM);
 
-        // ACT
-        manager_client.upgrade(&manager_wasm_hash, &pool_wasm_hash);
+        // ACT & ASSERT
+        assert_eq!(
+            manager_client.try_set_liquidation_auction(&LiquidationAuction {
+                min_bonus: 500_000,
+                max_bonus: 200_000,
+                duration: 1_000,
+            }),
+            Err(Ok(LoanManagerError::InvalidLiquidationAuction))
+        );
+        assert_eq!(
+            manager_client.try_set_liquidation_auction(&LiquidationAuction {
+                min_bonus: 200_000,
+                max_bonus: 500_000,
+                duration: 0,
+            }),
+            Err(Ok(LoanManagerError::InvalidLiquidationAuction))
+        );
+
+        assert_pool_invariants(&e, &manager_client);
     }
 
     #[test]
-    fn cannot_create_loan_untrusted_loan_pool() {
+    fn liquidate_batch() {
         // ARRANGE
         let e = Env::default();
-        e.mock_all_auths();
+
+        e.mock_all_auths_allowing_non_root_auth();
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000;
+            li.timestamp = 1;
+            li.min_persistent_entry_ttl = 1_000_000;
+            li.min_temp_entry_ttl = 1_000_000;
+            li.max_entry_ttl = 1_000_001;
+        });
+
         let TestEnv {
             admin,
             user,
-            manager_addr,
             manager_client,
             pool_xlm_addr,
+            pool_usdc_addr,
+            pool_eurc_addr,
+            pool_usdc_client,
+            pool_eurc_client,
+            eurc_asset_client,
+            xlm_asset_client,
+            usdc_asset_client,
+            reflector_addr,
             ..
         } = setup_test_env(&e);
 
-        // Set up a pool that's not trusted by loan manager
-        let ticker = Symbol::new(&e, "XLM");
-        let token_address = e
-            .register_stellar_asset_contract_v2(admin.clone())
-            .address();
-        let pool_addr = e.register(loan_pool::WASM, ());
-        let pool_client = loan_pool::Client::new(&e, &pool_addr);
-        pool_client.initialize(
-            &manager_addr,
-            &Currency {
-                ticker,
-                token_address,
-            },
-            &8_000_000,
+        usdc_asset_client.mint(&admin, &9_001);
+        eurc_asset_client.mint(&admin, &9_001);
+        xlm_asset_client.mint(&user, &30_000);
+        pool_usdc_client.deposit(&admin, &9_001);
+        pool_eurc_client.deposit(&admin, &9_001);
+        // Extra liquidity for the healthy loan.
+        pool_usdc_client.deposit(&admin, &1_000);
+
+        let usdc_loan =
+            manager_client.create_loan(&user, &10_000, &pool_usdc_addr, &12_505, &pool_xlm_addr);
+        let eurc_loan =
+            manager_client.create_loan(&user, &10_000, &pool_eurc_addr, &12_505, &pool_xlm_addr);
+        let healthy_loan =
+            manager_client.create_loan(&user, &100, &pool_usdc_addr, &1_000, &pool_xlm_addr);
+        let missing_loan_id = LoanId {
+            borrower_address: user.clone(),
+            nonce: 99,
+        };
+
+        // Move time so that the first two loans become liquidatable
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 10_000;
+            li.timestamp = 1 + 8_000_000;
+        });
+        e.register_at(&reflector_addr, oracle::WASM, ());
+
+        // ACT
+        // Every entry makes several calls to the pools, so a batch this size goes over the
+        // default test budget.
+        e.cost_estimate().budget().reset_unlimited();
+        let outcomes = manager_client.liquidate_batch(
+            &admin,
+            &vec![
+                &e,
+                (eurc_loan.loan_id.clone(), 5_000),
+                (usdc_loan.loan_id.clone(), 6_000),
+                (healthy_loan.loan_id.clone(), 10),
+                (missing_loan_id, 5_000),
+            ],
+        );
+
+        // ASSERT
+        assert_eq!(
+            outcomes,
+            vec![
+                &e,
+                LiquidationOutcome::Liquidated,
+                LiquidationOutcome::AmountOutOfRange,
+                LiquidationOutcome::NotLiquidatable,
+                LiquidationOutcome::LoanNotFound,
+            ]
         );
 
+        let eurc_loan = manager_client.get_loan(&eurc_loan.loan_id);
+        assert_eq!(eurc_loan.borrowed_amount, 5_789);
+        assert_eq!(eurc_loan.collateral_amount, 7_005);
+
+        // The rejected loans only had their interest accrued.
+        let usdc_loan = manager_client.get_loan(&usdc_loan.loan_id);
+        assert!(usdc_loan.borrowed_amount > 10_000);
+        assert_eq!(usdc_loan.collateral_amount, 12_505);
+        let healthy_loan = manager_client.get_loan(&healthy_loan.loan_id);
+        assert_eq!(healthy_loan.collateral_amount, 1_000);
+
+        assert_pool_invariants(&e, &manager_client);
+    }
+
+    #[test]
+    fn liquidate_batch_frozen_pool() {
+        // ARRANGE
+        let e = Env::default();
+
+        e.mock_all_auths_allowing_non_root_auth();
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000;
+            li.timestamp = 1;
+            li.min_persistent_entry_ttl = 1_000_000;
+            li.min_temp_entry_ttl = 1_000_000;
+            li.max_entry_ttl = 1_000_001;
+        });
+
+        let TestEnv {
+            admin,
+            user,
+            manager_client,
+            pool_xlm_addr,
+            pool_usdc_addr,
+            pool_eurc_addr,
+            pool_usdc_client,
+            pool_eurc_client,
+            eurc_asset_client,
+            xlm_asset_client,
+            usdc_asset_client,
+            reflector_addr,
+            ..
+        } = setup_test_env(&e);
+
+        usdc_asset_client.mint(&admin, &9_001);
+        eurc_asset_client.mint(&admin, &9_001);
+        xlm_asset_client.mint(&user, &30_000);
+        pool_usdc_client.deposit(&admin, &9_001);
+        pool_eurc_client.deposit(&admin, &9_001);
+
+        let eurc_loan =
+            manager_client.create_loan(&user, &10_000, &pool_eurc_addr, &12_505, &pool_xlm_addr);
+        let usdc_loan =
+            manager_client.create_loan(&user, &10_000, &pool_usdc_addr, &12_505, &pool_xlm_addr);
+
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 10_000;
+            li.timestamp = 1 + 8_000_000;
+        });
+        e.register_at(&reflector_addr, oracle::WASM, ());
+        freeze_pool(&e, &pool_usdc_addr);
+        let admin_usdc_balance = usdc_asset_client.balance(&admin);
+
         // ACT
-        let res = manager_client.try_create_loan(&user, &10, &pool_xlm_addr, &100, &pool_addr);
-        assert!(res.is_err());
-    }
+        e.cost_estimate().budget().reset_unlimited();
+        let outcomes = manager_client.liquidate_batch(
+            &admin,
+            &vec![
+                &e,
+                (eurc_loan.loan_id.clone(), 5_000),
+                (usdc_loan.loan_id.clone(), 5_000),
+            ],
+        );
 
-    #[test]
-    fn cannot_create_loan_untrusted_collateral_pool() {
-        // ARRANGE
-        let e = Env::default();
-        e.mock_all_auths();
-        let TestEnv {
-            admin,
-            user,
-            manager_addr,
-            manager_client,
-            pool_xlm_addr,
-            ..
-        } = setup_test_env(&e);
+        // ASSERT
+        assert_eq!(
+            outcomes,
+            vec![
+                &e,
+                LiquidationOutcome::Liquidated,
+                LiquidationOutcome::Failed,
+            ]
+        );
+        assert_eq!(
+            manager_client.get_loan(&eurc_loan.loan_id).borrowed_amount,
+            5_789
+        );
 
-        // Set up a pool that's not trusted by loan manager
-        let ticker = Symbol::new(&e, "XLM");
-        let token_address = e
-            .register_stellar_asset_contract_v2(admin.clone())
-            .address();
-        let pool_addr = e.register(loan_pool::WASM, ());
-        let pool_client = loan_pool::Client::new(&e, &pool_addr);
-        pool_client.initialize(
-            &manager_addr,
-            &Currency {
-                ticker,
-                token_address,
-            },
-            &8_000_000,
+        // Nothing moved for the loan in the frozen pool.
+        let usdc_loan = manager_client.get_loan(&usdc_loan.loan_id);
+        assert!(usdc_loan.borrowed_amount > 10_000);
+        assert_eq!(usdc_loan.collateral_amount, 12_505);
+        assert_eq!(usdc_asset_client.balance(&admin), admin_usdc_balance);
+        assert_eq!(
+            manager_client.try_liquidate(&admin, &usdc_loan.loan_id, &5_000),
+            Err(Ok(LoanManagerError::LiquidationFailed))
         );
 
-        // ACT
-        let res = manager_client.try_create_loan(&user, &10, &pool_addr, &100, &pool_xlm_addr);
-        assert!(res.is_err());
+        assert_pool_invariants(&e, &manager_client);
     }
 
     #[test]
-    fn withdraw_revenue_as_admin() {
+    fn liquidate_batch_failing_pool() {
         // ARRANGE
         let e = Env::default();
+
         e.mock_all_auths_allowing_non_root_auth();
         e.ledger().with_mut(|li| {
             li.sequence_number = 100_000;
@@ -689,67 +5593,128 @@ mod tests {
         });
 
         let TestEnv {
+            admin,
             user,
             manager_client,
             pool_xlm_addr,
             pool_usdc_addr,
-            xlm_token_client,
-            usdc_token_client,
-            manager_addr,
-            pool_xlm_client,
+            pool_eurc_addr,
+            pool_usdc_client,
+            pool_eurc_client,
+            eurc_asset_client,
             xlm_asset_client,
             usdc_asset_client,
-            admin,
             reflector_addr,
             ..
         } = setup_test_env(&e);
 
-        xlm_asset_client.mint(&admin, &9_001);
-        pool_xlm_client.deposit(&admin, &9_001);
-        usdc_asset_client.mint(&user, &100_000);
+        usdc_asset_client.mint(&admin, &9_001);
+        eurc_asset_client.mint(&admin, &9_001);
+        xlm_asset_client.mint(&user, &30_000);
+        pool_usdc_client.deposit(&admin, &9_001);
+        pool_eurc_client.deposit(&admin, &9_001);
 
-        // Create a loan.
-        let loan =
-            manager_client.create_loan(&user, &1_000, &pool_xlm_addr, &100_000, &pool_usdc_addr);
+        let usdc_loan =
+            manager_client.create_loan(&user, &10_000, &pool_usdc_addr, &12_505, &pool_xlm_addr);
+        let eurc_loan =
+            manager_client.create_loan(&user, &10_000, &pool_eurc_addr, &12_505, &pool_xlm_addr);
 
-        // Move in time
         e.ledger().with_mut(|li| {
-            li.sequence_number = 100_000 + 100_000;
-            li.timestamp = 1 + 31_556_926;
+            li.sequence_number = 100_000 + 10_000;
+            li.timestamp = 1 + 8_000_000;
         });
-
         e.register_at(&reflector_addr, oracle::WASM, ());
+        // Interest can not be added to loans of a pool whose accrual index is lost.
+        e.as_contract(&pool_usdc_addr, || {
+            e.storage()
+                .persistent()
+                .remove(&vec![&e, Symbol::new(&e, "Accrual")])
+        });
+
+        // ACT
+        e.cost_estimate().budget().reset_unlimited();
+        let outcomes = manager_client.liquidate_batch(
+            &admin,
+            &vec![
+                &e,
+                (usdc_loan.loan_id.clone(), 5_000),
+                (eurc_loan.loan_id.clone(), 5_000),
+            ],
+        );
 
         // ASSERT
-        assert_eq!(xlm_token_client.balance(&user), 2_000);
-        assert_eq!(usdc_token_client.balance(&user), 0);
+        assert_eq!(
+            outcomes,
+            vec![
+                &e,
+                LiquidationOutcome::Failed,
+                LiquidationOutcome::Liquidated,
+            ]
+        );
+        assert_eq!(
+            manager_client.get_loan(&usdc_loan.loan_id).borrowed_amount,
+            10_000
+        );
+        assert_eq!(
+            manager_client.get_loan(&eurc_loan.loan_id).borrowed_amount,
+            5_789
+        );
+        // The USDC pool's storage is broken on purpose, so the invariants are not checked.
+    }
 
-        let user_loan = manager_client.get_loan(&loan.loan_id);
+    #[test]
+    fn liquidate_batch_is_capped() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+        let TestEnv {
+            admin,
+            user,
+            manager_client,
+            ..
+        } = setup_test_env(&e);
 
-        assert_eq!(user_loan.borrowed_amount, 1_000);
-        assert_eq!(user_loan.collateral_amount, 100_000);
+        let mut liquidations = vec![&e];
+        for nonce in 0..(MAX_LIQUIDATION_BATCH as u64) {
+            let loan_id = LoanId {
+                borrower_address: user.clone(),
+                nonce,
+            };
+            liquidations.push_back((loan_id, 100));
+        }
 
-        manager_client.repay(&loan.loan_id, &100);
-        e.ledger().with_mut(|li| {
-            li.sequence_number = 100_000 + 100_000 + 1;
-        });
+        // ACT
+        let outcomes = manager_client.liquidate_batch(&admin, &liquidations);
 
-        let user_loan = manager_client.get_loan(&loan.loan_id);
+        // ASSERT
+        assert_eq!(outcomes.len(), MAX_LIQUIDATION_BATCH);
+        assert_eq!(
+            outcomes.get(MAX_LIQUIDATION_BATCH - 1),
+            Some(LiquidationOutcome::LoanNotFound)
+        );
 
-        assert_eq!(user_loan.borrowed_amount, 929);
-        assert_eq!(user_loan.collateral_amount, 100_000);
-        assert_eq!(xlm_token_client.balance(&manager_addr), 2);
+        // Larger batches are rejected as a whole, like in `accrue_loans`.
+        liquidations.push_back((
+            LoanId {
+                borrower_address: user.clone(),
+                nonce: MAX_LIQUIDATION_BATCH as u64,
+            },
+            100,
+        ));
+        assert_eq!(
+            manager_client.try_liquidate_batch(&admin, &liquidations),
+            Err(Ok(LoanManagerError::InvalidAmount))
+        );
 
-        manager_client
-            .admin_withdraw_revenue(&1_i128, &pool_xlm_client.get_currency().token_address);
+        assert_pool_invariants(&e, &manager_client);
     }
 
     #[test]
-    #[should_panic(expected = "Error(Contract, #12)")]
-    fn withdraw_new_user_after_pool_has_yield() {
+    fn fixed_rate_loan() {
+        // ARRANGE
+        const YEAR: u64 = 31_556_926;
         let e = Env::default();
         e.mock_all_auths_allowing_non_root_auth();
-
         e.ledger().with_mut(|li| {
             li.sequence_number = 100_000;
             li.timestamp = 1;
@@ -759,94 +5724,83 @@ mod tests {
         });
 
         let TestEnv {
-            user,
             admin,
+            user,
             manager_client,
             pool_xlm_addr,
             pool_usdc_addr,
-            xlm_token_client,
-            usdc_token_client,
             pool_usdc_client,
+            xlm_asset_client,
             reflector_addr,
-            usdc_asset_client,
             ..
         } = setup_test_env(&e);
+        xlm_asset_client.mint(&user, &19_000);
+        pool_usdc_client.deposit(&admin, &9_000);
+
+        manager_client.set_fixed_rate_config(
+            &pool_usdc_addr,
+            &FixedRateConfig {
+                premium: 100_000,      // 1%
+                penalty_rate: 500_000, // 5%
+            },
+        );
 
         // ACT
-        // Create a loan.
-        let mut loan =
-            manager_client.create_loan(&user, &100, &pool_usdc_addr, &500, &pool_xlm_addr);
-        assert_eq!(pool_usdc_client.get_available_balance(), 900);
+        let loan = manager_client.create_fixed_rate_loan(
+            &user,
+            &5_000,
+            &pool_usdc_addr,
+            &20_000,
+            &pool_xlm_addr,
+            &(1 + 2 * YEAR),
+        );
 
-        // Move in time
+        // ASSERT
+        // Variable rate at 50% utilisation plus the premium.
+        let fixed_rate = manager_client.get_fixed_rate(&loan.loan_id).unwrap();
+        assert_eq!(fixed_rate.rate, 744_440);
+        assert_eq!(fixed_rate.rate, pool_usdc_client.get_interest() + 100_000);
+
+        // A year in, interest follows the locked rate and the loan is not liquidatable.
         e.ledger().with_mut(|li| {
             li.sequence_number = 100_000 + 100_000;
-            li.timestamp = 1 + 31_556_926;
+            li.timestamp = 1 + YEAR;
         });
-
-        // A new instance of reflector mock needs to be created, they only live for one ledger.
         e.register_at(&reflector_addr, oracle::WASM, ());
-
-        // ASSERT
-        assert_eq!(xlm_token_client.balance(&user), 500);
-        assert_eq!(usdc_token_client.balance(&user), 100);
-
-        loan = manager_client.get_loan(&loan.loan_id);
-
-        assert_eq!(loan.borrowed_amount, 100);
-        assert_eq!(loan.collateral_amount, 500);
-
-        manager_client.repay(&loan.loan_id, &50);
-        loan = manager_client.get_loan(&loan.loan_id);
-        assert_eq!(loan.borrowed_amount, 52);
-
-        assert_eq!((52, 2), manager_client.repay(&loan.loan_id, &50));
-        assert_eq!(1000, pool_usdc_client.get_available_balance());
-        assert_eq!(1002, pool_usdc_client.get_contract_balance());
-        assert_eq!(1000, pool_usdc_client.get_total_balance_shares());
-
-        // Create a new user that should not be able to withdraw more than what they have deposited even if the pool already has interest
-        let new_user = Address::generate(&e);
-        usdc_asset_client.mint(&new_user, &1_000);
-
-        pool_usdc_client.deposit(&new_user, &1000);
-        assert_eq!(2002, pool_usdc_client.get_contract_balance());
-        let positions_new_user = Positions {
-            collateral: 0,
-            liabilities: 0,
-            receivable_shares: 998,
-        };
+        pool_usdc_client.deposit(&admin, &10_000);
+        let accrued = manager_client.add_interest(&loan.loan_id);
+        assert_eq!(accrued.borrowed_amount, 5_372);
         assert_eq!(
-            positions_new_user,
-            pool_usdc_client.get_user_positions(&new_user)
+            manager_client.try_liquidate(&admin, &loan.loan_id, &2_000),
+            Err(Ok(LoanManagerError::LoanNotLiquidatable))
         );
 
-        let test_positions_admin = Positions {
-            collateral: 0,
-            liabilities: 0,
-            receivable_shares: 1000,
-        };
-        assert_eq!(
-            test_positions_admin,
-            pool_usdc_client.get_user_positions(&admin)
-        );
-        let pool_state = PoolState {
-            annual_interest_rate: 200887,
-            available_balance_tokens: 2000,
-            total_balance_shares: 1998,
-            total_balance_tokens: 2002,
-        };
-        assert_eq!(pool_state, pool_usdc_client.get_pool_state());
+        // Half a year past maturity, the penalty rate applies and the loan can be liquidated.
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000 + 200_000;
+            li.timestamp = 1 + 2 * YEAR + YEAR / 2;
+        });
+        e.register_at(&reflector_addr, oracle::WASM, ());
+        let accrued = manager_client.add_interest(&loan.loan_id);
+        assert_eq!(accrued.borrowed_amount, 6_106);
+        assert!(accrued.health_factor > HEALTH_FACTOR_THRESHOLD);
 
-        // Should panic because the user has no xlm to withdraw
-        pool_usdc_client.withdraw(&new_user, &1002);
+        let liquidated = manager_client.liquidate(&admin, &loan.loan_id, &2_000);
+        assert_eq!(liquidated.borrowed_amount, 4_106);
+        assert_eq!(liquidated.collateral_amount, 17_800);
+
+        manager_client.repay_and_close_manager(&user, &4_106, &loan.loan_id);
+        assert_eq!(manager_client.get_fixed_rate(&loan.loan_id), None);
+
+        assert_pool_invariants(&e, &manager_client);
     }
 
     #[test]
-    fn create_loan() {
+    fn cannot_create_invalid_fixed_rate_loan() {
         // ARRANGE
         let e = Env::default();
         e.mock_all_auths_allowing_non_root_auth();
+        e.ledger().with_mut(|li| li.timestamp = 1_000);
 
         let TestEnv {
             user,
@@ -854,393 +5808,386 @@ mod tests {
             pool_xlm_addr,
             pool_usdc_addr,
             pool_eurc_addr,
-            xlm_token_client,
-            usdc_token_client,
-            eurc_token_client,
             ..
         } = setup_test_env(&e);
 
-        // ACT
-        manager_client.create_loan(&user, &10, &pool_usdc_addr, &100, &pool_xlm_addr);
-        manager_client.create_loan(&user, &30, &pool_eurc_addr, &300, &pool_xlm_addr);
-
-        // ASSERT
-        assert_eq!(xlm_token_client.balance(&user), 600);
-        assert_eq!(usdc_token_client.balance(&user), 10);
-        assert_eq!(eurc_token_client.balance(&user), 30);
-
-        let loans = manager_client.get_loans(&user);
-        assert_eq!(loans.len(), 2);
+        // ACT & ASSERT
+        assert_eq!(
+            manager_client.try_create_fixed_rate_loan(
+                &user,
+                &100,
+                &pool_usdc_addr,
+                &1000,
+                &pool_xlm_addr,
+                &2_000
+            ),
+            Err(Ok(LoanManagerError::FixedRateNotEnabled))
+        );
 
-        let loan_usdc = loans.get(0).unwrap();
-        assert_eq!(loan_usdc.borrowed_amount, 10);
-        assert_eq!(loan_usdc.collateral_amount, 100);
-        assert_eq!(loan_usdc.borrowed_from, pool_usdc_addr);
-        assert_eq!(loan_usdc.collateral_from, pool_xlm_addr);
+        let config = FixedRateConfig {
+            premium: 100_000,
+            penalty_rate: 500_000,
+        };
+        assert_eq!(
+            manager_client.try_set_fixed_rate_config(&Address::generate(&e), &config),
+            Err(Ok(LoanManagerError::AddressNotFound))
+        );
+        manager_client.set_fixed_rate_config(&pool_usdc_addr, &config);
+        assert_eq!(
+            manager_client.get_fixed_rate_config(&pool_usdc_addr),
+            Some(config)
+        );
+        // The terms only apply to the pool they were set for.
+        assert_eq!(manager_client.get_fixed_rate_config(&pool_eurc_addr), None);
+        assert_eq!(
+            manager_client.try_create_fixed_rate_loan(
+                &user,
+                &100,
+                &pool_eurc_addr,
+                &1000,
+                &pool_xlm_addr,
+                &2_000
+            ),
+            Err(Ok(LoanManagerError::FixedRateNotEnabled))
+        );
+        assert_eq!(
+            manager_client.try_create_fixed_rate_loan(
+                &user,
+                &100,
+                &pool_usdc_addr,
+                &1000,
+                &pool_xlm_addr,
+                &1_000
+            ),
+            Err(Ok(LoanManagerError::InvalidMaturity))
+        );
 
-        let loan_eurc = loans.get(1).unwrap();
-        assert_eq!(loan_eurc.borrowed_amount, 30);
-        assert_eq!(loan_eurc.collateral_amount, 300);
-        assert_eq!(loan_eurc.borrowed_from, pool_eurc_addr);
-        assert_eq!(loan_eurc.collateral_from, pool_xlm_addr);
+        assert_pool_invariants(&e, &manager_client);
     }
 
     #[test]
-    fn add_interest() {
+    fn legacy_loan_accrual_is_rescaled() {
         // ARRANGE
         let e = Env::default();
         e.mock_all_auths_allowing_non_root_auth();
-        e.ledger().with_mut(|li| {
-            li.sequence_number = 100_000;
-            li.timestamp = 1;
-            li.min_persistent_entry_ttl = 10_000_000;
-            li.min_temp_entry_ttl = 1_000_000;
-            li.max_entry_ttl = 1_000_001;
-        });
 
         let TestEnv {
             user,
             manager_client,
             pool_xlm_addr,
             pool_usdc_addr,
-            xlm_token_client,
-            usdc_token_client,
-            reflector_addr,
             ..
         } = setup_test_env(&e);
+        let loan = manager_client.create_loan(&user, &100, &pool_usdc_addr, &1000, &pool_xlm_addr);
 
         // ACT
-
-        // Create a loan.
-        let mut loan =
-            manager_client.create_loan(&user, &100, &pool_usdc_addr, &1000, &pool_xlm_addr);
-
-        // Here borrowed amount should be the same as time has not moved. add_interest() is only called to store the LastUpdate sequence number.
-        assert_eq!(loan.borrowed_amount, 100);
-        assert_eq!(loan.health_factor, 80_000_000);
-        assert_eq!(xlm_token_client.balance(&user), 0);
-        assert_eq!(usdc_token_client.balance(&user), 100);
-
-        // Move time
-        e.ledger().with_mut(|li| {
-            li.sequence_number = 100_000 + 100_000;
-            li.timestamp = 1 + 31_556_926;
+        // A loan stored before the accrual index had 18 decimals.
+        e.as_contract(&manager_client.address, || {
+            e.storage().persistent().set(
+                &storage::LoanManagerDataKey::Loan(loan.loan_id.clone()),
+                &Loan {
+                    last_accrual: loan.last_accrual / 100_000_000_000,
+                    ..loan.clone()
+                },
+            )
         });
 
-        // A new instance of reflector mock needs to be created, they only live for one ledger.
-        e.register_at(&reflector_addr, oracle::WASM, ());
-
-        loan = manager_client.add_interest(&loan.loan_id);
+        // ASSERT
+        assert_eq!(manager_client.get_loan(&loan.loan_id), loan);
+        assert_eq!(
+            manager_client.add_interest(&loan.loan_id).borrowed_amount,
+            100
+        );
 
-        assert_eq!(loan.borrowed_amount, 102);
-        assert_eq!(loan.health_factor, 78_431_372);
-        assert_eq!(loan.collateral_amount, 1000);
+        assert_pool_invariants(&e, &manager_client);
     }
 
     #[test]
-    fn repay() {
+    fn list_loans() {
         // ARRANGE
         let e = Env::default();
         e.mock_all_auths_allowing_non_root_auth();
-        e.ledger().with_mut(|li| {
-            li.sequence_number = 100_000;
-            li.timestamp = 1;
-            li.min_persistent_entry_ttl = 1_000_000;
-            li.min_temp_entry_ttl = 1_000_000;
-            li.max_entry_ttl = 1_000_001;
-        });
 
         let TestEnv {
+            admin,
             user,
             manager_client,
             pool_xlm_addr,
             pool_usdc_addr,
-            pool_eurc_addr,
-            pool_usdc_client,
-            pool_eurc_client,
-            xlm_token_client,
-            usdc_token_client,
-            reflector_addr,
-            eurc_token_client,
+            xlm_asset_client,
+            usdc_asset_client,
             ..
         } = setup_test_env(&e);
+        xlm_asset_client.mint(&user, &10_000);
+        xlm_asset_client.mint(&admin, &10_000);
+        usdc_asset_client.mint(&user, &1_000);
 
         // ACT
-        // Create a loan.
-        let mut loan_usdc =
-            manager_client.create_loan(&user, &100, &pool_usdc_addr, &500, &pool_xlm_addr);
-        let mut loan_eurc =
-            manager_client.create_loan(&user, &100, &pool_eurc_addr, &500, &pool_xlm_addr);
-
-        // Move in time
-        e.ledger().with_mut(|li| {
-            li.sequence_number = 100_000 + 100_000;
-            li.timestamp = 1 + 31_556_926;
-        });
-
-        // A new instance of reflector mock needs to be created, they only live for one ledger.
-        e.register_at(&reflector_addr, oracle::WASM, ());
+        let loan1 = manager_client.create_loan(&user, &100, &pool_usdc_addr, &1000, &pool_xlm_addr);
+        let loan2 =
+            manager_client.create_loan(&admin, &200, &pool_usdc_addr, &2000, &pool_xlm_addr);
+        let loan3 = manager_client.create_loan(&user, &300, &pool_usdc_addr, &3000, &pool_xlm_addr);
 
         // ASSERT
-        assert_eq!(xlm_token_client.balance(&user), 0);
-        assert_eq!(usdc_token_client.balance(&user), 100);
-        assert_eq!(eurc_token_client.balance(&user), 100);
+        assert_eq!(manager_client.loan_count(), 3);
+        assert_eq!(
+            manager_client.list_loans(&0, &2).loans,
+            vec![&e, loan1.clone(), loan2.clone()]
+        );
+        assert_eq!(
+            manager_client.list_loans(&2, &2).loans,
+            vec![&e, loan3.clone()]
+        );
+        assert_eq!(manager_client.list_loans(&3, &2).loans, vec![&e]);
+        assert_eq!(manager_client.list_loans(&0, &2).generation, 0);
 
-        loan_usdc = manager_client.get_loan(&loan_usdc.loan_id);
-        assert_eq!(loan_usdc.borrowed_amount, 100);
-        assert_eq!(loan_usdc.collateral_amount, 500);
+        // Closing a loan moves the last loan into its place.
+        manager_client.repay_and_close_manager(&user, &100, &loan1.loan_id);
+        assert_eq!(manager_client.loan_count(), 2);
+        assert_eq!(
+            manager_client.list_loans(&0, &10),
+            LoanPage {
+                loans: vec![&e, loan3.clone(), loan2.clone()],
+                generation: 1,
+            }
+        );
 
-        manager_client.repay(&loan_usdc.loan_id, &50);
-        loan_usdc = manager_client.get_loan(&loan_usdc.loan_id);
-        assert_eq!(loan_usdc.borrowed_amount, 52);
-        assert_eq!(loan_usdc.collateral_amount, 500);
+        manager_client.repay_and_close_manager(&user, &300, &loan3.loan_id);
+        assert_eq!(manager_client.loan_count(), 1);
+        assert_eq!(
+            manager_client.list_loans(&0, &10),
+            LoanPage {
+                loans: vec![&e, loan2.clone()],
+                generation: 2,
+            }
+        );
 
-        assert_eq!((52, 2), manager_client.repay(&loan_usdc.loan_id, &50));
-        assert_eq!(1000, pool_usdc_client.get_available_balance());
-        assert_eq!(1002, pool_usdc_client.get_contract_balance());
-        assert_eq!(1000, pool_usdc_client.get_total_balance_shares());
+        // Closing the last loan of the index moves nothing.
+        manager_client.repay_and_close_manager(&admin, &200, &loan2.loan_id);
+        assert_eq!(manager_client.list_loans(&0, &10).generation, 2);
 
-        loan_eurc = manager_client.get_loan(&loan_eurc.loan_id);
-        assert_eq!(loan_eurc.borrowed_amount, 100);
-        assert_eq!(loan_eurc.collateral_amount, 500);
-        assert_eq!(900, pool_eurc_client.get_available_balance());
-        assert_eq!(1000, pool_eurc_client.get_contract_balance());
-        assert_eq!(1000, pool_eurc_client.get_total_balance_shares());
+        assert_pool_invariants(&e, &manager_client);
     }
 
     #[test]
-    fn repay_and_close() {
+    fn index_loans() {
         // ARRANGE
         let e = Env::default();
         e.mock_all_auths_allowing_non_root_auth();
-        e.ledger().with_mut(|li| {
-            li.sequence_number = 100_000;
-            li.timestamp = 1;
-            li.min_persistent_entry_ttl = 1_000_000;
-            li.min_temp_entry_ttl = 1_000_000;
-            li.max_entry_ttl = 1_000_001;
-        });
 
         let TestEnv {
             user,
             manager_client,
             pool_xlm_addr,
             pool_usdc_addr,
-            xlm_token_client,
-            usdc_token_client,
-            reflector_addr,
-            pool_usdc_client,
+            xlm_asset_client,
             usdc_asset_client,
             ..
         } = setup_test_env(&e);
+        xlm_asset_client.mint(&user, &10_000);
+        usdc_asset_client.mint(&user, &1_000);
 
-        // ACT
-        // Create a loan.
-        let loan = manager_client.create_loan(&user, &100, &pool_usdc_addr, &300, &pool_xlm_addr);
-
-        // Move in time
-        e.ledger().with_mut(|li| {
-            li.sequence_number = 100_000 + 100_000;
-            li.timestamp = 1 + 31_556_926;
+        let loan = manager_client.create_loan(&user, &100, &pool_usdc_addr, &1000, &pool_xlm_addr);
+        // A loan stored before the global index existed.
+        let legacy_loan_id = LoanId {
+            borrower_address: user.clone(),
+            nonce: 99,
+        };
+        let legacy_loan = Loan {
+            loan_id: legacy_loan_id.clone(),
+            ..loan.clone()
+        };
+        e.as_contract(&manager_client.address, || {
+            storage::write_loan(&e, &legacy_loan_id, &legacy_loan)
         });
+        let missing_loan_id = LoanId {
+            borrower_address: user.clone(),
+            nonce: 100,
+        };
+        assert_eq!(manager_client.loan_count(), 1);
 
-        // A new instance of reflector mock needs to be created, they only live for one ledger.
-        e.register_at(&reflector_addr, oracle::WASM, ());
+        // ACT
+        let indexed = manager_client.index_loans(&vec![
+            &e,
+            legacy_loan_id.clone(),
+            loan.loan_id.clone(),
+            missing_loan_id,
+            legacy_loan_id.clone(),
+        ]);
 
         // ASSERT
-        assert_eq!(xlm_token_client.balance(&user), 700);
-        assert_eq!(usdc_token_client.balance(&user), 100);
-
-        let loans = manager_client.get_loans(&user);
-        assert_eq!(loans.len(), 1);
+        assert_eq!(indexed, 1);
+        assert_eq!(manager_client.loan_count(), 2);
+        assert_eq!(
+            manager_client.list_loans(&0, &10).loans,
+            vec![&e, loan.clone(), legacy_loan]
+        );
 
-        // mint the user some money so they can repay.
-        usdc_asset_client.mint(&user, &45);
-        manager_client.repay_and_close_manager(&145, &loan.loan_id);
+        // Indexed legacy loans leave the index like any other loan.
+        e.as_contract(&manager_client.address, || {
+            storage::delete_loan(&e, &legacy_loan_id)
+        });
+        assert_eq!(
+            manager_client.list_loans(&0, &10).loans,
+            vec![&e, loan.clone()]
+        );
 
-        let loans = manager_client.get_loans(&user);
-        assert_eq!(loans.len(), 0);
-        assert_eq!(1002, pool_usdc_client.get_available_balance());
-        assert_eq!(1002, pool_usdc_client.get_contract_balance());
-        assert_eq!(1000, pool_usdc_client.get_total_balance_shares());
+        let mut loan_ids = vec![&e];
+        for _ in 0..=MAX_LOANS_PAGE {
+            loan_ids.push_back(loan.loan_id.clone());
+        }
+        assert_eq!(
+            manager_client.try_index_loans(&loan_ids),
+            Err(Ok(LoanManagerError::InvalidAmount))
+        );
     }
 
     #[test]
-    fn repay_and_close_with_multiple_loans() {
+    fn get_loans_page() {
         // ARRANGE
         let e = Env::default();
         e.mock_all_auths_allowing_non_root_auth();
-        e.ledger().with_mut(|li| {
-            li.sequence_number = 100_000;
-            li.timestamp = 1;
-            li.min_persistent_entry_ttl = 1_000_000;
-            li.min_temp_entry_ttl = 1_000_000;
-            li.max_entry_ttl = 1_000_001;
-        });
 
         let TestEnv {
             user,
             manager_client,
-            usdc_asset_client,
-            pool_usdc_client,
             pool_xlm_addr,
             pool_usdc_addr,
-            pool_eurc_addr,
-            usdc_token_client,
-            reflector_addr,
+            xlm_asset_client,
             ..
         } = setup_test_env(&e);
+        xlm_asset_client.mint(&user, &10_000);
 
         // ACT
-        let mut usdc_loan =
-            manager_client.create_loan(&user, &100, &pool_usdc_addr, &300, &pool_xlm_addr);
-        manager_client.create_loan(&user, &100, &pool_eurc_addr, &300, &pool_xlm_addr);
-
-        // Move in time
-        e.ledger().with_mut(|li| {
-            li.sequence_number = 100_000 + 100_000;
-            li.timestamp = 1 + 31_556_926;
-        });
+        let loan1 = manager_client.create_loan(&user, &100, &pool_usdc_addr, &1000, &pool_xlm_addr);
+        let loan2 = manager_client.create_loan(&user, &200, &pool_usdc_addr, &2000, &pool_xlm_addr);
+        let loan3 = manager_client.create_loan(&user, &300, &pool_usdc_addr, &3000, &pool_xlm_addr);
 
         // ASSERT
-        // A new instance of reflector mock needs to be created, they only live for one ledger.
-        e.register_at(&reflector_addr, oracle::WASM, ());
-
-        let loans = manager_client.get_loans(&user);
-        assert_eq!(loans.len(), 2);
-        usdc_loan = manager_client.get_loan(&usdc_loan.loan_id);
-
-        assert_eq!(usdc_loan.borrowed_amount, 100);
-        assert_eq!(usdc_loan.collateral_amount, 300);
+        assert_eq!(manager_client.user_loan_count(&user), 3);
+        assert_eq!(
+            manager_client.get_loans_page(&user, &0, &2),
+            vec![&e, loan1.clone(), loan2.clone()]
+        );
+        assert_eq!(
+            manager_client.get_loans_page(&user, &2, &2),
+            vec![&e, loan3.clone()]
+        );
+        assert_eq!(manager_client.get_loans_page(&user, &3, &2), vec![&e]);
 
-        // mint the user some money so they can repay.
-        usdc_asset_client.mint(&user, &45);
+        // Closing a loan moves the user's last loan into its place.
+        manager_client.repay_and_close_manager(&user, &100, &loan1.loan_id);
+        assert_eq!(manager_client.user_loan_count(&user), 2);
         assert_eq!(
-            102,
-            manager_client
-                .repay_and_close_manager(&(usdc_loan.borrowed_amount + 45), &usdc_loan.loan_id)
+            manager_client.get_loans_page(&user, &0, &10),
+            vec![&e, loan3.clone(), loan2.clone()]
         );
 
-        assert_eq!(1002, pool_usdc_client.get_available_balance());
-        assert_eq!(1002, pool_usdc_client.get_contract_balance());
-        assert_eq!(1000, pool_usdc_client.get_total_balance_shares());
-        assert_eq!(43, usdc_token_client.balance(&user));
+        manager_client.repay_and_close_manager(&user, &200, &loan2.loan_id);
+        manager_client.repay_and_close_manager(&user, &300, &loan3.loan_id);
+        assert_eq!(manager_client.user_loan_count(&user), 0);
+        assert_eq!(manager_client.get_loans(&user), vec![&e]);
 
-        // The eurc loan should still be there after repaying the usdc loan
-        let loans = manager_client.get_loans(&user);
-        assert_eq!(loans.len(), 1);
-        let eurc_loan = loans.get(0).unwrap();
-        assert_eq!(eurc_loan.borrowed_amount, 100);
-        assert_eq!(eurc_loan.collateral_amount, 300);
+        assert_pool_invariants(&e, &manager_client);
     }
 
     #[test]
-    #[should_panic(expected = "Amount can not be greater than borrowed amount!")]
-    fn repay_more_than_borrowed() {
+    fn migrate_legacy_user_loan_ids() {
         // ARRANGE
         let e = Env::default();
         e.mock_all_auths_allowing_non_root_auth();
+
         let TestEnv {
             user,
             manager_client,
             pool_xlm_addr,
             pool_usdc_addr,
+            xlm_asset_client,
             ..
         } = setup_test_env(&e);
+        xlm_asset_client.mint(&user, &10_000);
+
+        let loan1 = manager_client.create_loan(&user, &100, &pool_usdc_addr, &1000, &pool_xlm_addr);
+        let loan2 = manager_client.create_loan(&user, &200, &pool_usdc_addr, &2000, &pool_xlm_addr);
+        // Rewrite the user's loans into the list layout used before the paginated index.
+        e.as_contract(&manager_client.address, || {
+            let storage = e.storage().persistent();
+            storage.set(
+                &(user.clone(), symbol_short!("ids")),
+                &vec![&e, 1_u64, 2_u64],
+            );
+            storage.remove(&storage::LoanManagerDataKey::UserLoanCount(user.clone()));
+            for position in 0..2_u32 {
+                storage.remove(&storage::LoanManagerDataKey::UserLoanIndex(
+                    user.clone(),
+                    position,
+                ));
+            }
+        });
+        assert_eq!(
+            manager_client.get_loans_page(&user, &1, &10),
+            vec![&e, loan2.clone()]
+        );
 
         // ACT
-        // Create a loan.
-        let loan = manager_client.create_loan(&user, &100, &pool_usdc_addr, &1000, &pool_xlm_addr);
+        let loan3 = manager_client.create_loan(&user, &300, &pool_usdc_addr, &3000, &pool_xlm_addr);
+        manager_client.repay_and_close_manager(&user, &100, &loan1.loan_id);
+
+        // ASSERT
+        assert_eq!(manager_client.user_loan_count(&user), 2);
+        assert_eq!(manager_client.get_loans(&user), vec![&e, loan3, loan2]);
+        let legacy = e.as_contract(&manager_client.address, || {
+            e.storage()
+                .persistent()
+                .has(&(user.clone(), symbol_short!("ids")))
+        });
+        assert!(!legacy);
 
-        manager_client.repay(&loan.loan_id, &2_000);
+        assert_pool_invariants(&e, &manager_client);
     }
 
     #[test]
-    fn liquidate() {
+    fn user_loan_index_cost_is_constant() {
         // ARRANGE
         let e = Env::default();
-
         e.mock_all_auths_allowing_non_root_auth();
-        e.ledger().with_mut(|li| {
-            li.sequence_number = 100_000;
-            li.timestamp = 1;
-            li.min_persistent_entry_ttl = 1_000_000;
-            li.min_temp_entry_ttl = 1_000_000;
-            li.max_entry_ttl = 1_000_001;
-        });
 
         let TestEnv {
-            admin,
             user,
             manager_client,
             pool_xlm_addr,
             pool_usdc_addr,
-            pool_eurc_addr,
-            pool_usdc_client,
-            pool_eurc_client,
-            eurc_asset_client,
             xlm_asset_client,
-            usdc_asset_client,
-            reflector_addr,
             ..
         } = setup_test_env(&e);
-
-        // print more money
-        usdc_asset_client.mint(&admin, &9_001);
-        eurc_asset_client.mint(&admin, &9_001);
-        xlm_asset_client.mint(&user, &30_000);
-        pool_usdc_client.deposit(&admin, &9_001);
-        pool_eurc_client.deposit(&admin, &9_001);
+        xlm_asset_client.mint(&user, &100_000);
 
         // ACT
-        // Create two loans, one to liquidate.
-        let mut usdc_loan =
-            manager_client.create_loan(&user, &10_000, &pool_usdc_addr, &12_505, &pool_xlm_addr);
-        let mut eurc_loan =
-            manager_client.create_loan(&user, &10_000, &pool_eurc_addr, &12_505, &pool_xlm_addr);
-
-        manager_client.add_interest(&usdc_loan.loan_id);
-        manager_client.add_interest(&eurc_loan.loan_id);
-
-        // Here borrowed amount should be the same as time has not moved. add_interest() is only called to store the LastUpdate sequence number.
-        assert_eq!(usdc_loan.borrowed_amount, 10_000);
-        assert_eq!(usdc_loan.health_factor, 10_004_000);
-
-        // Move time
-        e.ledger().with_mut(|li| {
-            li.sequence_number = 100_000 + 10_000;
-            li.timestamp = 1 + 8_000_000;
-        });
-
-        // A new instance of reflector mock needs to be created, they only live for one ledger.
-        e.register_at(&reflector_addr, oracle::WASM, ());
-
-        manager_client.add_interest(&usdc_loan.loan_id);
-        manager_client.add_interest(&eurc_loan.loan_id);
-
-        usdc_loan = manager_client.get_loan(&usdc_loan.loan_id);
-
-        assert_eq!(usdc_loan.borrowed_amount, 10_760);
-        assert_eq!(usdc_loan.health_factor, 9_297_397);
-        assert_eq!(usdc_loan.collateral_amount, 12_505);
-
-        e.ledger().with_mut(|li| {
-            li.sequence_number = 100_000 + 1_000;
-        });
-
-        e.register_at(&reflector_addr, oracle::WASM, ());
-
-        manager_client.liquidate(&admin, &usdc_loan.loan_id, &5_000);
-
-        usdc_loan = manager_client.get_loan(&usdc_loan.loan_id);
-        assert_eq!(usdc_loan.borrowed_amount, 5_760);
-        assert_eq!(usdc_loan.health_factor, 9_729_166);
-        assert_eq!(usdc_loan.collateral_amount, 7_005);
+        let first = manager_client.create_loan(&user, &10, &pool_usdc_addr, &100, &pool_xlm_addr);
+        manager_client.create_loan(&user, &10, &pool_usdc_addr, &100, &pool_xlm_addr);
+        let first_open = e.cost_estimate().resources();
+        for _ in 0..47 {
+            manager_client.create_loan(&user, &10, &pool_usdc_addr, &100, &pool_xlm_addr);
+        }
+        let last = manager_client.create_loan(&user, &10, &pool_usdc_addr, &100, &pool_xlm_addr);
+        let last_open = e.cost_estimate().resources();
+        manager_client.repay_and_close_manager(&user, &10, &last.loan_id);
+        let last_close = e.cost_estimate().resources();
+        manager_client.repay_and_close_manager(&user, &10, &first.loan_id);
+        let first_close = e.cost_estimate().resources();
 
-        eurc_loan = manager_client.get_loan(&eurc_loan.loan_id);
-        assert_eq!(eurc_loan.borrowed_amount, 10_760);
-        assert_eq!(eurc_loan.health_factor, 9_297_397);
-        assert_eq!(eurc_loan.collateral_amount, 12_505);
+        // ASSERT
+        // Opening a loan writes the same entries and bytes no matter how many loans the user
+        // already has.
+        assert_eq!(manager_client.user_loan_count(&user), 48);
+        assert_eq!(last_open.write_entries, first_open.write_entries);
+        assert_eq!(last_open.write_bytes, first_open.write_bytes);
+        assert_eq!(last_open.disk_read_entries, first_open.disk_read_entries);
+        // Closing a loan other than the last one also moves the last loan in the user and the
+        // global index, and bumps the global index's generation.
+        assert_eq!(first_close.write_entries, last_close.write_entries + 5);
+
+        assert_pool_invariants(&e, &manager_client);
     }
 
     #[test]
@@ -1281,6 +6228,8 @@ mod tests {
         assert_eq!(loan1.borrowed_amount, 100);
         assert_eq!(loan2.borrowed_amount, 200);
         assert_eq!(loan3.borrowed_amount, 300);
+
+        assert_pool_invariants(&e, &manager_client);
     }
 
     /* Test setup helpers */
@@ -1304,6 +6253,21 @@ mod tests {
         pool_eurc_client: loan_pool::Client<'a>,
     }
 
+    /// Deploy a dex mock with the test tokens listed and register it as the swap router.
+    fn setup_dex<'a>(
+        e: &'a Env,
+        test_env: &TestEnv,
+        slippage: i128,
+    ) -> (Address, dex_mock::Client<'a>) {
+        let router = e.register(dex_mock::WASM, (&test_env.reflector_addr, slippage));
+        let dex_client = dex_mock::Client::new(e, &router);
+        dex_client.set_ticker(&test_env.xlm_asset_client.address, &Symbol::new(e, "XLM"));
+        dex_client.set_ticker(&test_env.usdc_asset_client.address, &Symbol::new(e, "USDC"));
+        dex_client.set_ticker(&test_env.eurc_asset_client.address, &Symbol::new(e, "EURC"));
+        test_env.manager_client.set_swap_router(&router);
+        (router, dex_client)
+    }
+
     fn setup_test_env(e: &Env) -> TestEnv<'_> {
         let admin = Address::generate(e);
         let admin2 = Address::generate(e);
@@ -0,0 +1,81 @@
+use soroban_sdk::{contracttype, Address, Vec};
+
+use crate::storage::{Loan, LoanId};
+
+/// Live view of a loan with interest accrued up to the current ledger. Values are in oracle
+/// price units, i.e. token amount multiplied by the TWAP price.
+#[contracttype]
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoanSummary {
+    pub loan_id: LoanId,
+    pub borrowed_from: Address,
+    pub collateral_from: Address,
+    pub borrowed_amount: i128,
+    pub unpaid_interest: i128,
+    pub collateral_amount: i128,
+    pub borrowed_value: i128,
+    pub collateral_value: i128,
+    // Collateral value weighted by the collateral pool's collateral factor.
+    pub borrowing_power: i128,
+    pub health_factor: i128,
+    // Collateral price at which the loan becomes liquidatable, None for a loan without
+    // collateral.
+    pub liquidation_price: Option<i128>,
+    // Amount of the borrowed token that could still be borrowed against the collateral.
+    pub max_additional_borrow: i128,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccountSummary {
+    pub loans: Vec<LoanSummary>,
+    pub total_borrowed_value: i128,
+    pub total_collateral_value: i128,
+    pub total_borrowing_power: i128,
+}
+
+/// Outcome of a loan operation computed without executing it. Token amounts are what would move
+/// between the caller and the pools, the loan fields are the loan as it would be stored.
+#[contracttype]
+#[derive(Clone, Debug, PartialEq)]
+pub struct OperationPreview {
+    pub borrowed_amount: i128,
+    pub collateral_amount: i128,
+    pub unpaid_interest: i128,
+    // None when the operation closes the loan.
+    pub health_factor: Option<i128>,
+    // Borrowed token paid in by the caller.
+    pub amount_in: i128,
+    // Borrowed token paid out to the borrower.
+    pub amount_out: i128,
+    // Collateral paid in by the caller.
+    pub collateral_in: i128,
+    // Collateral paid out to the borrower or, on liquidation, seized from the loan.
+    pub collateral_out: i128,
+    // Part of `amount_in` that goes to the protocol.
+    pub protocol_fee: i128,
+    // Part of `collateral_out` that is the liquidation bonus.
+    pub liquidation_bonus: i128,
+    // Part of `liquidation_bonus` that goes to the protocol instead of the liquidator.
+    pub protocol_bonus: i128,
+}
+
+/// Target of `create_leveraged_loan`. 1.0 = 10000000_i128
+#[contracttype]
+#[derive(Clone, Debug, PartialEq)]
+pub struct LeverageTarget {
+    // Total collateral as a multiple of the user's own collateral.
+    pub leverage: i128,
+    // How much less than the oracle price the swap may return.
+    pub max_slippage: i128,
+}
+
+/// Page of the global loan index. Closing a loan can move another loan to a position a scan has
+/// already passed, which bumps `generation`. A scan that sees the generation change between pages
+/// may have missed loans and should start over.
+#[contracttype]
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoanPage {
+    pub loans: Vec<Loan>,
+    pub generation: u32,
+}
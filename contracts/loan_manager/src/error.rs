@@ -15,4 +15,28 @@ pub enum LoanManagerError {
     InvalidCollateralToken = 9,
     InvalidLiquidation = 10,
     OracleNotFound = 11,
+    Unauthorized = 12,
+    InvalidAmount = 13,
+    HealthFactorTooLow = 14,
+    InvalidLoanTransfer = 15,
+    TriggerNotFound = 16,
+    TriggerNotMet = 17,
+    InvalidTrigger = 18,
+    LoanNotLiquidatable = 19,
+    InvalidLiquidationAmount = 20,
+    InvalidLiquidationAuction = 21,
+    FixedRateNotEnabled = 22,
+    InvalidFixedRateConfig = 23,
+    InvalidMaturity = 24,
+    InvalidAssetCategory = 25,
+    SlippageExceeded = 26,
+    InvalidSwapRouter = 27,
+    SwapRouterNotFound = 28,
+    InvalidLiquidationFeeShare = 29,
+    InvalidLoanLimits = 30,
+    LoanBelowMinimum = 31,
+    DustRemaining = 32,
+    InvalidInterestRateParams = 33,
+    LiquidationFailed = 34,
+    PoolCallFailed = 35,
 }
@@ -2,6 +2,7 @@
 #![allow(clippy::unused_unit)]
 
 mod contract;
+mod dto;
 mod error;
 mod oracle;
 mod storage;
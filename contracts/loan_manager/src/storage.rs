@@ -1,4 +1,6 @@
-use soroban_sdk::{contractevent, contracttype, symbol_short, vec, Address, Env, Vec};
+use soroban_sdk::{
+    contractevent, contracttype, symbol_short, vec, Address, Env, IntoVal, TryFromVal, Val, Vec,
+};
 
 use crate::error::LoanManagerError;
 
@@ -11,9 +13,37 @@ pub enum LoanManagerDataKey {
     PoolAddresses,
     Loan(LoanId),
     LastUpdated,
+    Operator(Address, Address, OperatorScope),
+    Trigger(LoanId),
+    KeeperReward,
+    LiquidationAuction,
+    LiquidatableSince(LoanId),
+    LoanCount,
+    // Position in the global loan index -> loan id
+    LoanIndex(u32),
+    // Loan id -> position in the global loan index
+    LoanPosition(LoanId),
+    // Amount of times a loan was moved within the global loan index
+    LoanIndexGeneration,
+    FixedRateConfig(Address),
+    FixedRate(LoanId),
+    AssetCategory(u32),
+    PoolCategory(Address),
+    SwapRouter,
+    LoanLimits(Address),
+    // Recipient of the protocol's share of liquidation bonuses
+    Treasury,
+    // User -> amount of loans in the user's loan index
+    UserLoanCount(Address),
+    // Position in a user's loan index -> loan nonce
+    UserLoanIndex(Address, u32),
+    // Loan id -> position in the user's loan index
+    UserLoanPosition(LoanId),
+    // Loan id -> timestamp interest was last added to the loan
+    LoanAccruedAt(LoanId),
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 #[contracttype]
 pub struct LoanId {
     pub borrower_address: Address,
@@ -33,7 +63,7 @@ pub struct NewLoan {
     pub last_accrual: i128,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 #[contracttype]
 pub struct Loan {
     pub loan_id: LoanId,
@@ -46,6 +76,152 @@ pub struct Loan {
     pub last_accrual: i128,
 }
 
+/// Operations a borrower can delegate to an operator.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracttype]
+pub enum OperatorScope {
+    Repay,
+    AddCollateral,
+    WithdrawCollateral,
+    Close,
+}
+
+/// What a keeper does to a loan when its trigger fires.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracttype]
+pub enum TriggerAction {
+    // Repay part of the debt with the loan's own collateral.
+    RepayFromCollateral,
+    // Shrink both the debt and the collateral, returning the freed collateral the loan does not
+    // need to get back above the trigger level to the borrower.
+    PartialClose,
+}
+
+/// Stop-loss order registered by a borrower. Fires when the loan's health factor falls to
+/// `health_factor` or below.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct Trigger {
+    pub health_factor: i128,
+    pub action: TriggerAction,
+    // Share of the debt to repay, 1.0 = 10000000_i128
+    pub fraction: i128,
+}
+
+/// Reward paid from the manager's revenue to keepers for each loan they accrue interest on.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct KeeperReward {
+    pub token_address: Address,
+    pub amount_per_loan: i128,
+    // Seconds a loan has to go without accruing before accruing it is rewarded again
+    pub min_interval: u64,
+}
+
+/// Dutch auction for the liquidation bonus. The bonus starts at `min_bonus` when a loan becomes
+/// liquidatable and rises linearly to `max_bonus` over `duration` seconds. 1.0 = 10000000_i128
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct LiquidationAuction {
+    pub min_bonus: i128,
+    pub max_bonus: i128,
+    pub duration: u64,
+}
+
+/// Terms a pool offers for new fixed-rate loans. The locked rate is the pool's current variable rate
+/// plus `premium`, and `penalty_rate` is added on top of it once a loan is past its maturity.
+/// 1.0 = 10000000_i128
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct FixedRateConfig {
+    pub premium: i128,
+    pub penalty_rate: i128,
+}
+
+/// Terms of a fixed-rate loan, locked when the loan is created.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct FixedRate {
+    // Annual interest rate, 1.0 = 10000000_i128
+    pub rate: i128,
+    pub penalty_rate: i128,
+    pub maturity: u64,
+    // Timestamp up to which interest has been added to the loan
+    pub last_updated: u64,
+}
+
+/// Efficiency mode for correlated assets. A loan whose borrow and collateral pools are both in
+/// the category uses these instead of the collateral pool's collateral factor and the regular
+/// liquidation bonus. 1.0 = 10000000_i128
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct AssetCategory {
+    pub collateral_factor: i128,
+    pub liquidation_bonus: i128,
+}
+
+/// Parameters of a pool's kinked interest rate curve. Rates are yearly, 1.0 = 10000000_i128.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct InterestRateParams {
+    // Rate at 0% utilization
+    pub base_rate: i128,
+    // Rate at `panic_threshold` utilization
+    pub rate_at_panic: i128,
+    // Rate at 100% utilization
+    pub max_rate: i128,
+    // Utilization where the steeper part of the curve starts, 1.0 = 10000000_i128
+    pub panic_threshold: i128,
+}
+
+/// Parameters of a pool's adaptive interest rate curve, which moves its rate at target to steer
+/// utilization toward `target_utilization`. Rates are yearly, 1.0 = 10000000_i128.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct AdaptiveRateParams {
+    // Utilization the curve steers toward, 1.0 = 10000000_i128
+    pub target_utilization: i128,
+    pub initial_rate_at_target: i128,
+    pub min_rate_at_target: i128,
+    pub max_rate_at_target: i128,
+    // Yearly growth rate of the rate at target while the pool is fully utilized
+    pub adjustment_speed: i128,
+}
+
+/// Smallest loan a pool takes part in, in the pool's token. Loans below the limits are not
+/// worth liquidating.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[contracttype]
+pub struct LoanLimits {
+    // Smallest debt a loan borrowing from the pool can have outstanding.
+    pub min_borrow: i128,
+    // Smallest collateral a new loan can deposit into the pool.
+    pub min_collateral: i128,
+}
+
+/// Result of a single entry in a batch liquidation.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracttype]
+pub enum LiquidationOutcome {
+    Liquidated,
+    LoanNotFound,
+    NotLiquidatable,
+    AmountOutOfRange,
+    InsufficientBalance,
+    Failed,
+}
+
+/// Result of a single entry in a batch interest accrual.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracttype]
+pub enum AccrualOutcome {
+    Accrued,
+    // Accrued and paid the keeper reward
+    Rewarded,
+    LoanNotFound,
+    Failed,
+}
+
 /* Contract events */
 #[contractevent(topics = ["admin_added"])]
 pub struct EventAdminAdded {
@@ -82,16 +258,238 @@ pub struct EventLoanDeleted {
     pub loan_id: LoanId,
 }
 
+#[contractevent(topics = ["loan_transferred"])]
+pub struct EventLoanTransferred {
+    #[topic]
+    pub old_loan_id: LoanId,
+    #[topic]
+    pub new_loan_id: LoanId,
+}
+
+#[contractevent(topics = ["operator_approved"])]
+pub struct EventOperatorApproved {
+    #[topic]
+    pub owner: Address,
+    #[topic]
+    pub operator: Address,
+    pub scope: OperatorScope,
+}
+
+#[contractevent(topics = ["operator_revoked"])]
+pub struct EventOperatorRevoked {
+    #[topic]
+    pub owner: Address,
+    #[topic]
+    pub operator: Address,
+    pub scope: OperatorScope,
+}
+
+#[contractevent(topics = ["trigger_set"])]
+pub struct EventTriggerSet {
+    #[topic]
+    pub loan_id: LoanId,
+    pub trigger: Trigger,
+}
+
+#[contractevent(topics = ["trigger_removed"])]
+pub struct EventTriggerRemoved {
+    #[topic]
+    pub loan_id: LoanId,
+}
+
+#[contractevent(topics = ["trigger_executed"])]
+pub struct EventTriggerExecuted {
+    #[topic]
+    pub loan_id: LoanId,
+    pub keeper: Address,
+    pub action: TriggerAction,
+    pub debt_repaid: i128,
+    pub collateral_to_keeper: i128,
+    pub keeper_fee: i128,
+    pub collateral_to_borrower: i128,
+}
+
+#[contractevent(topics = ["loan_liquidated"])]
+pub struct EventLoanLiquidated {
+    #[topic]
+    pub loan_id: LoanId,
+    #[topic]
+    pub liquidator: Address,
+    pub debt_repaid: i128,
+    pub collateral_seized: i128,
+    // Part of `collateral_seized` paid on top of the repaid value
+    pub bonus_paid: i128,
+    // Part of `bonus_paid` that went to the protocol instead of the liquidator
+    pub protocol_bonus: i128,
+    pub protocol_fee: i128,
+    pub health_factor_before: i128,
+    pub health_factor_after: i128,
+}
+
+#[contractevent(topics = ["keeper_reward_changed"])]
+pub struct EventKeeperRewardChanged {
+    pub token_address: Address,
+    pub amount_per_loan: i128,
+    pub min_interval: u64,
+}
+
+#[contractevent(topics = ["loans_accrued"])]
+pub struct EventLoansAccrued {
+    #[topic]
+    pub keeper: Address,
+    pub accrued: u32,
+    pub failed: u32,
+    pub reward: i128,
+}
+
+#[contractevent(topics = ["liquidation_auction_changed"])]
+pub struct EventLiquidationAuctionChanged {
+    pub auction: LiquidationAuction,
+}
+
+#[contractevent(topics = ["treasury_set"])]
+pub struct EventTreasurySet {
+    pub treasury: Address,
+}
+
+#[contractevent(topics = ["swap_router_set"])]
+pub struct EventSwapRouterSet {
+    pub router: Address,
+}
+
+#[contractevent(topics = ["fixed_rate_config_changed"])]
+pub struct EventFixedRateConfigChanged {
+    #[topic]
+    pub pool_address: Address,
+    pub config: FixedRateConfig,
+}
+
+#[contractevent(topics = ["fixed_rate_loan_created"])]
+pub struct EventFixedRateLoanCreated {
+    #[topic]
+    pub loan_id: LoanId,
+    pub rate: i128,
+    pub maturity: u64,
+}
+
+#[contractevent(topics = ["asset_category_set"])]
+pub struct EventAssetCategorySet {
+    #[topic]
+    pub category_id: u32,
+    pub category: AssetCategory,
+}
+
+#[contractevent(topics = ["pool_category_set"])]
+pub struct EventPoolCategorySet {
+    #[topic]
+    pub pool_address: Address,
+    pub category_id: Option<u32>,
+}
+
+#[contractevent(topics = ["loan_limits_set"])]
+pub struct EventLoanLimitsSet {
+    #[topic]
+    pub pool_address: Address,
+    pub limits: LoanLimits,
+}
+
+#[contractevent(topics = ["loan_liquidatable"])]
+pub struct EventLoanLiquidatable {
+    #[topic]
+    pub loan_id: LoanId,
+    pub timestamp: u64,
+}
+
+// Loans with a health factor under this are liquidatable. 1.0 = 10000000_i128
+pub(crate) const HEALTH_FACTOR_THRESHOLD: i128 = 10000000;
+
 /* Ledger Thresholds */
 pub(crate) const DAY_IN_LEDGERS: u32 = 17280; // if ledger takes 5 seconds
 
 pub(crate) const POSITIONS_BUMP_AMOUNT: u32 = 30 * DAY_IN_LEDGERS;
 pub(crate) const POSITIONS_LIFETIME_THRESHOLD: u32 = POSITIONS_BUMP_AMOUNT - DAY_IN_LEDGERS;
 
-pub fn write_admin(e: &Env, admin: &Address) {
+pub(crate) const INSTANCE_BUMP_AMOUNT: u32 = 30 * DAY_IN_LEDGERS;
+pub(crate) const INSTANCE_LIFETIME_THRESHOLD: u32 = INSTANCE_BUMP_AMOUNT - DAY_IN_LEDGERS;
+
+/* Ttl bumpers */
+fn extend_persistent<K: IntoVal<Env, Val>>(e: &Env, key: &K) {
     e.storage()
         .persistent()
-        .set(&LoanManagerDataKey::Admin, &admin);
+        .extend_ttl(key, POSITIONS_LIFETIME_THRESHOLD, POSITIONS_BUMP_AMOUNT);
+}
+
+// Read a persistent entry and keep it from being archived.
+fn read_persistent<K, V>(e: &Env, key: &K) -> Option<V>
+where
+    K: IntoVal<Env, Val>,
+    V: TryFromVal<Env, Val>,
+{
+    let value = e.storage().persistent().get(key);
+    if value.is_some() {
+        extend_persistent(e, key);
+    }
+    value
+}
+
+/// Keep the contract instance and code from being archived.
+pub fn extend_instance(e: &Env) {
+    e.storage()
+        .instance()
+        .extend_ttl(INSTANCE_LIFETIME_THRESHOLD, INSTANCE_BUMP_AMOUNT);
+}
+
+/// Extend the TTL of a loan and everything stored for it. Returns the loan, `None` if it does
+/// not exist.
+pub fn bump_loan(e: &Env, loan_id: &LoanId) -> Option<Loan> {
+    let loan = read_loan(e, loan_id)?;
+    read_fixed_rate(e, loan_id);
+    read_trigger(e, loan_id);
+    read_liquidatable_since(e, loan_id);
+    read_loan_accrued_at(e, loan_id);
+    if let Some(position) =
+        read_persistent::<_, u32>(e, &LoanManagerDataKey::LoanPosition(loan_id.clone()))
+    {
+        read_persistent::<_, LoanId>(e, &LoanManagerDataKey::LoanIndex(position));
+    }
+    let user = &loan_id.borrower_address;
+    read_persistent::<_, u64>(e, &(user.clone(), symbol_short!("nonce")));
+    read_user_loan_count(e, user);
+    if let Some(position) =
+        read_persistent::<_, u32>(e, &LoanManagerDataKey::UserLoanPosition(loan_id.clone()))
+    {
+        read_persistent::<_, u64>(
+            e,
+            &LoanManagerDataKey::UserLoanIndex(user.clone(), position),
+        );
+    }
+    Some(loan)
+}
+
+/// Extend the TTL of the manager's configuration, including the settings of every pool.
+pub fn bump_config(e: &Env) {
+    extend_instance(e);
+    let _ = read_admin(e);
+    let _ = read_oracle(e);
+    let _ = read_swap_router(e);
+    read_keeper_reward(e);
+    read_liquidation_auction(e);
+    read_treasury(e);
+    read_loan_count(e);
+    read_loan_index_generation(e);
+    for pool_address in read_pool_addresses(e).iter() {
+        read_loan_limits(e, &pool_address);
+        read_fixed_rate_config(e, &pool_address);
+        if let Some(category_id) = read_pool_category(e, &pool_address) {
+            read_asset_category(e, category_id);
+        }
+    }
+}
+
+pub fn write_admin(e: &Env, admin: &Address) {
+    let key = LoanManagerDataKey::Admin;
+    e.storage().persistent().set(&key, &admin);
+    extend_persistent(e, &key);
     EventAdminAdded {
         admin: admin.clone(),
     }
@@ -103,16 +501,13 @@ pub fn admin_exists(e: &Env) -> bool {
 }
 
 pub fn read_admin(e: &Env) -> Result<Address, LoanManagerError> {
-    e.storage()
-        .persistent()
-        .get(&LoanManagerDataKey::Admin)
-        .ok_or(LoanManagerError::AdminNotFound)
+    read_persistent(e, &LoanManagerDataKey::Admin).ok_or(LoanManagerError::AdminNotFound)
 }
 
 pub fn write_oracle(e: &Env, oracle: &Address) {
-    e.storage()
-        .persistent()
-        .set(&LoanManagerDataKey::Oracle, &oracle);
+    let key = LoanManagerDataKey::Oracle;
+    e.storage().persistent().set(&key, &oracle);
+    extend_persistent(e, &key);
     EventOracleAdded {
         oracle: oracle.clone(),
     }
@@ -120,18 +515,31 @@ pub fn write_oracle(e: &Env, oracle: &Address) {
 }
 
 pub fn read_oracle(e: &Env) -> Result<Address, LoanManagerError> {
-    e.storage()
-        .persistent()
-        .get(&LoanManagerDataKey::Oracle)
-        .ok_or(LoanManagerError::OracleNotFound)
+    read_persistent(e, &LoanManagerDataKey::Oracle).ok_or(LoanManagerError::OracleNotFound)
+}
+
+pub fn write_keeper_reward(e: &Env, reward: &KeeperReward) {
+    let key = LoanManagerDataKey::KeeperReward;
+    e.storage().persistent().set(&key, reward);
+    extend_persistent(e, &key);
+    EventKeeperRewardChanged {
+        token_address: reward.token_address.clone(),
+        amount_per_loan: reward.amount_per_loan,
+        min_interval: reward.min_interval,
+    }
+    .publish(e);
+}
+
+pub fn read_keeper_reward(e: &Env) -> Option<KeeperReward> {
+    read_persistent(e, &LoanManagerDataKey::KeeperReward)
 }
 
 pub fn append_pool_address(e: &Env, pool_address: Address) {
     let mut pool_addresses = read_pool_addresses(e);
     pool_addresses.push_back(pool_address.clone());
-    e.storage()
-        .persistent()
-        .set(&LoanManagerDataKey::PoolAddresses, &pool_addresses);
+    let key = LoanManagerDataKey::PoolAddresses;
+    e.storage().persistent().set(&key, &pool_addresses);
+    extend_persistent(e, &key);
     EventPoolAddressAdded {
         pool_address: pool_address.clone(),
     }
@@ -139,13 +547,41 @@ pub fn append_pool_address(e: &Env, pool_address: Address) {
 }
 
 pub fn read_pool_addresses(e: &Env) -> Vec<Address> {
+    read_persistent(e, &LoanManagerDataKey::PoolAddresses).unwrap_or(vec![&e])
+}
+
+pub fn write_operator(e: &Env, owner: &Address, operator: &Address, scope: OperatorScope) {
+    let key = LoanManagerDataKey::Operator(owner.clone(), operator.clone(), scope);
+    e.storage().persistent().set(&key, &true);
     e.storage()
         .persistent()
-        .get(&LoanManagerDataKey::PoolAddresses)
-        .unwrap_or(vec![&e])
+        .extend_ttl(&key, POSITIONS_LIFETIME_THRESHOLD, POSITIONS_BUMP_AMOUNT);
+    EventOperatorApproved {
+        owner: owner.clone(),
+        operator: operator.clone(),
+        scope,
+    }
+    .publish(e);
+}
+
+pub fn remove_operator(e: &Env, owner: &Address, operator: &Address, scope: OperatorScope) {
+    let key = LoanManagerDataKey::Operator(owner.clone(), operator.clone(), scope);
+    e.storage().persistent().remove(&key);
+    EventOperatorRevoked {
+        owner: owner.clone(),
+        operator: operator.clone(),
+        scope,
+    }
+    .publish(e);
+}
+
+pub fn is_operator(e: &Env, owner: &Address, operator: &Address, scope: OperatorScope) -> bool {
+    let key = LoanManagerDataKey::Operator(owner.clone(), operator.clone(), scope);
+    read_persistent::<_, bool>(e, &key).is_some()
 }
 
 pub fn create_loan(e: &Env, user: Address, new_loan: NewLoan) -> Loan {
+    extend_instance(e);
     let nonce = get_next_loan_nonce(e, &user);
     let loan_id = LoanId {
         borrower_address: user.clone(),
@@ -168,7 +604,9 @@ pub fn create_loan(e: &Env, user: Address, new_loan: NewLoan) -> Loan {
         .persistent()
         .extend_ttl(&key, POSITIONS_LIFETIME_THRESHOLD, POSITIONS_BUMP_AMOUNT);
 
+    write_loan_accrued_at(e, &loan_id, e.ledger().timestamp());
     add_user_loan_id(e, &user, nonce);
+    add_global_loan_id(e, &loan_id);
 
     EventLoanCreated {
         loan_id,
@@ -180,11 +618,13 @@ pub fn create_loan(e: &Env, user: Address, new_loan: NewLoan) -> Loan {
 }
 
 pub fn write_loan(e: &Env, loan_id: &LoanId, loan: &Loan) {
+    extend_instance(e);
     let key = LoanManagerDataKey::Loan(loan_id.clone());
     e.storage().persistent().set(&key, loan);
     e.storage()
         .persistent()
         .extend_ttl(&key, POSITIONS_LIFETIME_THRESHOLD, POSITIONS_BUMP_AMOUNT);
+    update_liquidatable_since(e, loan_id, loan.health_factor);
     EventLoanUpdated {
         loan_id: loan_id.clone(),
         loan: loan.clone(),
@@ -192,16 +632,44 @@ pub fn write_loan(e: &Env, loan_id: &LoanId, loan: &Loan) {
     .publish(e);
 }
 
+// The pools' accrual index has 18 decimals. Loans created before the switch from 7 decimals
+// stored it with 7, and an index only grows, so any value below 1.0 in 18 decimals is legacy.
+const ACCRUAL_ONE: i128 = 1_000_000_000_000_000_000;
+const LEGACY_ACCRUAL_SCALE: i128 = 100_000_000_000;
+
 pub fn read_loan(e: &Env, loan_id: &LoanId) -> Option<Loan> {
     let key = LoanManagerDataKey::Loan(loan_id.clone());
-    e.storage().persistent().get(&key)
+    let loan: Loan = read_persistent(e, &key)?;
+    if loan.last_accrual >= ACCRUAL_ONE {
+        return Some(loan);
+    }
+    Some(Loan {
+        last_accrual: loan.last_accrual * LEGACY_ACCRUAL_SCALE,
+        ..loan
+    })
+}
+
+/// Timestamp interest was last added to a loan, `None` if the loan has not been accrued since
+/// this was tracked.
+pub fn read_loan_accrued_at(e: &Env, loan_id: &LoanId) -> Option<u64> {
+    read_persistent(e, &LoanManagerDataKey::LoanAccruedAt(loan_id.clone()))
+}
+
+pub fn write_loan_accrued_at(e: &Env, loan_id: &LoanId, timestamp: u64) {
+    let key = LoanManagerDataKey::LoanAccruedAt(loan_id.clone());
+    e.storage().persistent().set(&key, &timestamp);
+    extend_persistent(e, &key);
 }
 
 pub fn read_user_loans(e: &Env, user: &Address) -> Vec<Loan> {
-    let nonces = get_user_loan_id_nonces(e, user);
+    read_user_loans_page(e, user, 0, u32::MAX)
+}
+
+/// Read up to `limit` loans of a user, starting at position `cursor` of the user's loan index.
+pub fn read_user_loans_page(e: &Env, user: &Address, cursor: u32, limit: u32) -> Vec<Loan> {
     let mut loans = vec![&e];
 
-    for nonce in nonces.iter() {
+    for nonce in read_user_loan_nonces(e, user, cursor, limit).iter() {
         let loan_id = LoanId {
             borrower_address: user.clone(),
             nonce,
@@ -215,56 +683,479 @@ pub fn read_user_loans(e: &Env, user: &Address) -> Vec<Loan> {
 }
 
 pub fn delete_loan(e: &Env, loan_id: &LoanId) {
+    extend_instance(e);
     let key = LoanManagerDataKey::Loan(loan_id.clone());
     e.storage().persistent().remove(&key);
+    if read_trigger(e, loan_id).is_some() {
+        remove_trigger(e, loan_id);
+    }
+    e.storage()
+        .persistent()
+        .remove(&LoanManagerDataKey::LiquidatableSince(loan_id.clone()));
+    e.storage()
+        .persistent()
+        .remove(&LoanManagerDataKey::FixedRate(loan_id.clone()));
+    e.storage()
+        .persistent()
+        .remove(&LoanManagerDataKey::LoanAccruedAt(loan_id.clone()));
     remove_user_loan_id(e, &loan_id.borrower_address, loan_id.nonce);
+    remove_global_loan_id(e, loan_id);
     EventLoanDeleted {
         loan_id: loan_id.clone(),
     }
     .publish(e);
 }
 
+/// Move a loan to a new owner. The loan is deleted under its old id and created again under
+/// the next nonce of the new owner. Its fixed rate, liquidation auction clock and keeper
+/// accrual time move along, so a transfer does not restart the auction or the reward interval.
+pub fn transfer_loan(e: &Env, loan: Loan, new_owner: Address) -> Loan {
+    let old_loan_id = loan.loan_id.clone();
+    let fixed_rate = read_fixed_rate(e, &old_loan_id);
+    let liquidatable_since = read_liquidatable_since(e, &old_loan_id);
+    let accrued_at = read_loan_accrued_at(e, &old_loan_id);
+    delete_loan(e, &old_loan_id);
+
+    let new_loan = create_loan(
+        e,
+        new_owner.clone(),
+        NewLoan {
+            borrower_address: new_owner,
+            borrowed_amount: loan.borrowed_amount,
+            borrowed_from: loan.borrowed_from,
+            collateral_amount: loan.collateral_amount,
+            collateral_from: loan.collateral_from,
+            health_factor: loan.health_factor,
+            unpaid_interest: loan.unpaid_interest,
+            last_accrual: loan.last_accrual,
+        },
+    );
+
+    if let Some(fixed_rate) = fixed_rate {
+        write_fixed_rate(e, &new_loan.loan_id, &fixed_rate);
+    }
+    if let Some(timestamp) = liquidatable_since {
+        let key = LoanManagerDataKey::LiquidatableSince(new_loan.loan_id.clone());
+        e.storage().persistent().set(&key, &timestamp);
+        e.storage().persistent().extend_ttl(
+            &key,
+            POSITIONS_LIFETIME_THRESHOLD,
+            POSITIONS_BUMP_AMOUNT,
+        );
+    }
+    match accrued_at {
+        Some(timestamp) => write_loan_accrued_at(e, &new_loan.loan_id, timestamp),
+        None => e
+            .storage()
+            .persistent()
+            .remove(&LoanManagerDataKey::LoanAccruedAt(new_loan.loan_id.clone())),
+    }
+
+    EventLoanTransferred {
+        old_loan_id,
+        new_loan_id: new_loan.loan_id.clone(),
+    }
+    .publish(e);
+
+    new_loan
+}
+
+pub fn write_liquidation_auction(e: &Env, auction: &LiquidationAuction) {
+    let key = LoanManagerDataKey::LiquidationAuction;
+    e.storage().persistent().set(&key, auction);
+    extend_persistent(e, &key);
+    EventLiquidationAuctionChanged {
+        auction: auction.clone(),
+    }
+    .publish(e);
+}
+
+pub fn read_liquidation_auction(e: &Env) -> Option<LiquidationAuction> {
+    read_persistent(e, &LoanManagerDataKey::LiquidationAuction)
+}
+
+pub fn write_treasury(e: &Env, treasury: &Address) {
+    let key = LoanManagerDataKey::Treasury;
+    e.storage().persistent().set(&key, treasury);
+    extend_persistent(e, &key);
+    EventTreasurySet {
+        treasury: treasury.clone(),
+    }
+    .publish(e);
+}
+
+pub fn read_treasury(e: &Env) -> Option<Address> {
+    read_persistent(e, &LoanManagerDataKey::Treasury)
+}
+
+pub fn write_swap_router(e: &Env, router: &Address) {
+    let key = LoanManagerDataKey::SwapRouter;
+    e.storage().persistent().set(&key, router);
+    extend_persistent(e, &key);
+    EventSwapRouterSet {
+        router: router.clone(),
+    }
+    .publish(e);
+}
+
+pub fn read_swap_router(e: &Env) -> Result<Address, LoanManagerError> {
+    read_persistent(e, &LoanManagerDataKey::SwapRouter).ok_or(LoanManagerError::SwapRouterNotFound)
+}
+
+pub fn write_fixed_rate_config(e: &Env, pool_address: &Address, config: &FixedRateConfig) {
+    let key = LoanManagerDataKey::FixedRateConfig(pool_address.clone());
+    e.storage().persistent().set(&key, config);
+    extend_persistent(e, &key);
+    EventFixedRateConfigChanged {
+        pool_address: pool_address.clone(),
+        config: config.clone(),
+    }
+    .publish(e);
+}
+
+pub fn read_fixed_rate_config(e: &Env, pool_address: &Address) -> Option<FixedRateConfig> {
+    read_persistent(
+        e,
+        &LoanManagerDataKey::FixedRateConfig(pool_address.clone()),
+    )
+}
+
+pub fn write_fixed_rate(e: &Env, loan_id: &LoanId, fixed_rate: &FixedRate) {
+    let key = LoanManagerDataKey::FixedRate(loan_id.clone());
+    e.storage().persistent().set(&key, fixed_rate);
+    e.storage()
+        .persistent()
+        .extend_ttl(&key, POSITIONS_LIFETIME_THRESHOLD, POSITIONS_BUMP_AMOUNT);
+}
+
+pub fn read_fixed_rate(e: &Env, loan_id: &LoanId) -> Option<FixedRate> {
+    let key = LoanManagerDataKey::FixedRate(loan_id.clone());
+    read_persistent(e, &key)
+}
+
+pub fn write_asset_category(e: &Env, category_id: u32, category: &AssetCategory) {
+    let key = LoanManagerDataKey::AssetCategory(category_id);
+    e.storage().persistent().set(&key, category);
+    extend_persistent(e, &key);
+    EventAssetCategorySet {
+        category_id,
+        category: category.clone(),
+    }
+    .publish(e);
+}
+
+pub fn read_asset_category(e: &Env, category_id: u32) -> Option<AssetCategory> {
+    read_persistent(e, &LoanManagerDataKey::AssetCategory(category_id))
+}
+
+pub fn write_pool_category(e: &Env, pool_address: &Address, category_id: Option<u32>) {
+    let key = LoanManagerDataKey::PoolCategory(pool_address.clone());
+    match category_id {
+        Some(category_id) => {
+            e.storage().persistent().set(&key, &category_id);
+            extend_persistent(e, &key);
+        }
+        None => e.storage().persistent().remove(&key),
+    }
+    EventPoolCategorySet {
+        pool_address: pool_address.clone(),
+        category_id,
+    }
+    .publish(e);
+}
+
+pub fn read_pool_category(e: &Env, pool_address: &Address) -> Option<u32> {
+    read_persistent(e, &LoanManagerDataKey::PoolCategory(pool_address.clone()))
+}
+
+pub fn write_loan_limits(e: &Env, pool_address: &Address, limits: &LoanLimits) {
+    let key = LoanManagerDataKey::LoanLimits(pool_address.clone());
+    e.storage().persistent().set(&key, limits);
+    extend_persistent(e, &key);
+    EventLoanLimitsSet {
+        pool_address: pool_address.clone(),
+        limits: limits.clone(),
+    }
+    .publish(e);
+}
+
+pub fn read_loan_limits(e: &Env, pool_address: &Address) -> LoanLimits {
+    read_persistent(e, &LoanManagerDataKey::LoanLimits(pool_address.clone())).unwrap_or_default()
+}
+
+/// Timestamp of when the loan was first seen liquidatable, if it still is.
+pub fn read_liquidatable_since(e: &Env, loan_id: &LoanId) -> Option<u64> {
+    let key = LoanManagerDataKey::LiquidatableSince(loan_id.clone());
+    read_persistent(e, &key)
+}
+
+// Start the liquidation auction clock when a loan becomes liquidatable and stop it when it recovers.
+fn update_liquidatable_since(e: &Env, loan_id: &LoanId, health_factor: i128) {
+    let key = LoanManagerDataKey::LiquidatableSince(loan_id.clone());
+    let is_liquidatable = health_factor < HEALTH_FACTOR_THRESHOLD;
+    let is_recorded = e.storage().persistent().has(&key);
+
+    if is_liquidatable && !is_recorded {
+        let timestamp = e.ledger().timestamp();
+        e.storage().persistent().set(&key, &timestamp);
+        e.storage().persistent().extend_ttl(
+            &key,
+            POSITIONS_LIFETIME_THRESHOLD,
+            POSITIONS_BUMP_AMOUNT,
+        );
+        EventLoanLiquidatable {
+            loan_id: loan_id.clone(),
+            timestamp,
+        }
+        .publish(e);
+    } else if !is_liquidatable && is_recorded {
+        e.storage().persistent().remove(&key);
+    }
+}
+
+pub fn write_trigger(e: &Env, loan_id: &LoanId, trigger: &Trigger) {
+    let key = LoanManagerDataKey::Trigger(loan_id.clone());
+    e.storage().persistent().set(&key, trigger);
+    e.storage()
+        .persistent()
+        .extend_ttl(&key, POSITIONS_LIFETIME_THRESHOLD, POSITIONS_BUMP_AMOUNT);
+    EventTriggerSet {
+        loan_id: loan_id.clone(),
+        trigger: trigger.clone(),
+    }
+    .publish(e);
+}
+
+pub fn read_trigger(e: &Env, loan_id: &LoanId) -> Option<Trigger> {
+    let key = LoanManagerDataKey::Trigger(loan_id.clone());
+    read_persistent(e, &key)
+}
+
+pub fn remove_trigger(e: &Env, loan_id: &LoanId) {
+    let key = LoanManagerDataKey::Trigger(loan_id.clone());
+    e.storage().persistent().remove(&key);
+    EventTriggerRemoved {
+        loan_id: loan_id.clone(),
+    }
+    .publish(e);
+}
+
+pub fn read_loan_count(e: &Env) -> u32 {
+    read_persistent(e, &LoanManagerDataKey::LoanCount).unwrap_or(0)
+}
+
+pub fn read_loan_index_generation(e: &Env) -> u32 {
+    read_persistent(e, &LoanManagerDataKey::LoanIndexGeneration).unwrap_or(0)
+}
+
+fn bump_loan_index_generation(e: &Env) {
+    let key = LoanManagerDataKey::LoanIndexGeneration;
+    e.storage()
+        .persistent()
+        .set(&key, &read_loan_index_generation(e).wrapping_add(1));
+    e.storage()
+        .persistent()
+        .extend_ttl(&key, POSITIONS_LIFETIME_THRESHOLD, POSITIONS_BUMP_AMOUNT);
+}
+
+/// Add a loan created before the global index existed to the end of the index. Returns false if
+/// the loan does not exist or is already indexed.
+pub fn index_loan(e: &Env, loan_id: &LoanId) -> bool {
+    let position_key = LoanManagerDataKey::LoanPosition(loan_id.clone());
+    if e.storage().persistent().has(&position_key) || read_loan(e, loan_id).is_none() {
+        return false;
+    }
+    add_global_loan_id(e, loan_id);
+    true
+}
+
+/// Read up to `limit` loans from the global index, starting at position `cursor`.
+pub fn read_loans_page(e: &Env, cursor: u32, limit: u32) -> Vec<Loan> {
+    let end = cursor.saturating_add(limit).min(read_loan_count(e));
+    let mut loans = vec![&e];
+
+    for position in cursor..end {
+        let loan_id: Option<LoanId> = read_persistent(e, &LoanManagerDataKey::LoanIndex(position));
+        if let Some(loan) = loan_id.and_then(|loan_id| read_loan(e, &loan_id)) {
+            loans.push_back(loan);
+        }
+    }
+
+    loans
+}
+
+fn write_loan_count(e: &Env, count: u32) {
+    let key = LoanManagerDataKey::LoanCount;
+    e.storage().persistent().set(&key, &count);
+    e.storage()
+        .persistent()
+        .extend_ttl(&key, POSITIONS_LIFETIME_THRESHOLD, POSITIONS_BUMP_AMOUNT);
+}
+
+fn write_loan_position(e: &Env, position: u32, loan_id: &LoanId) {
+    let index_key = LoanManagerDataKey::LoanIndex(position);
+    let position_key = LoanManagerDataKey::LoanPosition(loan_id.clone());
+    e.storage().persistent().set(&index_key, loan_id);
+    e.storage().persistent().set(&position_key, &position);
+    e.storage().persistent().extend_ttl(
+        &index_key,
+        POSITIONS_LIFETIME_THRESHOLD,
+        POSITIONS_BUMP_AMOUNT,
+    );
+    e.storage().persistent().extend_ttl(
+        &position_key,
+        POSITIONS_LIFETIME_THRESHOLD,
+        POSITIONS_BUMP_AMOUNT,
+    );
+}
+
+// Append a loan to the end of the global loan index
+fn add_global_loan_id(e: &Env, loan_id: &LoanId) {
+    let count = read_loan_count(e);
+    write_loan_position(e, count, loan_id);
+    write_loan_count(e, count + 1);
+}
+
+// Remove a loan from the global loan index by moving the last loan into its place. Loans
+// created before the index existed and not yet backfilled with `index_loan` are skipped.
+fn remove_global_loan_id(e: &Env, loan_id: &LoanId) {
+    let position_key = LoanManagerDataKey::LoanPosition(loan_id.clone());
+    let Some(position) = e.storage().persistent().get::<_, u32>(&position_key) else {
+        return;
+    };
+
+    let last_position = read_loan_count(e) - 1;
+    if position != last_position {
+        let last_loan_id: LoanId = e
+            .storage()
+            .persistent()
+            .get(&LoanManagerDataKey::LoanIndex(last_position))
+            .expect("Loan index entry not found");
+        write_loan_position(e, position, &last_loan_id);
+        bump_loan_index_generation(e);
+    }
+
+    e.storage()
+        .persistent()
+        .remove(&LoanManagerDataKey::LoanIndex(last_position));
+    e.storage().persistent().remove(&position_key);
+    write_loan_count(e, last_position);
+}
+
 // Increment and return the next loan nonce for a user
 fn get_next_loan_nonce(e: &Env, user: &Address) -> u64 {
     let key = (user.clone(), symbol_short!("nonce"));
 
-    let prev_nonce = e.storage().persistent().get(&key).unwrap_or(0);
+    let prev_nonce = read_persistent(e, &key).unwrap_or(0);
     let next_nonce = prev_nonce + 1;
 
     e.storage().persistent().set(&key, &next_nonce);
+    extend_persistent(e, &key);
     next_nonce
 }
 
-// Get all loan ID nonces for a user
-pub fn get_user_loan_id_nonces(e: &Env, user: &Address) -> Vec<u64> {
-    let key = (user.clone(), symbol_short!("ids"));
-    e.storage().persistent().get(&key).unwrap_or(vec![&e])
+// Loan nonces of a user as stored before the paginated user loan index. The whole list was
+// rewritten on every open and close. It is moved into the index on the user's next write.
+fn read_legacy_user_loan_ids(e: &Env, user: &Address) -> Option<Vec<u64>> {
+    read_persistent(e, &(user.clone(), symbol_short!("ids")))
 }
 
-// Add a loan ID to user's loan list
-pub fn add_user_loan_id(e: &Env, user: &Address, loan_id: u64) {
-    let mut loan_ids = get_user_loan_id_nonces(e, user);
-    loan_ids.push_back(loan_id);
-    let key = (user.clone(), symbol_short!("ids"));
-    e.storage().persistent().set(&key, &loan_ids);
+/// Amount of loans in a user's loan index.
+pub fn read_user_loan_count(e: &Env, user: &Address) -> u32 {
+    if let Some(nonces) = read_legacy_user_loan_ids(e, user) {
+        return nonces.len();
+    }
+    read_persistent(e, &LoanManagerDataKey::UserLoanCount(user.clone())).unwrap_or(0)
 }
 
-// Remove a loan ID from user's loan list
-pub fn remove_user_loan_id(e: &Env, user: &Address, loan_id: u64) {
-    let nonces = get_user_loan_id_nonces(e, user);
-    let mut new_nonces = vec![&e];
+// Read up to `limit` loan nonces of a user, starting at position `cursor`
+fn read_user_loan_nonces(e: &Env, user: &Address, cursor: u32, limit: u32) -> Vec<u64> {
+    let count = read_user_loan_count(e, user);
+    let end = cursor.saturating_add(limit).min(count);
+    if cursor >= end {
+        return vec![&e];
+    }
+    if let Some(nonces) = read_legacy_user_loan_ids(e, user) {
+        return nonces.slice(cursor..end);
+    }
 
-    for id in nonces.iter() {
-        if id != loan_id {
-            new_nonces.push_back(id);
+    let mut nonces = vec![&e];
+    for position in cursor..end {
+        let key = LoanManagerDataKey::UserLoanIndex(user.clone(), position);
+        if let Some(nonce) = read_persistent(e, &key) {
+            nonces.push_back(nonce);
         }
     }
+    nonces
+}
 
-    let key = (user.clone(), symbol_short!("ids"));
-
-    if new_nonces.is_empty() {
+fn write_user_loan_count(e: &Env, user: &Address, count: u32) {
+    let key = LoanManagerDataKey::UserLoanCount(user.clone());
+    if count == 0 {
         e.storage().persistent().remove(&key);
     } else {
-        e.storage().persistent().set(&key, &new_nonces);
+        e.storage().persistent().set(&key, &count);
+        extend_persistent(e, &key);
     }
 }
+
+fn write_user_loan_position(e: &Env, user: &Address, position: u32, nonce: u64) {
+    let index_key = LoanManagerDataKey::UserLoanIndex(user.clone(), position);
+    let position_key = LoanManagerDataKey::UserLoanPosition(LoanId {
+        borrower_address: user.clone(),
+        nonce,
+    });
+    e.storage().persistent().set(&index_key, &nonce);
+    e.storage().persistent().set(&position_key, &position);
+    extend_persistent(e, &index_key);
+    extend_persistent(e, &position_key);
+}
+
+// Move the nonces of the legacy list into the user loan index
+fn migrate_user_loan_ids(e: &Env, user: &Address) {
+    let Some(nonces) = read_legacy_user_loan_ids(e, user) else {
+        return;
+    };
+    e.storage()
+        .persistent()
+        .remove(&(user.clone(), symbol_short!("ids")));
+    for (position, nonce) in nonces.iter().enumerate() {
+        write_user_loan_position(e, user, position as u32, nonce);
+    }
+    write_user_loan_count(e, user, nonces.len());
+}
+
+// Append a loan nonce to the end of the user's loan index
+fn add_user_loan_id(e: &Env, user: &Address, nonce: u64) {
+    migrate_user_loan_ids(e, user);
+    let count = read_user_loan_count(e, user);
+    write_user_loan_position(e, user, count, nonce);
+    write_user_loan_count(e, user, count + 1);
+}
+
+// Remove a loan nonce from the user's loan index by moving the user's last loan into its place
+fn remove_user_loan_id(e: &Env, user: &Address, nonce: u64) {
+    migrate_user_loan_ids(e, user);
+    let position_key = LoanManagerDataKey::UserLoanPosition(LoanId {
+        borrower_address: user.clone(),
+        nonce,
+    });
+    let Some(position) = e.storage().persistent().get::<_, u32>(&position_key) else {
+        return;
+    };
+
+    let last_position = read_user_loan_count(e, user) - 1;
+    let last_key = LoanManagerDataKey::UserLoanIndex(user.clone(), last_position);
+    if position != last_position {
+        let last_nonce: u64 = e
+            .storage()
+            .persistent()
+            .get(&last_key)
+            .expect("User loan index entry not found");
+        write_user_loan_position(e, user, position, last_nonce);
+    }
+
+    e.storage().persistent().remove(&last_key);
+    e.storage().persistent().remove(&position_key);
+    write_user_loan_count(e, user, last_position);
+}
@@ -1,10 +1,17 @@
-use crate::dto::PoolState;
+use crate::dto::{InvariantReport, PoolState};
 use crate::error::LoanPoolError;
-use crate::interest::{self, get_interest};
-use crate::storage::{Currency, PoolStatus, Positions};
+use crate::interest;
+use crate::storage::{
+    AdaptiveRateParams, Currency, InterestRateModel, InterestRateParams, PoolStatus, Positions,
+};
 use crate::{positions, storage};
 
-use soroban_sdk::{contract, contractimpl, contractmeta, token, Address, BytesN, Env};
+use soroban_sdk::token::TokenInterface;
+use soroban_sdk::{
+    contract, contractimpl, contractmeta, panic_with_error, token, Address, BytesN, Env,
+    MuxedAddress, String, Vec,
+};
+use soroban_token_sdk::events::{Approve, Burn, Mint, Transfer};
 
 // Metadata that is added on to the WASM custom section
 contractmeta!(
@@ -12,6 +19,8 @@ contractmeta!(
     val = "Lending pool with variable interest rate."
 );
 
+const FIXED_POINT_ONE: i128 = 10_000_000;
+
 #[contract]
 struct LoanPoolContract;
 
@@ -31,10 +40,11 @@ impl LoanPoolContract {
         storage::write_total_shares(&e, 0);
         storage::write_total_balance(&e, 0);
         storage::write_available_balance(&e, 0);
-        storage::write_accrual(&e, 10_000_000); // Default initial accrual value.
+        storage::write_accrual(&e, interest::ACCRUAL_ONE); // Default initial accrual value.
         storage::write_accrual_last_updated(&e, e.ledger().timestamp());
         storage::change_interest_rate_multiplier(&e, 1); // Temporary parameter
         storage::change_pool_status(&e, PoolStatus::Healthy);
+        storage::write_position_totals_tracked(&e);
     }
 
     pub fn upgrade(e: Env, new_wasm_hash: BytesN<32>) -> Result<(), LoanPoolError> {
@@ -53,6 +63,75 @@ impl LoanPoolContract {
         Ok(())
     }
 
+    /// Replace the pool's interest rate curve. Interest up to now is accrued at the old curve.
+    pub fn set_interest_rate_params(
+        e: Env,
+        params: InterestRateParams,
+    ) -> Result<(), LoanPoolError> {
+        let loan_manager_addr = storage::read_loan_manager_addr(&e)?;
+        loan_manager_addr.require_auth();
+
+        interest::validate_params(&params)?;
+        Self::add_interest_to_accrual(e.clone())?;
+        storage::write_interest_rate_params(&e, params);
+        if storage::read_interest_rate_model(&e) != InterestRateModel::Kinked {
+            storage::write_interest_rate_model(&e, InterestRateModel::Kinked);
+        }
+        Ok(())
+    }
+
+    pub fn get_interest_rate_params(e: Env) -> InterestRateParams {
+        storage::read_interest_rate_params(&e)
+    }
+
+    /// Switch the pool to the adaptive interest rate curve. The rate at target starts from
+    /// `initial_rate_at_target`, also when the pool already used the adaptive curve.
+    pub fn set_adaptive_rate_params(
+        e: Env,
+        params: AdaptiveRateParams,
+    ) -> Result<(), LoanPoolError> {
+        let loan_manager_addr = storage::read_loan_manager_addr(&e)?;
+        loan_manager_addr.require_auth();
+
+        interest::validate_adaptive_params(&params)?;
+        Self::add_interest_to_accrual(e.clone())?;
+        storage::write_rate_at_target(&e, params.initial_rate_at_target);
+        storage::write_adaptive_rate_params(&e, params);
+        if storage::read_interest_rate_model(&e) != InterestRateModel::Adaptive {
+            storage::write_interest_rate_model(&e, InterestRateModel::Adaptive);
+        }
+        Ok(())
+    }
+
+    pub fn get_adaptive_rate_params(e: Env) -> Option<AdaptiveRateParams> {
+        storage::read_adaptive_rate_params(&e)
+    }
+
+    pub fn get_interest_rate_model(e: Env) -> InterestRateModel {
+        storage::read_interest_rate_model(&e)
+    }
+
+    /// Current rate at target of the adaptive curve, `None` if it was never selected.
+    pub fn get_rate_at_target(e: Env) -> Option<i128> {
+        storage::read_rate_at_target(&e)
+    }
+
+    /// Set the share of the liquidation bonus that goes to the protocol. 1.0 = 10000000_i128
+    pub fn set_liquidation_fee_share(e: Env, share: i128) -> Result<(), LoanPoolError> {
+        let loan_manager_addr = storage::read_loan_manager_addr(&e)?;
+        loan_manager_addr.require_auth();
+
+        if !(0..=FIXED_POINT_ONE).contains(&share) {
+            return Err(LoanPoolError::InvalidLiquidationFeeShare);
+        }
+        storage::write_liquidation_fee_share(&e, share);
+        Ok(())
+    }
+
+    pub fn get_liquidation_fee_share(e: Env) -> i128 {
+        storage::read_liquidation_fee_share(&e)
+    }
+
     /// Deposits token. Also, mints pool shares for the "user" Identifier.
     pub fn deposit(e: Env, user: Address, amount: i128) -> Result<i128, LoanPoolError> {
         user.require_auth();
@@ -97,6 +176,12 @@ impl LoanPoolContract {
             storage::adjust_available_balance(&e, amount)?;
             storage::adjust_total_shares(&e, shares_issued)?;
             storage::adjust_total_balance(&e, amount)?;
+            Mint {
+                to: user,
+                to_muxed_id: None,
+                amount: shares_issued,
+            }
+            .publish(&e);
 
             Ok(amount)
         }
@@ -160,6 +245,11 @@ impl LoanPoolContract {
             liabilities,
             collateral,
         )?;
+        Burn {
+            from: user.clone(),
+            amount: shares_to_decrease,
+        }
+        .publish(&e);
 
         // Transfer tokens from the pool to the user
         let token_address = &storage::read_currency(&e)?.token_address;
@@ -270,8 +360,34 @@ impl LoanPoolContract {
         Ok(amount)
     }
 
+    /// Add collateral to a loan owner's positions. Tokens are pulled from the payer.
+    pub fn add_collateral(
+        e: Env,
+        payer: Address,
+        amount: i128,
+        loan_owner: Address,
+    ) -> Result<i128, LoanPoolError> {
+        let loan_manager_addr = storage::read_loan_manager_addr(&e)?;
+        loan_manager_addr.require_auth();
+        assert!(amount > 0, "Amount must be positive!");
+
+        let pool_status = storage::read_pool_status(&e)?;
+        if pool_status == PoolStatus::Frozen {
+            return Err(LoanPoolError::WrongStatus);
+        }
+
+        Self::add_interest_to_accrual(e.clone())?;
+
+        let token_address = &storage::read_currency(&e)?.token_address;
+        let client = token::Client::new(&e, token_address);
+        client.transfer(&payer, e.current_contract_address(), &amount);
+
+        positions::increase_positions(&e, loan_owner, 0, 0, amount)?;
+
+        Ok(amount)
+    }
+
     pub fn withdraw_collateral(e: Env, user: Address, amount: i128) -> Result<i128, LoanPoolError> {
-        user.require_auth();
         Self::add_interest_to_accrual(e.clone())?;
 
         let loan_manager_addr = storage::read_loan_manager_addr(&e)?;
@@ -289,46 +405,53 @@ impl LoanPoolContract {
         Ok(amount)
     }
 
-    pub fn add_interest_to_accrual(e: Env) -> Result<(), LoanPoolError> {
-        const DECIMAL: i128 = 10000000;
-        const SECONDS_IN_YEAR: u64 = 31_556_926;
+    /// Move `amount` of the loan owner's collateral to `to`, e.g. to a swap router selling it on
+    /// the owner's behalf or a keeper taking a fee.
+    pub fn transfer_collateral_out(
+        e: Env,
+        to: Address,
+        amount: i128,
+        loan_owner: Address,
+    ) -> Result<i128, LoanPoolError> {
+        Self::add_interest_to_accrual(e.clone())?;
 
-        let current_timestamp = e.ledger().timestamp();
-        let accrual = storage::read_accrual(&e)?;
-        let accrual_last_update = storage::read_accrual_last_updated(&e)?;
-        let ledgers_since_update = current_timestamp
-            .checked_sub(accrual_last_update)
-            .ok_or(LoanPoolError::OverOrUnderFlow)?;
-        let ledger_ratio: i128 = (i128::from(ledgers_since_update))
-            .checked_mul(DECIMAL)
-            .ok_or(LoanPoolError::OverOrUnderFlow)?
-            .checked_div(i128::from(SECONDS_IN_YEAR))
-            .ok_or(LoanPoolError::OverOrUnderFlow)?;
+        let loan_manager_addr = storage::read_loan_manager_addr(&e)?;
+        loan_manager_addr.require_auth();
+        assert!(amount > 0, "Amount must be positive!");
 
-        let interest_rate: i128 = get_interest(e.clone())?;
-        let interest_amount_in_year: i128 = accrual
-            .checked_mul(interest_rate)
-            .ok_or(LoanPoolError::OverOrUnderFlow)?
-            .checked_div(DECIMAL)
-            .ok_or(LoanPoolError::OverOrUnderFlow)?;
-        let interest_since_update: i128 = interest_amount_in_year
-            .checked_mul(ledger_ratio)
-            .ok_or(LoanPoolError::OverOrUnderFlow)?
-            .checked_div(DECIMAL)
-            .ok_or(LoanPoolError::OverOrUnderFlow)?;
-        let new_accrual: i128 = accrual
-            .checked_add(interest_since_update)
-            .ok_or(LoanPoolError::OverOrUnderFlow)?;
+        let client = token::Client::new(&e, &storage::read_currency(&e)?.token_address);
+        client.transfer(&e.current_contract_address(), &to, &amount);
+
+        positions::decrease_positions(&e, loan_owner, 0, 0, amount)?;
+        Ok(amount)
+    }
+
+    pub fn add_interest_to_accrual(e: Env) -> Result<(), LoanPoolError> {
+        let current_timestamp = e.ledger().timestamp();
+        let new_accrual = interest::calculate_accrual(&e)?;
+        interest::update_rate_at_target(&e)?;
 
         storage::write_accrual_last_updated(&e, current_timestamp);
         storage::write_accrual(&e, new_accrual);
         Ok(())
     }
 
+    /// Extend the TTL of the pool and the positions of `users` so that they are not archived.
+    /// Anyone can call this.
+    pub fn bump(e: Env, users: Vec<Address>) {
+        storage::bump(&e, &users);
+    }
+
     pub fn get_accrual(e: &Env) -> Result<i128, LoanPoolError> {
         storage::read_accrual(e)
     }
 
+    /// Accrual as it would be after `add_interest_to_accrual` at the current ledger timestamp,
+    /// without writing it.
+    pub fn get_current_accrual(e: &Env) -> Result<i128, LoanPoolError> {
+        interest::calculate_accrual(e)
+    }
+
     pub fn get_collateral_factor(e: &Env) -> Result<i128, LoanPoolError> {
         storage::read_collateral_factor(e)
     }
@@ -368,6 +491,43 @@ impl LoanPoolContract {
         })
     }
 
+    /// Check the pool's accounting against the token balance it holds. Nothing is written.
+    pub fn check_invariants(e: Env) -> Result<InvariantReport, LoanPoolError> {
+        let token_address = storage::read_currency(&e)?.token_address;
+        let token_balance =
+            token::Client::new(&e, &token_address).balance(&e.current_contract_address());
+        let total_balance_tokens = storage::read_total_balance(&e)?;
+        let available_balance_tokens = storage::read_available_balance(&e)?;
+        let total_collateral = storage::read_total_collateral(&e);
+        let total_balance_shares = storage::read_total_shares(&e)?;
+        let total_receivable_shares = storage::read_total_receivable_shares(&e);
+
+        let balance_covers_holdings = total_collateral.map(|total_collateral| {
+            available_balance_tokens
+                .checked_add(total_collateral)
+                .is_some_and(|held| token_balance >= held)
+        });
+        let total_covers_available = total_balance_tokens >= available_balance_tokens;
+        let shares_consistent = total_receivable_shares.map(|total_receivable_shares| {
+            total_balance_shares >= 0 && total_balance_shares == total_receivable_shares
+        });
+
+        Ok(InvariantReport {
+            token_balance,
+            total_balance_tokens,
+            available_balance_tokens,
+            total_collateral,
+            total_balance_shares,
+            total_receivable_shares,
+            balance_covers_holdings,
+            total_covers_available,
+            shares_consistent,
+            holds: balance_covers_holdings != Some(false)
+                && total_covers_available
+                && shares_consistent != Some(false),
+        })
+    }
+
     pub fn increase_liabilities(e: Env, user: Address, amount: i128) -> Result<(), LoanPoolError> {
         let loan_manager_addr = storage::read_loan_manager_addr(&e)?;
         loan_manager_addr.require_auth();
@@ -378,9 +538,10 @@ impl LoanPoolContract {
 
     pub fn repay(
         e: Env,
-        user: Address,
+        payer: Address,
         amount: i128,
         unpaid_interest: i128,
+        loan_owner: Address,
     ) -> Result<(), LoanPoolError> {
         let loan_manager_addr = storage::read_loan_manager_addr(&e)?;
         loan_manager_addr.require_auth();
@@ -411,11 +572,11 @@ impl LoanPoolContract {
             .ok_or(LoanPoolError::OverOrUnderFlow)?;
 
         let client = token::Client::new(&e, &storage::read_currency(&e)?.token_address);
-        client.transfer(&user, e.current_contract_address(), &amount_to_storage);
-        client.transfer(&user, &loan_manager_addr, &amount_to_admin);
+        client.transfer(&payer, e.current_contract_address(), &amount_to_storage);
+        client.transfer(&payer, &loan_manager_addr, &amount_to_admin);
 
         // Get current user liabilities to ensure we don't decrease by more than they have
-        let user_positions = storage::read_positions(&e, &user);
+        let user_positions = storage::read_positions(&e, &loan_owner);
         let current_liabilities = user_positions.liabilities;
 
         // Only decrease liabilities by the minimum of principal_paid and current_liabilities
@@ -425,7 +586,7 @@ impl LoanPoolContract {
             principal_paid
         };
 
-        positions::decrease_positions(&e, user, 0, liabilities_to_decrease, 0)?;
+        positions::decrease_positions(&e, loan_owner, 0, liabilities_to_decrease, 0)?;
 
         // All net paid funds (principal + interest - admin) increase available liquidity
         storage::adjust_available_balance(&e, amount - amount_to_admin)?;
@@ -437,10 +598,11 @@ impl LoanPoolContract {
 
     pub fn repay_and_close(
         e: Env,
-        user: Address,
+        payer: Address,
         borrowed_amount: i128,
         max_allowed_amount: i128,
         unpaid_interest: i128,
+        loan_owner: Address,
     ) -> Result<(), LoanPoolError> {
         let loan_manager_addr = storage::read_loan_manager_addr(&e)?;
         loan_manager_addr.require_auth();
@@ -463,21 +625,46 @@ impl LoanPoolContract {
             .ok_or(LoanPoolError::OverOrUnderFlow)?;
 
         let client = token::Client::new(&e, &storage::read_currency(&e)?.token_address);
-        client.transfer(&user, e.current_contract_address(), &max_allowed_amount);
+        client.transfer(&payer, e.current_contract_address(), &max_allowed_amount);
         client.transfer(
             &e.current_contract_address(),
             &loan_manager_addr,
             &amount_to_admin,
         );
-        client.transfer(&e.current_contract_address(), &user, &amount_to_user);
+        client.transfer(&e.current_contract_address(), &payer, &amount_to_user);
 
-        let user_liabilities = storage::read_positions(&e, &user).liabilities;
-        positions::decrease_positions(&e, user, 0, user_liabilities, 0)?;
+        let user_liabilities = storage::read_positions(&e, &loan_owner).liabilities;
+        positions::decrease_positions(&e, loan_owner, 0, user_liabilities, 0)?;
         storage::adjust_available_balance(&e, borrowed_amount - amount_to_admin)?;
         storage::adjust_total_balance(&e, unpaid_interest - amount_to_admin)?;
         Ok(())
     }
 
+    /// Move liabilities and collateral of a loan from one user to another.
+    pub fn transfer_positions(
+        e: Env,
+        from: Address,
+        to: Address,
+        liabilities: i128,
+        collateral: i128,
+    ) -> Result<(), LoanPoolError> {
+        let loan_manager_addr = storage::read_loan_manager_addr(&e)?;
+        loan_manager_addr.require_auth();
+
+        // Positions are tracked per user rather than per loan, so never move more liabilities
+        // than the user has.
+        let current_liabilities = storage::read_positions(&e, &from).liabilities;
+        let liabilities_to_move = if liabilities > current_liabilities {
+            current_liabilities
+        } else {
+            liabilities
+        };
+
+        positions::decrease_positions(&e, from, 0, liabilities_to_move, collateral)?;
+        positions::increase_positions(&e, to, 0, liabilities_to_move, collateral)?;
+        Ok(())
+    }
+
     pub fn liquidate(
         e: Env,
         user: Address,
@@ -495,11 +682,12 @@ impl LoanPoolContract {
 
         Self::add_interest_to_accrual(e.clone())?;
 
-        let amount_to_admin = if amount < unpaid_interest {
-            amount / 10
+        let interest_paid = if amount < unpaid_interest {
+            amount
         } else {
-            unpaid_interest / 10
+            unpaid_interest
         };
+        let amount_to_admin = interest_paid / 10;
 
         let amount_to_storage = amount
             .checked_sub(amount_to_admin)
@@ -511,37 +699,208 @@ impl LoanPoolContract {
 
         positions::decrease_positions(&e, loan_owner, 0, amount, 0)?;
         storage::adjust_available_balance(&e, amount_to_storage)?;
+        // Like in `repay`, the interest net of the admin fee is the pool's revenue
+        storage::adjust_total_balance(&e, interest_paid - amount_to_admin)?;
         Ok(())
     }
 
+    /// Move `amount_collateral` of the loan owner's collateral out of the pool. `bonus` is the
+    /// part of it paid to the liquidator on top of the repaid value, and the protocol's share of
+    /// the bonus goes to `protocol_recipient` instead of `user`. Returns the protocol's share.
     pub fn liquidate_transfer_collateral(
         e: Env,
         user: Address,
         amount_collateral: i128,
+        bonus: i128,
         loan_owner: Address,
-    ) -> Result<(), LoanPoolError> {
+        protocol_recipient: Address,
+    ) -> Result<i128, LoanPoolError> {
         let loan_manager_addr = storage::read_loan_manager_addr(&e)?;
         loan_manager_addr.require_auth();
 
+        let protocol_bonus = Self::protocol_bonus(&e, bonus)?;
+        let amount_to_user = amount_collateral
+            .checked_sub(protocol_bonus)
+            .ok_or(LoanPoolError::OverOrUnderFlow)?;
+
         let client = token::Client::new(&e, &storage::read_currency(&e)?.token_address);
-        client.transfer(&e.current_contract_address(), &user, &amount_collateral);
+        client.transfer(&e.current_contract_address(), &user, &amount_to_user);
+        if protocol_bonus > 0 {
+            client.transfer(
+                &e.current_contract_address(),
+                &protocol_recipient,
+                &protocol_bonus,
+            );
+        }
+        if bonus > 0 {
+            storage::EventLiquidationBonusSplit {
+                loan_owner: loan_owner.clone(),
+                liquidator: user,
+                liquidator_bonus: bonus - protocol_bonus,
+                protocol_bonus,
+            }
+            .publish(&e);
+        }
 
         positions::decrease_positions(&e, loan_owner, 0, 0, amount_collateral)?;
+        Ok(protocol_bonus)
+    }
+
+    // The protocol's share of a liquidation bonus of `bonus`, rounded down. The loan manager
+    // mirrors this when it quotes a liquidation.
+    fn protocol_bonus(e: &Env, bonus: i128) -> Result<i128, LoanPoolError> {
+        bonus
+            .checked_mul(storage::read_liquidation_fee_share(e))
+            .map(|share| share / FIXED_POINT_ONE)
+            .ok_or(LoanPoolError::OverOrUnderFlow)
+    }
+
+    // Move pool shares between lenders. Shares can not move while the pool is frozen, like
+    // the tokens they are worth.
+    fn transfer_shares(
+        e: &Env,
+        from: &Address,
+        to: &Address,
+        amount: i128,
+    ) -> Result<(), LoanPoolError> {
+        if amount < 0 {
+            return Err(LoanPoolError::InvalidShareAmount);
+        }
+        if storage::read_pool_status(e)? == PoolStatus::Frozen {
+            return Err(LoanPoolError::WrongStatus);
+        }
+        if storage::read_positions(e, from).receivable_shares < amount {
+            return Err(LoanPoolError::InsufficientShares);
+        }
+
+        positions::decrease_positions(e, from.clone(), amount, 0, 0)?;
+        positions::increase_positions(e, to.clone(), amount, 0, 0)?;
         Ok(())
     }
+
+    // `prefix` + `value` + `suffix`, used to name the share token after the pool's token
+    fn share_metadata(e: &Env, prefix: &str, value: String, suffix: &str) -> String {
+        let mut buffer = [0u8; 128];
+        let value_len = value.len() as usize;
+        let len = prefix.len() + value_len + suffix.len();
+        if len > buffer.len() {
+            panic_with_error!(e, LoanPoolError::Currency);
+        }
+        buffer[..prefix.len()].copy_from_slice(prefix.as_bytes());
+        value.copy_into_slice(&mut buffer[prefix.len()..prefix.len() + value_len]);
+        buffer[prefix.len() + value_len..len].copy_from_slice(suffix.as_bytes());
+        String::from_bytes(e, &buffer[..len])
+    }
+
+    fn pool_token(e: &Env) -> token::Client<'_> {
+        let currency =
+            storage::read_currency(e).unwrap_or_else(|error| panic_with_error!(e, error));
+        token::Client::new(e, &currency.token_address)
+    }
+}
+
+/// Lenders' pool shares as a SEP-41 token. A balance is the holder's `receivable_shares` and the
+/// total supply is `TotalBalanceShares`. Shares are redeemed with `withdraw`, they can not be
+/// burned.
+#[contractimpl]
+impl TokenInterface for LoanPoolContract {
+    fn allowance(e: Env, from: Address, spender: Address) -> i128 {
+        storage::read_allowance(&e, &from, &spender).amount
+    }
+
+    fn approve(e: Env, from: Address, spender: Address, amount: i128, expiration_ledger: u32) {
+        from.require_auth();
+
+        if amount < 0 {
+            panic_with_error!(&e, LoanPoolError::InvalidShareAmount);
+        }
+        storage::write_allowance(&e, &from, &spender, amount, expiration_ledger)
+            .unwrap_or_else(|error| panic_with_error!(&e, error));
+        Approve {
+            from,
+            spender,
+            amount,
+            expiration_ledger,
+        }
+        .publish(&e);
+    }
+
+    fn balance(e: Env, id: Address) -> i128 {
+        storage::read_positions(&e, &id).receivable_shares
+    }
+
+    fn transfer(e: Env, from: Address, to: MuxedAddress, amount: i128) {
+        from.require_auth();
+
+        let to_address = to.address();
+        Self::transfer_shares(&e, &from, &to_address, amount)
+            .unwrap_or_else(|error| panic_with_error!(&e, error));
+        Transfer {
+            from,
+            to: to_address,
+            to_muxed_id: to.id(),
+            amount,
+        }
+        .publish(&e);
+    }
+
+    fn transfer_from(e: Env, spender: Address, from: Address, to: Address, amount: i128) {
+        spender.require_auth();
+
+        storage::spend_allowance(&e, &from, &spender, amount)
+            .and_then(|_| Self::transfer_shares(&e, &from, &to, amount))
+            .unwrap_or_else(|error| panic_with_error!(&e, error));
+        Transfer {
+            from,
+            to,
+            to_muxed_id: None,
+            amount,
+        }
+        .publish(&e);
+    }
+
+    fn burn(e: Env, _from: Address, _amount: i128) {
+        panic_with_error!(&e, LoanPoolError::SharesNotBurnable);
+    }
+
+    fn burn_from(e: Env, _spender: Address, _from: Address, _amount: i128) {
+        panic_with_error!(&e, LoanPoolError::SharesNotBurnable);
+    }
+
+    fn decimals(e: Env) -> u32 {
+        Self::pool_token(&e).decimals()
+    }
+
+    fn name(e: Env) -> String {
+        let name = Self::pool_token(&e).name();
+        Self::share_metadata(&e, "", name, " pool share")
+    }
+
+    fn symbol(e: Env) -> String {
+        let symbol = Self::pool_token(&e).symbol();
+        Self::share_metadata(&e, "s", symbol, "")
+    }
 }
 
 #[cfg(test)]
 mod test {
+    extern crate std;
+
     use super::*;
     use soroban_sdk::{
-        testutils::{Address as _, Ledger},
+        events::Event as _,
+        testutils::{Address as _, Events as _, Ledger},
         token::{Client as TokenClient, StellarAssetClient},
-        Env, Symbol,
+        Bytes, Env, Symbol, I256,
     };
 
     const TEST_LIQUIDATION_THRESHOLD: i128 = 8_000_000;
 
+    fn assert_invariants(contract_client: &LoanPoolContractClient) {
+        let report = contract_client.check_invariants();
+        assert!(report.holds, "{report:?}");
+    }
+
     #[test]
     fn initialize() {
         let e = Env::default();
@@ -566,6 +925,36 @@ mod test {
             &currency,
             &TEST_LIQUIDATION_THRESHOLD,
         );
+        assert_invariants(&contract_client);
+    }
+
+    #[test]
+    fn invariants_without_position_totals() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths();
+        let contract_client = setup_borrowed_pool(&e);
+        // A pool that had positions before the position totals were introduced.
+        e.as_contract(&contract_client.address, || {
+            let storage = e.storage().persistent();
+            storage.remove(&storage::PoolDataKey::PositionTotalsTracked);
+            storage.remove(&storage::PoolDataKey::TotalCollateral);
+            storage.remove(&storage::PoolDataKey::TotalReceivableShares);
+        });
+
+        // ACT
+        e.as_contract(&contract_client.address, || {
+            storage::write_positions(&e, Address::generate(&e), 0, 0, 100)
+        });
+        let report = contract_client.check_invariants();
+
+        // ASSERT
+        assert_eq!(report.total_collateral, None);
+        assert_eq!(report.total_receivable_shares, None);
+        assert_eq!(report.balance_covers_holdings, None);
+        assert_eq!(report.shares_consistent, None);
+        assert!(report.total_covers_available);
+        assert!(report.holds);
     }
 
     #[test]
@@ -597,6 +986,7 @@ mod test {
         let result: i128 = contract_client.deposit(&user, &amount);
 
         assert_eq!(result, amount);
+        assert_invariants(&contract_client);
     }
 
     #[test]
@@ -635,6 +1025,7 @@ mod test {
         // Did the funds move?
         assert_eq!(token_client.balance(&depositer), 0);
         assert_eq!(token_client.balance(&borrower), 50);
+        assert_invariants(&contract_client);
     }
 
     #[test]
@@ -668,6 +1059,7 @@ mod test {
         assert_eq!(result, amount);
 
         contract_client.withdraw(&user, &amount);
+        assert_invariants(&contract_client);
     }
 
     #[test]
@@ -684,7 +1076,7 @@ mod test {
         };
 
         let user = Address::generate(&e);
-        stellar_asset.mint(&user, &2000);
+        stellar_asset.mint(&user, &3000);
 
         let contract_id = e.register(LoanPoolContract, ());
         let contract_client = LoanPoolContractClient::new(&e, &contract_id);
@@ -698,12 +1090,61 @@ mod test {
         let borrowed_amount = 1000_i128;
         let max_allowed_amount = 1050_i128;
         let unpaid_interest = 20_i128;
+        contract_client.deposit(&user, &2000);
+        contract_client.borrow(&user, &borrowed_amount);
         contract_client.repay_and_close(
             &user,
             &borrowed_amount,
             &max_allowed_amount,
             &unpaid_interest,
+            &user,
         );
+
+        assert_invariants(&contract_client);
+    }
+
+    #[test]
+    fn liquidate_credits_interest() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths_allowing_non_root_auth();
+
+        let admin = Address::generate(&e);
+        let token = e.register_stellar_asset_contract_v2(admin.clone());
+        let stellar_asset = StellarAssetClient::new(&e, &token.address());
+        let token_client = TokenClient::new(&e, &token.address());
+        let currency = Currency {
+            token_address: token.address(),
+            ticker: Symbol::new(&e, "XLM"),
+        };
+
+        let depositor = Address::generate(&e);
+        let borrower = Address::generate(&e);
+        let liquidator = Address::generate(&e);
+        stellar_asset.mint(&depositor, &2000);
+        stellar_asset.mint(&liquidator, &500);
+
+        let loan_manager = Address::generate(&e);
+        let contract_id = e.register(LoanPoolContract, ());
+        let contract_client = LoanPoolContractClient::new(&e, &contract_id);
+        contract_client.initialize(&loan_manager, &currency, &TEST_LIQUIDATION_THRESHOLD);
+        contract_client.deposit(&depositor, &2000);
+        contract_client.borrow(&borrower, &1000);
+
+        // ACT
+        // 100 of the repaid 500 is interest, a tenth of it goes to the loan manager.
+        contract_client.liquidate(&liquidator, &500, &100, &borrower);
+
+        // ASSERT
+        let state = contract_client.get_pool_state();
+        assert_eq!(state.available_balance_tokens, 1490);
+        assert_eq!(state.total_balance_tokens, 2090);
+        assert_eq!(token_client.balance(&loan_manager), 10);
+        assert_eq!(
+            contract_client.get_user_positions(&borrower).liabilities,
+            500
+        );
+        assert_invariants(&contract_client);
     }
 
     #[test]
@@ -733,6 +1174,8 @@ mod test {
             &TEST_LIQUIDATION_THRESHOLD,
         );
 
+        assert_invariants(&contract_client);
+
         contract_client.deposit(&user, &amount);
     }
 
@@ -767,6 +1210,8 @@ mod test {
 
         assert_eq!(result, amount);
 
+        assert_invariants(&contract_client);
+
         contract_client.withdraw(&user, &(amount * 2));
     }
     #[test]
@@ -805,6 +1250,8 @@ mod test {
 
         contract_client.borrow(&user2, &500);
 
+        assert_invariants(&contract_client);
+
         let withdraw_result = contract_client.withdraw(&user, &amount);
 
         assert_eq!(withdraw_result, contract_client.get_pool_state());
@@ -857,12 +1304,12 @@ mod test {
         });
 
         contract_client.add_interest_to_accrual();
-        // value of 12980000 is expected as usage is 999/1000 and max interest rate is 30%
-        // Time in ledgers is shifted by ~one year.
-        assert_eq!(12_980_000, contract_client.get_accrual());
+        // Usage is 999/1000 so the interest rate is 29.8%, compounded every second for ~one year.
+        assert_eq!(1_347_161_785_935_850_759, contract_client.get_accrual());
 
         contract_client.add_interest_to_accrual();
-        assert_eq!(12_980_000, contract_client.get_accrual());
+        assert_eq!(1_347_161_785_935_850_759, contract_client.get_accrual());
+        assert_invariants(&contract_client);
     }
     #[test]
     fn add_accrual_half_usage() {
@@ -911,6 +1358,642 @@ mod test {
         });
 
         contract_client.add_interest_to_accrual();
-        assert_eq!(10_644_440, contract_client.get_accrual());
+        assert_eq!(1_066_565_848_752_916_983, contract_client.get_accrual());
+        assert_invariants(&contract_client);
+    }
+
+    #[test]
+    fn interest_rate_params() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths();
+
+        let admin = Address::generate(&e);
+        let token = e.register_stellar_asset_contract_v2(admin.clone());
+        let stellar_asset = StellarAssetClient::new(&e, &token.address());
+        let currency = Currency {
+            token_address: token.address(),
+            ticker: Symbol::new(&e, "USDC"),
+        };
+
+        let user = Address::generate(&e);
+        stellar_asset.mint(&user, &1000);
+
+        let contract_id = e.register(LoanPoolContract, ());
+        let contract_client = LoanPoolContractClient::new(&e, &contract_id);
+        contract_client.initialize(
+            &Address::generate(&e),
+            &currency,
+            &TEST_LIQUIDATION_THRESHOLD,
+        );
+        contract_client.deposit(&user, &1000);
+        contract_client.borrow(&Address::generate(&e), &500);
+        assert_eq!(
+            contract_client.get_interest_rate_params(),
+            InterestRateParams {
+                base_rate: interest::BASE_INTEREST_RATE,
+                rate_at_panic: interest::INTEREST_RATE_AT_PANIC,
+                max_rate: interest::MAX_INTEREST_RATE,
+                panic_threshold: interest::PANIC_RATES_THRESHOLD,
+            }
+        );
+        assert_eq!(contract_client.get_interest(), 644_440);
+
+        // A flat stablecoin curve that gets steep above 80% utilization.
+        let params = InterestRateParams {
+            base_rate: 0,
+            rate_at_panic: 400_000,
+            max_rate: 6_000_000,
+            panic_threshold: 8_000_000,
+        };
+
+        // ACT
+        contract_client.set_interest_rate_params(&params);
+
+        // ASSERT
+        let event = storage::EventInterestRateParamsChanged {
+            params: params.clone(),
+        };
+        assert!(e
+            .events()
+            .all()
+            .contains((contract_id.clone(), event.topics(&e), event.data(&e))));
+        assert_eq!(contract_client.get_interest_rate_params(), params);
+        assert_eq!(contract_client.get_interest(), 250_000);
+        contract_client.borrow(&Address::generate(&e), &400);
+        assert_eq!(contract_client.get_interest(), 3_200_000);
+
+        for invalid in [
+            InterestRateParams {
+                base_rate: -1,
+                ..params.clone()
+            },
+            InterestRateParams {
+                rate_at_panic: 7_000_000,
+                ..params.clone()
+            },
+            InterestRateParams {
+                max_rate: interest::MAX_INTEREST_RATE_CAP + 1,
+                ..params.clone()
+            },
+            InterestRateParams {
+                panic_threshold: 10_000_000,
+                ..params.clone()
+            },
+            InterestRateParams {
+                panic_threshold: 0,
+                ..params.clone()
+            },
+        ] {
+            assert_eq!(
+                contract_client.try_set_interest_rate_params(&invalid),
+                Err(Ok(LoanPoolError::InvalidInterestRateParams))
+            );
+        }
+        assert_eq!(contract_client.get_interest_rate_params(), params);
+
+        assert_invariants(&contract_client);
+    }
+
+    const DAY: u64 = 86_400;
+
+    // Target 80% utilization, start at 4% and move at most ~e^50 a year.
+    fn test_adaptive_params() -> AdaptiveRateParams {
+        AdaptiveRateParams {
+            target_utilization: 8_000_000,
+            initial_rate_at_target: 400_000,
+            min_rate_at_target: 100_000,
+            max_rate_at_target: 5_000_000,
+            adjustment_speed: 500_000_000,
+        }
+    }
+
+    /// Pool on the adaptive model with 10_000 deposited and `borrowed` lent out.
+    fn setup_adaptive_pool(
+        e: &Env,
+        borrowed: i128,
+    ) -> (LoanPoolContractClient<'_>, StellarAssetClient<'_>) {
+        e.mock_all_auths();
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000;
+            li.timestamp = 1;
+            li.min_persistent_entry_ttl = 10_000_000;
+            li.min_temp_entry_ttl = 1_000_000;
+            li.max_entry_ttl = 1_000_001;
+        });
+
+        let admin = Address::generate(e);
+        let token = e.register_stellar_asset_contract_v2(admin);
+        let stellar_asset = StellarAssetClient::new(e, &token.address());
+        let currency = Currency {
+            token_address: token.address(),
+            ticker: Symbol::new(e, "USDC"),
+        };
+
+        let contract_id = e.register(LoanPoolContract, ());
+        let contract_client = LoanPoolContractClient::new(e, &contract_id);
+        contract_client.initialize(
+            &Address::generate(e),
+            &currency,
+            &TEST_LIQUIDATION_THRESHOLD,
+        );
+        contract_client.set_adaptive_rate_params(&test_adaptive_params());
+
+        let lender = Address::generate(e);
+        stellar_asset.mint(&lender, &10_000);
+        contract_client.deposit(&lender, &10_000);
+        contract_client.borrow(&Address::generate(e), &borrowed);
+        (contract_client, stellar_asset)
+    }
+
+    /// Accrue once a day for `days` days and return the rate at target after each day.
+    fn simulate_days(
+        e: &Env,
+        contract_client: &LoanPoolContractClient,
+        days: u64,
+    ) -> std::vec::Vec<i128> {
+        (0..days)
+            .map(|_| {
+                e.ledger().with_mut(|li| li.timestamp += DAY);
+                contract_client.add_interest_to_accrual();
+                contract_client.get_rate_at_target().unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn adaptive_rate_follows_utilization() {
+        // ARRANGE
+        let e = Env::default();
+        let (contract_client, stellar_asset) = setup_adaptive_pool(&e, 9_500);
+        assert_eq!(
+            contract_client.get_interest_rate_model(),
+            InterestRateModel::Adaptive
+        );
+        // 95% utilization is 3/4 of the way from the target to 100%, so the curve is at
+        // 1 + 3 * 0.75 times the rate at target.
+        assert_eq!(contract_client.get_interest(), 1_300_000);
+
+        // ACT
+        // A month above target.
+        let high = simulate_days(&e, &contract_client, 30);
+        // Lenders come in and utilization drops to 50% for a month.
+        let lender = Address::generate(&e);
+        stellar_asset.mint(&lender, &9_000);
+        contract_client.deposit(&lender, &9_000);
+        let low = simulate_days(&e, &contract_client, 30);
+
+        // ASSERT
+        // Rates rise every day until they reach the top of the range.
+        let mut previous = 400_000;
+        for rate_at_target in high.iter().copied() {
+            assert!(rate_at_target > previous || rate_at_target == 5_000_000);
+            previous = rate_at_target;
+        }
+        assert_eq!(high[0], 443_250);
+        assert_eq!(*high.last().unwrap(), 5_000_000);
+
+        // And fall every day while utilization stays below target.
+        for rate_at_target in low.iter().copied() {
+            assert!(rate_at_target < previous);
+            previous = rate_at_target;
+        }
+        assert_eq!(*low.last().unwrap(), 1_071_817);
+        // 50% utilization is 3/8 of the way from the target to 0%, so the curve is at
+        // 1 - 0.75 * 0.375 times the rate at target.
+        assert_eq!(contract_client.get_interest(), 770_368);
+
+        assert_invariants(&contract_client);
+    }
+
+    #[test]
+    fn adaptive_rate_holds_at_target() {
+        // ARRANGE
+        let e = Env::default();
+        let (contract_client, _) = setup_adaptive_pool(&e, 8_000);
+
+        // ACT
+        let rates = simulate_days(&e, &contract_client, 30);
+
+        // ASSERT
+        assert!(rates
+            .iter()
+            .all(|rate_at_target| *rate_at_target == 400_000));
+        assert_eq!(contract_client.get_interest(), 400_000);
+
+        assert_invariants(&contract_client);
+    }
+
+    #[test]
+    fn adaptive_rate_oscillating_utilization_stays_in_range() {
+        // ARRANGE
+        let e = Env::default();
+        let (contract_client, stellar_asset) = setup_adaptive_pool(&e, 9_000);
+        let lender = Address::generate(&e);
+        stellar_asset.mint(&lender, &12_500);
+
+        // ACT
+        // Utilization swings between 90% and 40% every week for a year. Both are half way
+        // between the target and the end of the curve, so the curve moves up and down at the
+        // same speed.
+        let mut rates = std::vec::Vec::new();
+        for week in 0..52 {
+            if week % 2 == 1 {
+                contract_client.deposit(&lender, &12_500);
+            } else if week > 0 {
+                contract_client.withdraw(&lender, &12_500);
+            }
+            rates.extend(simulate_days(&e, &contract_client, 7));
+        }
+
+        // ASSERT
+        let params = test_adaptive_params();
+        assert!(rates.iter().all(|rate_at_target| {
+            (params.min_rate_at_target..=params.max_rate_at_target).contains(rate_at_target)
+        }));
+        assert!(rates.iter().any(|rate_at_target| *rate_at_target > 600_000));
+        // Every week above target is undone by the following week below it.
+        let last = *rates.last().unwrap();
+        assert!((last - 400_000).abs() < 1_000, "{last}");
+
+        assert_invariants(&contract_client);
+    }
+
+    #[test]
+    fn adaptive_rate_adjustment_is_capped_per_update() {
+        // ARRANGE
+        let e = Env::default();
+        let (contract_client, _) = setup_adaptive_pool(&e, 9_500);
+
+        // ACT
+        // Nobody touches the pool for a year.
+        e.ledger().with_mut(|li| li.timestamp += 365 * DAY);
+        contract_client.add_interest_to_accrual();
+
+        // ASSERT
+        // Only the first week counts: 4% * e^(50 * 0.75 * 7 / 365.24).
+        assert_eq!(contract_client.get_rate_at_target(), Some(820_706));
+
+        assert_invariants(&contract_client);
+    }
+
+    #[test]
+    fn switch_interest_rate_model() {
+        // ARRANGE
+        let e = Env::default();
+        let (contract_client, _) = setup_adaptive_pool(&e, 5_000);
+        let kinked = contract_client.get_interest_rate_params();
+
+        // ACT
+        contract_client.set_interest_rate_params(&kinked);
+
+        // ASSERT
+        assert_eq!(
+            contract_client.get_interest_rate_model(),
+            InterestRateModel::Kinked
+        );
+        assert_eq!(contract_client.get_interest(), 644_440);
+        // The adaptive curve no longer moves.
+        simulate_days(&e, &contract_client, 3);
+        assert_eq!(contract_client.get_rate_at_target(), Some(400_000));
+
+        for invalid in [
+            AdaptiveRateParams {
+                target_utilization: 10_000_000,
+                ..test_adaptive_params()
+            },
+            AdaptiveRateParams {
+                min_rate_at_target: 0,
+                ..test_adaptive_params()
+            },
+            AdaptiveRateParams {
+                initial_rate_at_target: 6_000_000,
+                ..test_adaptive_params()
+            },
+            AdaptiveRateParams {
+                max_rate_at_target: interest::MAX_INTEREST_RATE_CAP,
+                ..test_adaptive_params()
+            },
+            AdaptiveRateParams {
+                adjustment_speed: interest::MAX_ADJUSTMENT_SPEED + 1,
+                ..test_adaptive_params()
+            },
+        ] {
+            assert_eq!(
+                contract_client.try_set_adaptive_rate_params(&invalid),
+                Err(Ok(LoanPoolError::InvalidInterestRateParams))
+            );
+        }
+        assert_eq!(
+            contract_client.get_interest_rate_model(),
+            InterestRateModel::Kinked
+        );
+
+        assert_invariants(&contract_client);
+    }
+
+    #[test]
+    fn share_token() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths();
+
+        let admin = Address::generate(&e);
+        let token = e.register_stellar_asset_contract_v2(admin.clone());
+        let stellar_asset = StellarAssetClient::new(&e, &token.address());
+        let token_client = TokenClient::new(&e, &token.address());
+        let currency = Currency {
+            token_address: token.address(),
+            ticker: Symbol::new(&e, "XLM"),
+        };
+
+        let lender = Address::generate(&e);
+        let buyer = Address::generate(&e);
+        let vault = Address::generate(&e);
+        stellar_asset.mint(&lender, &1000);
+
+        let contract_id = e.register(LoanPoolContract, ());
+        let contract_client = LoanPoolContractClient::new(&e, &contract_id);
+        let share_client = TokenClient::new(&e, &contract_id);
+        contract_client.initialize(
+            &Address::generate(&e),
+            &currency,
+            &TEST_LIQUIDATION_THRESHOLD,
+        );
+        contract_client.deposit(&lender, &1000);
+        let mint = Mint {
+            to: lender.clone(),
+            to_muxed_id: None,
+            amount: 1000,
+        };
+        assert!(e
+            .events()
+            .all()
+            .contains((contract_id.clone(), mint.topics(&e), mint.data(&e))));
+
+        // ACT
+        share_client.transfer(&lender, &buyer, &400);
+        let transfer = Transfer {
+            from: lender.clone(),
+            to: buyer.clone(),
+            to_muxed_id: None,
+            amount: 400,
+        };
+        assert!(e.events().all().contains((
+            contract_id.clone(),
+            transfer.topics(&e),
+            transfer.data(&e)
+        )));
+        share_client.approve(&lender, &vault, &300, &(e.ledger().sequence() + 100));
+        share_client.transfer_from(&vault, &lender, &vault, &200);
+
+        // ASSERT
+        assert_eq!(share_client.balance(&lender), 400);
+        assert_eq!(share_client.balance(&buyer), 400);
+        assert_eq!(share_client.balance(&vault), 200);
+        assert_eq!(share_client.allowance(&lender, &vault), 100);
+        assert_eq!(contract_client.get_total_balance_shares(), 1000);
+
+        // Shares are worth their part of the pool to whoever holds them.
+        contract_client.withdraw(&buyer, &400);
+        assert_eq!(token_client.balance(&buyer), 400);
+        assert_eq!(share_client.balance(&buyer), 0);
+
+        assert_eq!(
+            share_client.try_transfer_from(&vault, &lender, &vault, &200),
+            Err(Ok(LoanPoolError::InsufficientAllowance.into()))
+        );
+        assert_eq!(
+            share_client.try_transfer(&vault, &buyer, &201),
+            Err(Ok(LoanPoolError::InsufficientShares.into()))
+        );
+        assert_eq!(
+            share_client.try_transfer(&vault, &buyer, &-1),
+            Err(Ok(LoanPoolError::InvalidShareAmount.into()))
+        );
+        assert_eq!(
+            share_client.try_burn(&vault, &100),
+            Err(Ok(LoanPoolError::SharesNotBurnable.into()))
+        );
+
+        e.ledger().with_mut(|li| li.sequence_number += 101);
+        assert_eq!(share_client.allowance(&lender, &vault), 0);
+
+        assert_eq!(share_client.decimals(), token_client.decimals());
+        let mut symbol = Bytes::from_slice(&e, b"s");
+        symbol.append(&token_client.symbol().to_bytes());
+        assert_eq!(share_client.symbol().to_bytes(), symbol);
+        let mut name = token_client.name().to_bytes();
+        name.append(&Bytes::from_slice(&e, b" pool share"));
+        assert_eq!(share_client.name().to_bytes(), name);
+
+        assert_invariants(&contract_client);
+    }
+
+    /// (1 + rate / SECONDS_IN_YEAR) ^ seconds computed in floating point.
+    fn reference_compound_factor(interest_rate: i128, seconds: u64) -> f64 {
+        let rate_per_second = interest_rate as f64 / 10_000_000.0 / 31_556_926.0;
+        (seconds as f64 * rate_per_second.ln_1p()).exp()
+    }
+
+    fn relative_drift(accrual: i128, reference: f64) -> f64 {
+        ((accrual as f64 / interest::ACCRUAL_ONE as f64) - reference).abs() / reference
+    }
+
+    #[test]
+    fn compound_factor_matches_reference() {
+        for interest_rate in [200_000, 1_000_000, 3_000_000] {
+            for seconds in [1, 3_600, 86_400, 31_556_926, 10 * 31_556_926] {
+                let factor = interest::compound_factor(interest_rate, seconds).unwrap();
+                let drift =
+                    relative_drift(factor, reference_compound_factor(interest_rate, seconds));
+                assert!(
+                    drift < 1e-9,
+                    "rate {interest_rate}, {seconds} seconds: drift {drift}"
+                );
+            }
+        }
+        assert_eq!(
+            interest::compound_factor(3_000_000, 0).unwrap(),
+            interest::ACCRUAL_ONE
+        );
+    }
+
+    #[test]
+    fn accrual_drift_with_daily_updates() {
+        const DAY: u64 = 86_400;
+        const YEARS: u64 = 10;
+
+        let e = Env::default();
+        e.mock_all_auths();
+        e.ledger().with_mut(|li| {
+            li.sequence_number = 100_000;
+            li.timestamp = 1;
+            li.min_persistent_entry_ttl = 10_000_000;
+            li.min_temp_entry_ttl = 1_000_000;
+            li.max_entry_ttl = 1_000_001;
+        });
+
+        let admin = Address::generate(&e);
+        let token = e.register_stellar_asset_contract_v2(admin.clone());
+        let stellar_asset = StellarAssetClient::new(&e, &token.address());
+        let currency = Currency {
+            token_address: token.address(),
+            ticker: Symbol::new(&e, "XLM"),
+        };
+
+        let user = Address::generate(&e);
+        stellar_asset.mint(&user, &1000);
+
+        let contract_id = e.register(LoanPoolContract, ());
+        let contract_client = LoanPoolContractClient::new(&e, &contract_id);
+        contract_client.initialize(
+            &Address::generate(&e),
+            &currency,
+            &TEST_LIQUIDATION_THRESHOLD,
+        );
+        contract_client.deposit(&user, &1000);
+        contract_client.borrow(&Address::generate(&e), &500);
+        let interest_rate = contract_client.get_interest();
+
+        // Accrue once a day for ten years.
+        let days = YEARS * 31_556_926 / DAY;
+        for day in 1..=days {
+            e.ledger().with_mut(|li| {
+                li.timestamp = 1 + day * DAY;
+            });
+            contract_client.add_interest_to_accrual();
+        }
+
+        // Accruing daily ends up at the same index as compounding the whole period at once.
+        let accrual = contract_client.get_accrual();
+        let reference = reference_compound_factor(interest_rate, days * DAY);
+        let drift = relative_drift(accrual, reference);
+        assert!(drift < 1e-9, "drift {drift}");
+        assert!(
+            (accrual - interest::compound_factor(interest_rate, days * DAY).unwrap()).abs()
+                < interest::ACCRUAL_ONE / 1_000_000_000
+        );
+        assert_invariants(&contract_client);
+    }
+
+    fn setup_borrowed_pool(e: &Env) -> LoanPoolContractClient<'_> {
+        let admin = Address::generate(e);
+        let token = e.register_stellar_asset_contract_v2(admin);
+        let currency = Currency {
+            token_address: token.address(),
+            ticker: Symbol::new(e, "XLM"),
+        };
+        let user = Address::generate(e);
+        StellarAssetClient::new(e, &token.address()).mint(&user, &1000);
+
+        let contract_id = e.register(LoanPoolContract, ());
+        let contract_client = LoanPoolContractClient::new(e, &contract_id);
+        contract_client.initialize(
+            &Address::generate(e),
+            &currency,
+            &TEST_LIQUIDATION_THRESHOLD,
+        );
+        contract_client.deposit(&user, &1000);
+        contract_client.borrow(&Address::generate(e), &500);
+        contract_client
+    }
+
+    #[test]
+    fn accrual_with_large_index() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths();
+        e.ledger().with_mut(|li| li.timestamp = 1);
+        let contract_client = setup_borrowed_pool(&e);
+        let interest_rate = contract_client.get_interest();
+        let accrual = 1_000 * interest::ACCRUAL_ONE;
+        e.as_contract(&contract_client.address, || {
+            storage::write_accrual(&e, accrual)
+        });
+
+        // ACT
+        e.ledger().with_mut(|li| li.timestamp = 1 + 31_556_926);
+        contract_client.add_interest_to_accrual();
+
+        // ASSERT
+        let factor = interest::compound_factor(interest_rate, 31_556_926).unwrap();
+        assert_eq!(contract_client.get_accrual(), 1_000 * factor);
+    }
+
+    #[test]
+    fn accrual_at_max_rate_after_long_gap() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths();
+        e.ledger().with_mut(|li| li.timestamp = 1);
+        let contract_client = setup_borrowed_pool(&e);
+        contract_client.set_interest_rate_params(&InterestRateParams {
+            base_rate: interest::MAX_INTEREST_RATE_CAP,
+            rate_at_panic: interest::MAX_INTEREST_RATE_CAP,
+            max_rate: interest::MAX_INTEREST_RATE_CAP,
+            panic_threshold: interest::PANIC_RATES_THRESHOLD,
+        });
+        let interest_rate = contract_client.get_interest();
+        let five_years = 5 * 31_556_926;
+        // A single binomial series over the whole gap overflows.
+        assert_eq!(
+            interest::compound_factor(interest_rate, five_years),
+            Err(LoanPoolError::OverOrUnderFlow)
+        );
+
+        // ACT
+        e.ledger().with_mut(|li| li.timestamp = 1 + five_years);
+        contract_client.add_interest_to_accrual();
+
+        // ASSERT
+        let factor = interest::compound_factor(interest_rate, 31_556_926).unwrap();
+        let one = I256::from_i128(&e, interest::ACCRUAL_ONE);
+        let mut expected = one.clone();
+        for _ in 0..5 {
+            expected = expected.mul(&I256::from_i128(&e, factor)).div(&one);
+        }
+        assert_eq!(contract_client.get_accrual(), expected.to_i128().unwrap());
+        assert_invariants(&contract_client);
+    }
+
+    #[test]
+    fn legacy_accrual_is_rescaled() {
+        // ARRANGE
+        let e = Env::default();
+        e.mock_all_auths();
+        e.ledger().with_mut(|li| li.timestamp = 1);
+        let contract_client = setup_borrowed_pool(&e);
+        let interest_rate = contract_client.get_interest();
+        // A pool deployed before the index had 18 decimals, at 1.2 in 7 decimals.
+        e.as_contract(&contract_client.address, || {
+            e.storage()
+                .persistent()
+                .set(&storage::PoolDataKey::Accrual, &12_000_000_i128);
+            e.storage()
+                .persistent()
+                .remove(&storage::PoolDataKey::AccrualVersion);
+        });
+
+        // ACT & ASSERT
+        assert_eq!(
+            contract_client.get_accrual(),
+            12 * interest::ACCRUAL_ONE / 10
+        );
+
+        e.ledger().with_mut(|li| li.timestamp = 1 + 31_556_926);
+        contract_client.add_interest_to_accrual();
+        let factor = interest::compound_factor(interest_rate, 31_556_926).unwrap();
+        assert_eq!(contract_client.get_accrual(), 12 * factor / 10);
+        // The rescaled index is stored, so it is not scaled again.
+        assert_eq!(
+            e.as_contract(&contract_client.address, || {
+                e.storage()
+                    .persistent()
+                    .get::<_, i128>(&storage::PoolDataKey::Accrual)
+            }),
+            Some(12 * factor / 10)
+        );
+        assert_invariants(&contract_client);
     }
 }
@@ -8,3 +8,26 @@ pub struct PoolState {
     pub total_balance_shares: i128,
     pub annual_interest_rate: i128,
 }
+
+/// Result of `check_invariants`. `holds` is true when every check that could be made passed.
+/// Pools that had positions before the position totals were introduced do not track them, the
+/// totals and the checks that need them are `None` for those pools.
+#[contracttype]
+#[derive(Debug, PartialEq)]
+pub struct InvariantReport {
+    // Pool's balance of its token as reported by the token contract
+    pub token_balance: i128,
+    pub total_balance_tokens: i128,
+    pub available_balance_tokens: i128,
+    pub total_collateral: Option<i128>,
+    pub total_balance_shares: i128,
+    // Sum of the receivable shares of all positions
+    pub total_receivable_shares: Option<i128>,
+    // token_balance >= available_balance_tokens + total_collateral
+    pub balance_covers_holdings: Option<bool>,
+    // total_balance_tokens >= available_balance_tokens
+    pub total_covers_available: bool,
+    // total_balance_shares == total_receivable_shares and neither is negative
+    pub shares_consistent: Option<bool>,
+    pub holds: bool,
+}
@@ -19,4 +19,11 @@ pub enum LoanPoolError {
     InterestRateMultiplier = 13,
     PoolStatus = 14,
     WrongStatus = 15,
+    InvalidLiquidationFeeShare = 16,
+    InvalidInterestRateParams = 17,
+    InsufficientAllowance = 18,
+    InsufficientShares = 19,
+    InvalidShareAmount = 20,
+    InvalidExpirationLedger = 21,
+    SharesNotBurnable = 22,
 }
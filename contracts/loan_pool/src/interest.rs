@@ -1,57 +1,151 @@
-use crate::{error::LoanPoolError, storage};
-use soroban_sdk::Env;
+use crate::error::LoanPoolError;
+use crate::storage::{self, AdaptiveRateParams, InterestRateModel, InterestRateParams};
+use soroban_sdk::{Env, I256};
 
-#[allow(dead_code)]
-// These are mockup numbers that should be set to each pool based on the token.
+/// Fixed point of the accrual index. 1.0 = 1_000_000_000_000_000_000_i128
+pub const ACCRUAL_ONE: i128 = 1_000_000_000_000_000_000;
+const DECIMAL: i128 = 10_000_000;
+const SECONDS_IN_YEAR: i128 = 31_556_926;
+
+// Longest period compounded in one go. The binomial series of a single period overflows once
+// rate × time grows large, so longer gaps are compounded one year at a time.
+const MAX_COMPOUND_PERIOD: u64 = 31_556_926;
+
+// Default interest rate curve, used by pools that have no curve of their own.
 pub const BASE_INTEREST_RATE: i128 = 200_000; // 2%
 pub const INTEREST_RATE_AT_PANIC: i128 = 1_000_000; // 10%
 pub const MAX_INTEREST_RATE: i128 = 3_000_000; // 30%
-pub const PANIC_BASE_RATE: i128 = -17_000_000;
+pub const PANIC_RATES_THRESHOLD: i128 = 9_000_000; // 90%
+
+// Highest rate a pool's curve can reach
+pub const MAX_INTEREST_RATE_CAP: i128 = 50_000_000; // 500%
+
+// Utilization is computed with one more digit than the other fixed point values
+const UTILIZATION_ONE: i128 = 100_000_000;
+
+/// Check that a curve is non-decreasing, starts at or above zero, stays below
+/// `MAX_INTEREST_RATE_CAP` and has its kink strictly between 0% and 100% utilization.
+pub fn validate_params(params: &InterestRateParams) -> Result<(), LoanPoolError> {
+    let InterestRateParams {
+        base_rate,
+        rate_at_panic,
+        max_rate,
+        panic_threshold,
+    } = *params;
+    if base_rate < 0
+        || rate_at_panic < base_rate
+        || max_rate < rate_at_panic
+        || max_rate > MAX_INTEREST_RATE_CAP
+        || !(1..DECIMAL).contains(&panic_threshold)
+    {
+        return Err(LoanPoolError::InvalidInterestRateParams);
+    }
+    Ok(())
+}
+
+// Ratio of the adaptive curve's rate at 100% utilization to its rate at target, and of its rate
+// at target to its rate at 0% utilization
+const CURVE_STEEPNESS: i128 = 4;
+// Fastest the adaptive curve can move, as a yearly growth rate
+pub const MAX_ADJUSTMENT_SPEED: i128 = 1_000_000_000; // 10000%
+
+// Longest period the adaptive curve is moved for in one update, so that a pool nobody touched
+// for a long time does not jump to the end of its range
+const MAX_ADJUSTMENT_PERIOD: u64 = 7 * 24 * 60 * 60;
 
+/// Check that the adaptive curve targets a utilization strictly between 0% and 100%, that its
+/// rate at target range is positive and contains the initial rate, that four times the highest
+/// rate at target stays below `MAX_INTEREST_RATE_CAP` and that it moves at a positive speed of
+/// at most `MAX_ADJUSTMENT_SPEED`.
+pub fn validate_adaptive_params(params: &AdaptiveRateParams) -> Result<(), LoanPoolError> {
+    let AdaptiveRateParams {
+        target_utilization,
+        initial_rate_at_target,
+        min_rate_at_target,
+        max_rate_at_target,
+        adjustment_speed,
+    } = *params;
+    if !(1..DECIMAL).contains(&target_utilization)
+        || min_rate_at_target <= 0
+        || !(min_rate_at_target..=max_rate_at_target).contains(&initial_rate_at_target)
+        || max_rate_at_target > MAX_INTEREST_RATE_CAP / CURVE_STEEPNESS
+        || !(1..=MAX_ADJUSTMENT_SPEED).contains(&adjustment_speed)
+    {
+        return Err(LoanPoolError::InvalidInterestRateParams);
+    }
+    Ok(())
+}
+
+/// Current yearly interest rate of the pool, 1.0 = 10000000_i128
 pub fn get_interest(e: Env) -> Result<i128, LoanPoolError> {
     let interest_rate_multiplier = storage::read_interest_rate_multiplier(&e)?;
-    const PANIC_RATES_THRESHOLD: i128 = 90_000_000;
-    let available = storage::read_available_balance(&e)?;
-    let total = storage::read_total_balance(&e)?;
+    let interest_rate = match storage::read_interest_rate_model(&e) {
+        InterestRateModel::Kinked => kinked_interest(&e)?,
+        InterestRateModel::Adaptive => adaptive_interest(&e)?,
+    };
+    interest_rate
+        .checked_mul(interest_rate_multiplier)
+        .ok_or(LoanPoolError::OverOrUnderFlow)
+}
+
+fn kinked_interest(e: &Env) -> Result<i128, LoanPoolError> {
+    let InterestRateParams {
+        base_rate,
+        rate_at_panic,
+        max_rate,
+        panic_threshold,
+    } = storage::read_interest_rate_params(e);
+    let panic_threshold = panic_threshold
+        .checked_mul(UTILIZATION_ONE / DECIMAL)
+        .ok_or(LoanPoolError::OverOrUnderFlow)?;
+    let available = storage::read_available_balance(e)?;
+    let total = storage::read_total_balance(e)?;
 
     if total > 0 {
-        let slope_before_panic = (INTEREST_RATE_AT_PANIC
-            .checked_sub(BASE_INTEREST_RATE)
+        let slope_before_panic = (rate_at_panic
+            .checked_sub(base_rate)
             .ok_or(LoanPoolError::OverOrUnderFlow)?)
         .checked_mul(10_000_000)
         .ok_or(LoanPoolError::OverOrUnderFlow)?
-        .checked_div(PANIC_RATES_THRESHOLD)
+        .checked_div(panic_threshold)
         .ok_or(LoanPoolError::OverOrUnderFlow)?;
 
-        let slope_after_panic = (MAX_INTEREST_RATE
-            .checked_sub(INTEREST_RATE_AT_PANIC)
+        let slope_after_panic = (max_rate
+            .checked_sub(rate_at_panic)
             .ok_or(LoanPoolError::OverOrUnderFlow)?)
         .checked_mul(10_000_000)
         .ok_or(LoanPoolError::OverOrUnderFlow)?
         .checked_div(
-            100_000_000_i128
-                .checked_sub(PANIC_RATES_THRESHOLD)
+            UTILIZATION_ONE
+                .checked_sub(panic_threshold)
                 .ok_or(LoanPoolError::OverOrUnderFlow)?,
         )
         .ok_or(LoanPoolError::OverOrUnderFlow)?;
 
+        // Rate the steeper part of the curve would have at 0% utilization
+        let panic_base_rate = max_rate
+            .checked_sub(
+                slope_after_panic
+                    .checked_mul(UTILIZATION_ONE / 10_000_000)
+                    .ok_or(LoanPoolError::OverOrUnderFlow)?,
+            )
+            .ok_or(LoanPoolError::OverOrUnderFlow)?;
+
         let ratio_of_balances = ((total
             .checked_sub(available)
             .ok_or(LoanPoolError::OverOrUnderFlow)?)
-        .checked_mul(100_000_000)
+        .checked_mul(UTILIZATION_ONE)
         .ok_or(LoanPoolError::OverOrUnderFlow)?)
         .checked_div(total)
         .ok_or(LoanPoolError::OverOrUnderFlow)?;
 
-        if ratio_of_balances < PANIC_RATES_THRESHOLD {
+        if ratio_of_balances < panic_threshold {
             Ok((slope_before_panic
                 .checked_mul(ratio_of_balances)
                 .ok_or(LoanPoolError::OverOrUnderFlow)?)
             .checked_div(10_000_000)
             .ok_or(LoanPoolError::OverOrUnderFlow)?
-            .checked_add(BASE_INTEREST_RATE)
-            .ok_or(LoanPoolError::OverOrUnderFlow)?
-            .checked_mul(interest_rate_multiplier)
+            .checked_add(base_rate)
             .ok_or(LoanPoolError::OverOrUnderFlow)?)
         } else {
             Ok((slope_after_panic
@@ -59,14 +153,175 @@ pub fn get_interest(e: Env) -> Result<i128, LoanPoolError> {
                 .ok_or(LoanPoolError::OverOrUnderFlow)?)
             .checked_div(10_000_000)
             .ok_or(LoanPoolError::OverOrUnderFlow)?
-            .checked_add(PANIC_BASE_RATE)
-            .ok_or(LoanPoolError::OverOrUnderFlow)?
-            .checked_mul(interest_rate_multiplier)
+            .checked_add(panic_base_rate)
             .ok_or(LoanPoolError::OverOrUnderFlow)?)
         }
     } else {
-        Ok(BASE_INTEREST_RATE
-            .checked_mul(interest_rate_multiplier)
-            .ok_or(LoanPoolError::OverOrUnderFlow)?)
+        Ok(base_rate)
+    }
+}
+
+fn read_adaptive_rate_params(e: &Env) -> Result<AdaptiveRateParams, LoanPoolError> {
+    storage::read_adaptive_rate_params(e).ok_or(LoanPoolError::InvalidInterestRateParams)
+}
+
+/// Rate at target of the adaptive curve.
+pub fn rate_at_target(e: &Env, params: &AdaptiveRateParams) -> i128 {
+    storage::read_rate_at_target(e).unwrap_or(params.initial_rate_at_target)
+}
+
+/// Share of the pool's balance that is lent out, fixed-rate loans included, 1.0 = 10000000_i128
+pub fn utilization(e: &Env) -> Result<i128, LoanPoolError> {
+    let available = storage::read_available_balance(e)?;
+    let total = storage::read_total_balance(e)?;
+    if total <= 0 {
+        return Ok(0);
+    }
+    Ok(total
+        .checked_sub(available)
+        .ok_or(LoanPoolError::OverOrUnderFlow)?
+        .checked_mul(DECIMAL)
+        .ok_or(LoanPoolError::OverOrUnderFlow)?
+        .checked_div(total)
+        .ok_or(LoanPoolError::OverOrUnderFlow)?
+        .clamp(0, DECIMAL))
+}
+
+// Distance of the utilization from the target, -1.0 at 0% and 1.0 at 100% utilization
+fn utilization_error(e: &Env, target_utilization: i128) -> Result<i128, LoanPoolError> {
+    let utilization = utilization(e)?;
+    let range = if utilization > target_utilization {
+        DECIMAL - target_utilization
+    } else {
+        target_utilization
+    };
+    utilization
+        .checked_sub(target_utilization)
+        .ok_or(LoanPoolError::OverOrUnderFlow)?
+        .checked_mul(DECIMAL)
+        .ok_or(LoanPoolError::OverOrUnderFlow)?
+        .checked_div(range)
+        .ok_or(LoanPoolError::OverOrUnderFlow)
+}
+
+fn adaptive_interest(e: &Env) -> Result<i128, LoanPoolError> {
+    let params = read_adaptive_rate_params(e)?;
+    let rate_at_target = rate_at_target(e, &params);
+    let error = utilization_error(e, params.target_utilization)?;
+
+    // rate_at_target * (1 + coefficient * error)
+    let coefficient = if error < 0 {
+        DECIMAL - DECIMAL / CURVE_STEEPNESS
+    } else {
+        (CURVE_STEEPNESS - 1) * DECIMAL
+    };
+    let curve = coefficient
+        .checked_mul(error)
+        .ok_or(LoanPoolError::OverOrUnderFlow)?
+        .checked_div(DECIMAL)
+        .ok_or(LoanPoolError::OverOrUnderFlow)?
+        .checked_add(DECIMAL)
+        .ok_or(LoanPoolError::OverOrUnderFlow)?;
+    rate_at_target
+        .checked_mul(curve)
+        .ok_or(LoanPoolError::OverOrUnderFlow)?
+        .checked_div(DECIMAL)
+        .ok_or(LoanPoolError::OverOrUnderFlow)
+}
+
+/// Move the adaptive curve for the time since the last accrual update. The rate at target grows
+/// at `adjustment_speed` times the utilization error per year, compounded every second, and
+/// stays within its range. Does nothing for pools on the kinked model.
+pub fn update_rate_at_target(e: &Env) -> Result<(), LoanPoolError> {
+    if storage::read_interest_rate_model(e) != InterestRateModel::Adaptive {
+        return Ok(());
+    }
+    let params = read_adaptive_rate_params(e)?;
+    let seconds_since_update = e
+        .ledger()
+        .timestamp()
+        .checked_sub(storage::read_accrual_last_updated(e)?)
+        .ok_or(LoanPoolError::OverOrUnderFlow)?
+        .min(MAX_ADJUSTMENT_PERIOD);
+    if seconds_since_update == 0 {
+        return Ok(());
+    }
+
+    let rate_at_target = rate_at_target(e, &params);
+    let growth_rate = params
+        .adjustment_speed
+        .checked_mul(utilization_error(e, params.target_utilization)?)
+        .ok_or(LoanPoolError::OverOrUnderFlow)?
+        .checked_div(DECIMAL)
+        .ok_or(LoanPoolError::OverOrUnderFlow)?;
+    let new_rate_at_target = rate_at_target
+        .checked_mul(compound_factor(growth_rate, seconds_since_update)?)
+        .ok_or(LoanPoolError::OverOrUnderFlow)?
+        .checked_div(ACCRUAL_ONE)
+        .ok_or(LoanPoolError::OverOrUnderFlow)?
+        .clamp(params.min_rate_at_target, params.max_rate_at_target);
+
+    if new_rate_at_target != rate_at_target {
+        storage::write_rate_at_target(e, new_rate_at_target);
+    }
+    Ok(())
+}
+
+pub fn calculate_accrual(e: &Env) -> Result<i128, LoanPoolError> {
+    let current_timestamp = e.ledger().timestamp();
+    let accrual = storage::read_accrual(e)?;
+    let accrual_last_update = storage::read_accrual_last_updated(e)?;
+    let seconds_since_update = current_timestamp
+        .checked_sub(accrual_last_update)
+        .ok_or(LoanPoolError::OverOrUnderFlow)?;
+
+    let interest_rate: i128 = get_interest(e.clone())?;
+    // The product of two 1e18 fixed point values outgrows i128 once the index passes ~170
+    let one = I256::from_i128(e, ACCRUAL_ONE);
+    let mut new_accrual = I256::from_i128(e, accrual);
+    let mut seconds_left = seconds_since_update;
+    while seconds_left > 0 {
+        let period = seconds_left.min(MAX_COMPOUND_PERIOD);
+        let factor = compound_factor(interest_rate, period)?;
+        new_accrual = new_accrual.mul(&I256::from_i128(e, factor)).div(&one);
+        seconds_left -= period;
+    }
+    new_accrual.to_i128().ok_or(LoanPoolError::OverOrUnderFlow)
+}
+
+/// Growth of the accrual index over `seconds` at a yearly `interest_rate` (1.0 = 10000000_i128)
+/// compounded every second, i.e. (1 + rate / SECONDS_IN_YEAR) ^ seconds in `ACCRUAL_ONE` fixed
+/// point. The power is expanded as a binomial series, which is summed until its terms no longer
+/// register in the fixed point.
+pub fn compound_factor(interest_rate: i128, seconds: u64) -> Result<i128, LoanPoolError> {
+    let rate_per_second = interest_rate
+        .checked_mul(ACCRUAL_ONE / DECIMAL)
+        .ok_or(LoanPoolError::OverOrUnderFlow)?
+        .checked_div(SECONDS_IN_YEAR)
+        .ok_or(LoanPoolError::OverOrUnderFlow)?;
+    let seconds = i128::from(seconds);
+
+    // term_k = C(seconds, k) * rate_per_second ^ k
+    let mut factor = ACCRUAL_ONE;
+    let mut term = ACCRUAL_ONE;
+    let mut k = 1;
+    while k <= seconds {
+        term = term
+            .checked_mul(rate_per_second)
+            .ok_or(LoanPoolError::OverOrUnderFlow)?
+            .checked_div(ACCRUAL_ONE)
+            .ok_or(LoanPoolError::OverOrUnderFlow)?
+            .checked_mul(seconds - k + 1)
+            .ok_or(LoanPoolError::OverOrUnderFlow)?
+            .checked_div(k)
+            .ok_or(LoanPoolError::OverOrUnderFlow)?;
+        if term == 0 {
+            break;
+        }
+        factor = factor
+            .checked_add(term)
+            .ok_or(LoanPoolError::OverOrUnderFlow)?;
+        k += 1;
     }
+    Ok(factor)
 }
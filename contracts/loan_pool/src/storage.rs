@@ -1,6 +1,7 @@
-use soroban_sdk::{contractevent, contracttype, Address, Env, Symbol};
+use soroban_sdk::{contractevent, contracttype, Address, Env, Symbol, TryFromVal, Val, Vec};
 
 use crate::error::LoanPoolError;
+use crate::interest;
 
 /* Storage Types */
 
@@ -27,6 +28,56 @@ pub struct Currency {
     pub ticker: Symbol,
 }
 
+/// Parameters of the kinked interest rate curve. Rates are yearly, 1.0 = 10000000_i128.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct InterestRateParams {
+    // Rate at 0% utilization
+    pub base_rate: i128,
+    // Rate at `panic_threshold` utilization
+    pub rate_at_panic: i128,
+    // Rate at 100% utilization
+    pub max_rate: i128,
+    // Utilization where the steeper part of the curve starts, 1.0 = 10000000_i128
+    pub panic_threshold: i128,
+}
+
+/// Parameters of the adaptive interest rate curve. The curve goes from a quarter of the rate at
+/// target at 0% utilization to four times it at 100%. The rate at target moves up while
+/// utilization is above `target_utilization` and down while it is below. Rates are yearly,
+/// 1.0 = 10000000_i128.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct AdaptiveRateParams {
+    // Utilization the curve steers toward, 1.0 = 10000000_i128
+    pub target_utilization: i128,
+    // Rate at target when the model is selected
+    pub initial_rate_at_target: i128,
+    pub min_rate_at_target: i128,
+    pub max_rate_at_target: i128,
+    // Yearly growth rate of the rate at target while the pool is fully utilized
+    pub adjustment_speed: i128,
+}
+
+/// Interest rate model a pool uses.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracttype]
+pub enum InterestRateModel {
+    // Static curve of `InterestRateParams`
+    Kinked,
+    // Self-adjusting curve of `AdaptiveRateParams`
+    Adaptive,
+}
+
+// Amount of pool shares a spender may transfer on behalf of their owner
+#[derive(Clone)]
+#[contracttype]
+pub struct AllowanceValue {
+    pub amount: i128,
+    // Last ledger the allowance can be used on
+    pub expiration_ledger: u32,
+}
+
 #[derive(PartialEq, Eq, Debug)]
 #[contracttype]
 pub enum PoolStatus {
@@ -38,7 +89,7 @@ pub enum PoolStatus {
 
 #[derive(Clone)]
 #[contracttype]
-enum PoolDataKey {
+pub(crate) enum PoolDataKey {
     // Address of the loan manager for authorization.
     LoanManagerAddress,
     // Pool's token's address & ticker
@@ -61,7 +112,31 @@ enum PoolDataKey {
     InterestRateMultiplier,
     // Pool health status,
     PoolStatus,
-}
+    // Share of the liquidation bonus that goes to the protocol, 1.0 = 10000000_i128
+    LiquidationFeeShare,
+    // Sum of the collateral of all positions
+    TotalCollateral,
+    // Parameters of the interest rate curve
+    InterestRateParams,
+    // Interest rate model in use
+    InterestRateModel,
+    // Parameters of the adaptive interest rate curve
+    AdaptiveRateParams,
+    // Current rate at target of the adaptive interest rate curve
+    RateAtTarget,
+    // Owner, spender -> share allowance, kept in temporary storage
+    Allowance(Address, Address),
+    // Sum of the receivable shares of all positions
+    TotalReceivableShares,
+    // Set once the accrual index is stored in `interest::ACCRUAL_ONE` fixed point
+    AccrualVersion,
+    // Set for pools whose position totals cover every position, i.e. pools initialized after
+    // the totals were introduced
+    PositionTotalsTracked,
+}
+
+// Pools deployed before the accrual index moved to 18 decimals store it with 7
+const LEGACY_ACCRUAL_SCALE: i128 = 100_000_000_000;
 
 /* Contract events */
 #[contractevent(topics = ["pool_status_updated"])]
@@ -114,6 +189,40 @@ pub struct EventInterestMultiplierChanged {
     pub multiplier: i128,
 }
 
+#[contractevent(topics = ["interest_rate_params_changed"])]
+pub struct EventInterestRateParamsChanged {
+    pub params: InterestRateParams,
+}
+
+#[contractevent(topics = ["interest_rate_model_changed"])]
+pub struct EventInterestRateModelChanged {
+    pub model: InterestRateModel,
+}
+
+#[contractevent(topics = ["adaptive_rate_params_changed"])]
+pub struct EventAdaptiveRateParamsChanged {
+    pub params: AdaptiveRateParams,
+}
+
+#[contractevent(topics = ["rate_at_target_changed"])]
+pub struct EventRateAtTargetChanged {
+    pub rate_at_target: i128,
+}
+
+#[contractevent(topics = ["liquidation_fee_share_changed"])]
+pub struct EventLiquidationFeeShareChanged {
+    pub share: i128,
+}
+
+#[contractevent(topics = ["liquidation_bonus_split"])]
+pub struct EventLiquidationBonusSplit {
+    #[topic]
+    pub loan_owner: Address,
+    pub liquidator: Address,
+    pub liquidator_bonus: i128,
+    pub protocol_bonus: i128,
+}
+
 #[contractevent(topics = ["positions_updated"])]
 pub struct EventPositionsUpdated {
     pub addr: Address,
@@ -127,6 +236,9 @@ pub(crate) const DAY_IN_LEDGERS: u32 = 17280; // if ledger takes 5 seconds
 pub(crate) const POSITIONS_BUMP_AMOUNT: u32 = 30 * DAY_IN_LEDGERS;
 pub(crate) const POSITIONS_LIFETIME_THRESHOLD: u32 = POSITIONS_BUMP_AMOUNT - DAY_IN_LEDGERS;
 
+pub(crate) const INSTANCE_BUMP_AMOUNT: u32 = 30 * DAY_IN_LEDGERS;
+pub(crate) const INSTANCE_LIFETIME_THRESHOLD: u32 = INSTANCE_BUMP_AMOUNT - DAY_IN_LEDGERS;
+
 /* Persistent ttl bumper */
 fn extend_persistent(e: &Env, key: &PoolDataKey) {
     e.storage()
@@ -134,6 +246,58 @@ fn extend_persistent(e: &Env, key: &PoolDataKey) {
         .extend_ttl(key, POSITIONS_LIFETIME_THRESHOLD, POSITIONS_BUMP_AMOUNT);
 }
 
+// Read a persistent entry and keep it from being archived.
+fn read_persistent<V: TryFromVal<Env, Val>>(e: &Env, key: &PoolDataKey) -> Option<V> {
+    let value = e.storage().persistent().get(key);
+    if value.is_some() {
+        extend_persistent(e, key);
+    }
+    value
+}
+
+/// Keep the contract instance and code from being archived.
+pub fn extend_instance(e: &Env) {
+    e.storage()
+        .instance()
+        .extend_ttl(INSTANCE_LIFETIME_THRESHOLD, INSTANCE_BUMP_AMOUNT);
+}
+
+/// Extend the TTL of the contract, its configuration and the positions of `users`.
+pub fn bump(e: &Env, users: &Vec<Address>) {
+    extend_instance(e);
+    for key in [
+        PoolDataKey::LoanManagerAddress,
+        PoolDataKey::Currency,
+        PoolDataKey::LiquidationThreshold,
+        PoolDataKey::TotalBalanceShares,
+        PoolDataKey::TotalBalanceTokens,
+        PoolDataKey::AvailableBalanceTokens,
+        PoolDataKey::Accrual,
+        PoolDataKey::AccrualLastUpdate,
+        PoolDataKey::InterestRateMultiplier,
+        PoolDataKey::PoolStatus,
+        PoolDataKey::LiquidationFeeShare,
+        PoolDataKey::TotalCollateral,
+        PoolDataKey::TotalReceivableShares,
+        PoolDataKey::InterestRateParams,
+        PoolDataKey::InterestRateModel,
+        PoolDataKey::AdaptiveRateParams,
+        PoolDataKey::RateAtTarget,
+        PoolDataKey::AccrualVersion,
+        PoolDataKey::PositionTotalsTracked,
+    ] {
+        if e.storage().persistent().has(&key) {
+            extend_persistent(e, &key);
+        }
+    }
+    for user in users.iter() {
+        let key = PoolDataKey::Positions(user);
+        if e.storage().persistent().has(&key) {
+            extend_persistent(e, &key);
+        }
+    }
+}
+
 pub fn change_pool_status(e: &Env, pool_status: PoolStatus) {
     let key = PoolDataKey::PoolStatus;
     e.storage().persistent().set(&key, &pool_status);
@@ -142,10 +306,7 @@ pub fn change_pool_status(e: &Env, pool_status: PoolStatus) {
 }
 
 pub fn read_pool_status(e: &Env) -> Result<PoolStatus, LoanPoolError> {
-    e.storage()
-        .persistent()
-        .get(&PoolDataKey::PoolStatus)
-        .ok_or(LoanPoolError::PoolStatus)
+    read_persistent(e, &PoolDataKey::PoolStatus).ok_or(LoanPoolError::PoolStatus)
 }
 
 pub fn write_loan_manager_addr(e: &Env, loan_manager_addr: Address) {
@@ -159,10 +320,7 @@ pub fn write_loan_manager_addr(e: &Env, loan_manager_addr: Address) {
 }
 
 pub fn read_loan_manager_addr(e: &Env) -> Result<Address, LoanPoolError> {
-    e.storage()
-        .persistent()
-        .get(&PoolDataKey::LoanManagerAddress)
-        .ok_or(LoanPoolError::LoanManager)
+    read_persistent(e, &PoolDataKey::LoanManagerAddress).ok_or(LoanPoolError::LoanManager)
 }
 
 pub fn write_currency(e: &Env, currency: Currency) {
@@ -173,10 +331,7 @@ pub fn write_currency(e: &Env, currency: Currency) {
 }
 
 pub fn read_currency(e: &Env) -> Result<Currency, LoanPoolError> {
-    e.storage()
-        .persistent()
-        .get(&PoolDataKey::Currency)
-        .ok_or(LoanPoolError::Currency)
+    read_persistent(e, &PoolDataKey::Currency).ok_or(LoanPoolError::Currency)
 }
 
 pub fn write_liquidation_threshold(e: &Env, threshold: i128) {
@@ -194,10 +349,7 @@ pub fn write_total_shares(e: &Env, amount: i128) {
 }
 
 pub fn read_total_shares(e: &Env) -> Result<i128, LoanPoolError> {
-    e.storage()
-        .persistent()
-        .get(&PoolDataKey::TotalBalanceShares)
-        .ok_or(LoanPoolError::TotalShares)
+    read_persistent(e, &PoolDataKey::TotalBalanceShares).ok_or(LoanPoolError::TotalShares)
 }
 
 pub fn adjust_total_shares(e: &Env, amount: i128) -> Result<i128, LoanPoolError> {
@@ -218,10 +370,7 @@ pub fn write_total_balance(e: &Env, amount: i128) {
 }
 
 pub fn read_total_balance(e: &Env) -> Result<i128, LoanPoolError> {
-    e.storage()
-        .persistent()
-        .get(&PoolDataKey::TotalBalanceTokens)
-        .ok_or(LoanPoolError::TotalBalance)
+    read_persistent(e, &PoolDataKey::TotalBalanceTokens).ok_or(LoanPoolError::TotalBalance)
 }
 
 pub fn adjust_total_balance(e: &Env, amount: i128) -> Result<i128, LoanPoolError> {
@@ -242,10 +391,7 @@ pub fn write_available_balance(e: &Env, amount: i128) {
 }
 
 pub fn read_available_balance(e: &Env) -> Result<i128, LoanPoolError> {
-    e.storage()
-        .persistent()
-        .get(&PoolDataKey::AvailableBalanceTokens)
-        .ok_or(LoanPoolError::AvailableBalance)
+    read_persistent(e, &PoolDataKey::AvailableBalanceTokens).ok_or(LoanPoolError::AvailableBalance)
 }
 
 pub fn adjust_available_balance(e: &Env, amount: i128) -> Result<i128, LoanPoolError> {
@@ -262,14 +408,23 @@ pub fn write_accrual(e: &Env, accrual: i128) {
     let key = PoolDataKey::Accrual;
     e.storage().persistent().set(&key, &accrual);
     extend_persistent(e, &key);
+    let version_key = PoolDataKey::AccrualVersion;
+    e.storage().persistent().set(&version_key, &1_u32);
+    extend_persistent(e, &version_key);
+    extend_instance(e);
     EventAccrualChanged { accrual }.publish(e);
 }
 
+/// Accrual index in `interest::ACCRUAL_ONE` fixed point. An index written before the switch to
+/// 18 decimals is rescaled on read, and stored rescaled on the next write.
 pub fn read_accrual(e: &Env) -> Result<i128, LoanPoolError> {
-    e.storage()
-        .persistent()
-        .get(&PoolDataKey::Accrual)
-        .ok_or(LoanPoolError::Accrual)
+    let accrual: i128 = read_persistent(e, &PoolDataKey::Accrual).ok_or(LoanPoolError::Accrual)?;
+    if e.storage().persistent().has(&PoolDataKey::AccrualVersion) {
+        return Ok(accrual);
+    }
+    accrual
+        .checked_mul(LEGACY_ACCRUAL_SCALE)
+        .ok_or(LoanPoolError::OverOrUnderFlow)
 }
 
 pub fn write_accrual_last_updated(e: &Env, sequence: u64) -> u64 {
@@ -286,36 +441,90 @@ pub fn write_accrual_last_updated(e: &Env, sequence: u64) -> u64 {
 }
 
 pub fn read_accrual_last_updated(e: &Env) -> Result<u64, LoanPoolError> {
-    e.storage()
-        .persistent()
-        .get(&PoolDataKey::AccrualLastUpdate)
-        .ok_or(LoanPoolError::AccrualLastUpdated)
+    read_persistent(e, &PoolDataKey::AccrualLastUpdate).ok_or(LoanPoolError::AccrualLastUpdated)
+}
+
+pub fn write_liquidation_fee_share(e: &Env, share: i128) {
+    let key = PoolDataKey::LiquidationFeeShare;
+    e.storage().persistent().set(&key, &share);
+    extend_persistent(e, &key);
+    EventLiquidationFeeShareChanged { share }.publish(e);
+}
+
+pub fn read_liquidation_fee_share(e: &Env) -> i128 {
+    read_persistent(e, &PoolDataKey::LiquidationFeeShare).unwrap_or(0)
+}
+
+pub fn write_interest_rate_params(e: &Env, params: InterestRateParams) {
+    let key = PoolDataKey::InterestRateParams;
+    e.storage().persistent().set(&key, &params);
+    extend_persistent(e, &key);
+    EventInterestRateParamsChanged { params }.publish(e);
+}
+
+/// Interest rate curve of the pool. Pools that never had it set use the default curve.
+pub fn read_interest_rate_params(e: &Env) -> InterestRateParams {
+    read_persistent(e, &PoolDataKey::InterestRateParams).unwrap_or(InterestRateParams {
+        base_rate: interest::BASE_INTEREST_RATE,
+        rate_at_panic: interest::INTEREST_RATE_AT_PANIC,
+        max_rate: interest::MAX_INTEREST_RATE,
+        panic_threshold: interest::PANIC_RATES_THRESHOLD,
+    })
+}
+
+pub fn write_interest_rate_model(e: &Env, model: InterestRateModel) {
+    let key = PoolDataKey::InterestRateModel;
+    e.storage().persistent().set(&key, &model);
+    extend_persistent(e, &key);
+    EventInterestRateModelChanged { model }.publish(e);
+}
+
+pub fn read_interest_rate_model(e: &Env) -> InterestRateModel {
+    read_persistent(e, &PoolDataKey::InterestRateModel).unwrap_or(InterestRateModel::Kinked)
+}
+
+pub fn write_adaptive_rate_params(e: &Env, params: AdaptiveRateParams) {
+    let key = PoolDataKey::AdaptiveRateParams;
+    e.storage().persistent().set(&key, &params);
+    extend_persistent(e, &key);
+    EventAdaptiveRateParamsChanged { params }.publish(e);
+}
+
+pub fn read_adaptive_rate_params(e: &Env) -> Option<AdaptiveRateParams> {
+    read_persistent(e, &PoolDataKey::AdaptiveRateParams)
+}
+
+pub fn write_rate_at_target(e: &Env, rate_at_target: i128) {
+    let key = PoolDataKey::RateAtTarget;
+    e.storage().persistent().set(&key, &rate_at_target);
+    extend_persistent(e, &key);
+    EventRateAtTargetChanged { rate_at_target }.publish(e);
+}
+
+pub fn read_rate_at_target(e: &Env) -> Option<i128> {
+    read_persistent(e, &PoolDataKey::RateAtTarget)
 }
 
 pub fn change_interest_rate_multiplier(e: &Env, multiplier: i128) {
     let key = PoolDataKey::InterestRateMultiplier;
     e.storage().persistent().set(&key, &multiplier);
+    extend_persistent(e, &key);
     EventInterestMultiplierChanged { multiplier }.publish(e)
 }
 
 pub fn read_interest_rate_multiplier(e: &Env) -> Result<i128, LoanPoolError> {
-    e.storage()
-        .persistent()
-        .get(&PoolDataKey::InterestRateMultiplier)
+    read_persistent(e, &PoolDataKey::InterestRateMultiplier)
         .ok_or(LoanPoolError::InterestRateMultiplier)
 }
 
 pub fn read_collateral_factor(e: &Env) -> Result<i128, LoanPoolError> {
-    e.storage()
-        .persistent()
-        .get(&PoolDataKey::LiquidationThreshold)
+    read_persistent(e, &PoolDataKey::LiquidationThreshold)
         .ok_or(LoanPoolError::LiquidationThreshold)
 }
 
 pub fn read_positions(e: &Env, addr: &Address) -> Positions {
     let key = PoolDataKey::Positions(addr.clone());
-    if let Some(positions) = e.storage().persistent().get(&key) {
-        extend_persistent(e, &key);
+    if let Some(positions) = read_persistent(e, &key) {
         positions
     } else {
         Positions {
@@ -335,6 +544,18 @@ pub fn write_positions(
 ) {
     let key = PoolDataKey::Positions(addr.clone());
 
+    let previous = read_positions(e, &addr);
+    adjust_position_total(
+        e,
+        PoolDataKey::TotalCollateral,
+        collateral - previous.collateral,
+    );
+    adjust_position_total(
+        e,
+        PoolDataKey::TotalReceivableShares,
+        receivables - previous.receivable_shares,
+    );
+
     let positions = Positions {
         receivable_shares: receivables,
         liabilities,
@@ -342,6 +563,102 @@ pub fn write_positions(
     };
     e.storage().persistent().set(&key, &positions);
     extend_persistent(e, &key);
+    extend_instance(e);
 
     EventPositionsUpdated { addr, positions }.publish(e)
 }
+
+/// Start keeping sums over all positions. Only called when the pool is initialized, while
+/// there are no positions yet, so the sums cover every position.
+pub fn write_position_totals_tracked(e: &Env) {
+    let key = PoolDataKey::PositionTotalsTracked;
+    e.storage().persistent().set(&key, &true);
+    extend_persistent(e, &key);
+}
+
+pub fn read_position_totals_tracked(e: &Env) -> bool {
+    read_persistent(e, &PoolDataKey::PositionTotalsTracked).unwrap_or(false)
+}
+
+// Keep a sum over all positions up to date. Pools that had positions before the sums were
+// introduced do not keep them, as they would miss the earlier positions.
+fn adjust_position_total(e: &Env, key: PoolDataKey, amount: i128) {
+    if amount == 0 || !read_position_totals_tracked(e) {
+        return;
+    }
+    let total: i128 = read_persistent(e, &key).unwrap_or(0);
+    e.storage().persistent().set(&key, &(total + amount));
+    extend_persistent(e, &key);
+}
+
+/// Sum of the collateral of all positions, `None` if the pool does not track it.
+pub fn read_total_collateral(e: &Env) -> Option<i128> {
+    read_position_totals_tracked(e)
+        .then(|| read_persistent(e, &PoolDataKey::TotalCollateral).unwrap_or(0))
+}
+
+/// Sum of the receivable shares of all positions, `None` if the pool does not track it.
+pub fn read_total_receivable_shares(e: &Env) -> Option<i128> {
+    read_position_totals_tracked(e)
+        .then(|| read_persistent(e, &PoolDataKey::TotalReceivableShares).unwrap_or(0))
+}
+
+/// Allowance of `spender` over the shares of `from`. Expired allowances read as zero.
+pub fn read_allowance(e: &Env, from: &Address, spender: &Address) -> AllowanceValue {
+    let key = PoolDataKey::Allowance(from.clone(), spender.clone());
+    match e.storage().temporary().get::<_, AllowanceValue>(&key) {
+        Some(allowance) if allowance.expiration_ledger >= e.ledger().sequence() => allowance,
+        _ => AllowanceValue {
+            amount: 0,
+            expiration_ledger: 0,
+        },
+    }
+}
+
+pub fn write_allowance(
+    e: &Env,
+    from: &Address,
+    spender: &Address,
+    amount: i128,
+    expiration_ledger: u32,
+) -> Result<(), LoanPoolError> {
+    if amount > 0 && expiration_ledger < e.ledger().sequence() {
+        return Err(LoanPoolError::InvalidExpirationLedger);
+    }
+
+    let key = PoolDataKey::Allowance(from.clone(), spender.clone());
+    e.storage().temporary().set(
+        &key,
+        &AllowanceValue {
+            amount,
+            expiration_ledger,
+        },
+    );
+    if amount > 0 {
+        let live_for = expiration_ledger - e.ledger().sequence();
+        e.storage().temporary().extend_ttl(&key, live_for, live_for);
+    }
+    Ok(())
+}
+
+pub fn spend_allowance(
+    e: &Env,
+    from: &Address,
+    spender: &Address,
+    amount: i128,
+) -> Result<(), LoanPoolError> {
+    let allowance = read_allowance(e, from, spender);
+    if allowance.amount < amount {
+        return Err(LoanPoolError::InsufficientAllowance);
+    }
+    if amount > 0 {
+        write_allowance(
+            e,
+            from,
+            spender,
+            allowance.amount - amount,
+            allowance.expiration_ledger,
+        )?;
+    }
+    Ok(())
+}
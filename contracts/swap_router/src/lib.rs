@@ -0,0 +1,20 @@
+#![no_std]
+use soroban_sdk::{contractclient, Address, Env};
+
+/// Interface of the swap adapters `LoanManager` trades through. An adapter wraps a DEX and is
+/// registered with the manager by the admin.
+///
+/// The caller transfers `amount_in` of `token_in` to the adapter before calling `swap`, and the
+/// adapter sends the output to `to`. Returns the amount of `token_out` sent, which has to be at
+/// least `min_amount_out`.
+#[contractclient(name = "SwapRouterClient")]
+pub trait SwapRouter {
+    fn swap(
+        e: Env,
+        token_in: Address,
+        token_out: Address,
+        amount_in: i128,
+        min_amount_out: i128,
+        to: Address,
+    ) -> i128;
+}